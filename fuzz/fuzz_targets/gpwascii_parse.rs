@@ -0,0 +1,26 @@
+//! Feeds arbitrary bytes to the GPW ASCII parsers, which must return
+//! a `GpwError` rather than panic on malformed input.
+//!
+//! Run with a nightly toolchain and `cargo install cargo-fuzz`:
+//!
+//! ```sh
+//! cd fuzz
+//! cargo +nightly fuzz run gpwascii_parse
+//! ```
+#![no_main]
+
+use gpwgen::gpwascii::{GpwAscii, GpwAsciiHeader, ParseOptions};
+use libfuzzer_sys::fuzz_target;
+use std::io::BufReader;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = GpwAsciiHeader::parse(&mut BufReader::new(data));
+
+    // Keep the fuzzer from spending its time on legitimately huge
+    // (but well-formed) grid allocations.
+    let opts = ParseOptions {
+        max_cells: 1 << 16,
+        ..Default::default()
+    };
+    let _ = GpwAscii::parse_with(&mut BufReader::new(data), &opts);
+});
@@ -0,0 +1,47 @@
+//! Compares the serial and parallel GPW ASCII grid parsers.
+//!
+//! Run with `cargo bench -p gpwgen --bench parse`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gpwgen::gpwascii::{GpwAscii, ParseOptions};
+use std::io::{BufReader, Cursor};
+
+/// A synthetic grid about the size of one GPW tile, with a mix of
+/// NODATA and fractional samples.
+fn grid(nrows: usize, ncols: usize) -> String {
+    let mut src = format!(
+        "ncols {}\nnrows {}\nxllcorner 0\nyllcorner 0\ncellsize 0.0083333333333333\nNODATA_value -9999\n",
+        ncols, nrows
+    );
+    for row in 0..nrows {
+        for col in 0..ncols {
+            if col > 0 {
+                src.push(' ');
+            }
+            match (row * ncols + col) % 4 {
+                0 => src.push_str("-9999"),
+                n => src.push_str(&format!("{}.{:03}", row % 1000, n * 137)),
+            }
+        }
+        src.push('\n');
+    }
+    src
+}
+
+fn parse(c: &mut Criterion) {
+    let src = grid(2000, 2000);
+    let opts = ParseOptions::default();
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(src.len() as u64));
+    group.bench_function("serial", |b| {
+        b.iter(|| GpwAscii::parse_with(&mut BufReader::new(Cursor::new(&src)), &opts).unwrap())
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| GpwAscii::parse_parallel(&mut BufReader::new(Cursor::new(&src)), &opts).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+//! Cell areas, for turning populations into densities.
+
+use crate::error::GpwError;
+use hextree::h3ron::H3Cell;
+
+/// Returns the area of `cell` in km².
+pub fn cell_area_km2(cell: H3Cell) -> Result<f64, GpwError> {
+    Ok(cell.area_km2()?)
+}
+
+/// Returns the summed area of `cells` in km².
+pub fn cells_area_km2(cells: impl IntoIterator<Item = H3Cell>) -> Result<f64, GpwError> {
+    cells.into_iter().map(cell_area_km2).sum()
+}
+
+/// Returns `pop` per km² of `area_km2`. An empty area has no density
+/// to speak of, so gives zero.
+pub fn density(pop: f32, area_km2: f64) -> f32 {
+    if area_km2 > 0.0 {
+        (f64::from(pop) / area_km2) as f32
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::coord;
+
+    #[test]
+    fn test_cell_area() {
+        // Res 8 hexes average 0.737 km², varying by under a factor of
+        // two across the globe.
+        let cell = H3Cell::from_coordinate(coord! {x: 8.5403, y: 47.3779}, 8).unwrap();
+        let area = cell_area_km2(cell).unwrap();
+        assert!((0.5..1.0).contains(&area), "{}", area);
+
+        let children = cell.get_children(9).unwrap();
+        let summed = cells_area_km2(children.iter()).unwrap();
+        // Children only approximately tile their parent.
+        assert!((summed / area - 1.0).abs() < 0.05, "{} {}", summed, area);
+
+        assert_eq!(density(7.37, 0.737), 10.0);
+        assert_eq!(density(1.0, 0.0), 0.0);
+    }
+}
@@ -1,23 +1,132 @@
-use clap::Parser;
+use crate::{
+    generate::Distribution,
+    h3tess::{Encoding, Quantize},
+    heatmap::{Colormap, Extent},
+};
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser, Debug)]
 pub enum Args {
     Tessellate(Tessellate),
+    MergeTiles(MergeTiles),
     Combine(Combine),
+    Build(Build),
+    Verify(Verify),
+    Hist(Hist),
+    ImportCsv(ImportCsv),
+    Heatmap(Heatmap),
 }
 
 /// Tessellate global world population (GPW) asc file grids into H3
 /// cell/value pairs.
 #[derive(Parser, Debug)]
 pub struct Tessellate {
-    /// Intermediate H3 resolution.
-    #[arg(short, long, default_value_t = 10)]
-    pub resolution: u8,
-    /// Input GPW ASCII file.
+    /// Intermediate H3 resolution, or a comma separated list (e.g.
+    /// `10,9`) giving one per source, in order, for mixing fine and
+    /// coarse grids. Grids found under a `--recursive` directory share
+    /// its resolution.
+    #[arg(short, long, value_delimiter = ',', default_value = "10")]
+    pub resolution: Vec<u8>,
+    /// Input GPW ASCII files (optionally gzipped), or NetCDF `.nc`
+    /// files when built with the `netcdf` feature.
     pub sources: Vec<std::path::PathBuf>,
     /// Output directory.
     #[arg(short, long)]
     pub outdir: std::path::PathBuf,
+    /// Treat `nan`/`inf` cells as NODATA instead of failing.
+    #[arg(long)]
+    pub non_finite_as_nodata: bool,
+    /// Tessellate grids whose data ends before `nrows` rows, as a
+    /// truncated download does, treating the missing rows as NODATA.
+    #[arg(long)]
+    pub allow_partial: bool,
+    /// Replace negative population values with zero.
+    #[arg(long)]
+    pub clamp_negative: bool,
+    /// Treat cells of exactly zero as NODATA, leaving them out of the
+    /// output. Applies after `--clamp-negative`.
+    #[arg(long)]
+    pub drop_zero: bool,
+    /// Sum every NxN block of grid cells into one before tessellating,
+    /// for quick low-resolution output at about 1/N² the work. N must
+    /// divide both the grid's rows and columns.
+    #[arg(long, value_name = "N")]
+    pub coarsen: Option<usize>,
+    /// Number of tessellation threads [default: logical core count].
+    #[arg(long)]
+    pub threads: Option<usize>,
+    /// Number of files tessellated at the same time. Each in-flight
+    /// file holds its whole grid in memory and gets its own pool of
+    /// `--threads` threads.
+    #[arg(long, default_value_t = 1)]
+    pub parallel_files: usize,
+    /// Treat directory sources as trees of `.asc`/`.asc.gz`/`.nc` grids,
+    /// mirroring their layout under the output directory.
+    #[arg(long)]
+    pub recursive: bool,
+    /// Output encoding. Text encodings write `(index, value)` rows
+    /// for other tools and can't be combined.
+    #[arg(long, value_enum, default_value_t = Encoding::H3tess)]
+    pub format: Encoding,
+    /// Write the extended record format carrying a per-hex count of
+    /// contributing grid cells.
+    #[arg(long)]
+    pub counts: bool,
+    /// End the output with a CRC32 checksum of its records, checked
+    /// whenever it's read. Only for h3tess output.
+    #[arg(long)]
+    pub checksum: bool,
+    /// How each grid cell's value is divided among its hexes.
+    #[arg(long, value_enum, default_value_t = Distribution::Equal)]
+    pub distribution: Distribution,
+    /// Check each grid cell's hex count against what its area
+    /// predicts and log a per-file summary of cells that produced none
+    /// or far too few or many, a sign of a misaligned header.
+    #[arg(long)]
+    pub drift_check: bool,
+    /// Write records in grid order so identical inputs produce
+    /// byte-identical outputs. Buffers each file's records in memory
+    /// until it's tessellated, about 16 bytes per output hex.
+    #[arg(long)]
+    pub deterministic: bool,
+    /// Skip sources whose output already exists and is non-empty, so
+    /// an interrupted batch can be rerun. Existing h3tess outputs must
+    /// also read back cleanly; without `--checksum` one cut short at a
+    /// record boundary still counts as complete.
+    #[arg(long)]
+    pub skip_existing: bool,
+    /// Fail before doing any work if an output already exists, rather
+    /// than overwriting it. Combine with `--skip-existing` to pass over
+    /// complete outputs and only fail on partial ones.
+    #[arg(long, env = "GPWGEN_NO_CLOBBER")]
+    pub no_clobber: bool,
+    /// Name of the population variable in NetCDF sources. Its last two
+    /// dimensions must be latitude and longitude.
+    #[arg(long, value_name = "NAME")]
+    pub netcdf_variable: Option<String>,
+    /// Write a JSON summary to this file once done: per source, the
+    /// populated grid cells read, pairs written, cells dropped for
+    /// containing no hex center, clamped to zero or dropped as zero,
+    /// total population and seconds taken.
+    #[arg(long)]
+    pub report: Option<std::path::PathBuf>,
+    /// Parse every source header and check output paths, then print
+    /// the plan without tessellating or writing anything.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Merge adjacent GPW ASCII tiles into one grid, counting cells where
+/// tiles overlap only once, for tessellating as a whole.
+#[derive(Parser, Debug)]
+pub struct MergeTiles {
+    /// Input GPW ASCII tiles (optionally gzipped), all with the same
+    /// cell size and aligned to a common grid. Overlapping cells keep
+    /// the value of the first tile listed.
+    pub sources: Vec<std::path::PathBuf>,
+    /// Output `.asc` file, or `-` for stdout.
+    #[arg(short, long)]
+    pub output: std::path::PathBuf,
 }
 
 /// Combine multiple h3tess files into a single serialized H3 map at
@@ -27,9 +136,201 @@ pub struct Combine {
     /// H3 resolution.
     #[arg(short, long, default_value_t = 8)]
     pub resolution: u8,
+    /// Produce one map per listed resolution (e.g. `6,7,8`) from a
+    /// single pass over the sources, overriding `--resolution`. Each
+    /// output file has `res{N}` inserted before its extension.
+    #[arg(long, value_delimiter = ',')]
+    pub resolutions: Vec<u8>,
+    /// Drop, with a warning, a partial record at the end of a source
+    /// instead of failing.
+    #[arg(long)]
+    pub tolerate_truncation: bool,
+    /// Accept sources with a missing or mismatched checksum.
+    #[arg(long)]
+    pub skip_checksum: bool,
+    /// Write the extended record format carrying summed per-hex
+    /// contribution counts, for area-weighted means.
+    #[arg(long)]
+    pub counts: bool,
+    /// Store values as fixed-point integers, given as `u16:SCALE` or
+    /// `u32:SCALE` steps per person (e.g. `u16:10`). Values are kept to
+    /// within half a step and writing fails for values out of range;
+    /// only `u16` shrinks the file.
+    #[arg(long)]
+    pub quantize: Option<Quantize>,
+    /// End the output with a CRC32 checksum of its records, checked
+    /// whenever it's read.
+    #[arg(long)]
+    pub checksum: bool,
+    /// Store each index as a varint delta from the previous one,
+    /// about 3 bytes instead of 8 for a dense map. Can't be used with
+    /// `--compact-partial`, whose mixed resolutions don't come out in
+    /// index order.
+    #[arg(long)]
+    pub delta: bool,
+    /// Also merge cells left finer than the output resolution because
+    /// some of their siblings are missing, as at the edges of
+    /// populated areas, treating absent cells as zero. Only groups of
+    /// at least MIN_CELLS cells are merged; 1 merges isolated cells
+    /// too.
+    #[arg(long, value_name = "MIN_CELLS")]
+    pub compact_partial: Option<usize>,
+    /// Write a sidecar text file listing, for every cell of the finest
+    /// output resolution, the ids of the sources that contributed to
+    /// it. Ids are positions in the source list.
+    #[arg(long)]
+    pub provenance: Option<std::path::PathBuf>,
+    /// Write a JSON summary to this file once done: per source, the
+    /// records read, bytes dropped from a truncated end, total
+    /// population and seconds taken, and per output the pairs written.
+    #[arg(long)]
+    pub report: Option<std::path::PathBuf>,
+    /// Output format. `sqlite` writes a database with a `cells
+    /// (h3_index INTEGER PRIMARY KEY, population REAL)` table, plus a
+    /// `count` column with `--counts`, indexed on population. `parquet`
+    /// writes `h3_index: uint64` and `population: float32` columns,
+    /// plus `count: uint32` with `--counts`.
+    #[arg(long, value_enum, default_value_t = MapFormat::H3tess)]
+    pub format: MapFormat,
+    /// Check every source and output path, then print the plan
+    /// without combining or writing anything.
+    #[arg(long)]
+    pub dry_run: bool,
     /// h3tess source files.
     pub sources: Vec<std::path::PathBuf>,
+    /// Output file, or `-` for stdout [default: `gpw.res{N}.` and the
+    /// format's extension, e.g. `gpw.res8.h3idx`].
+    #[arg(short, long)]
+    pub output: Option<std::path::PathBuf>,
+}
+
+/// Tessellate GPW asc files and combine them into a single serialized
+/// H3 map in one step, without intermediate h3tess files.
+#[derive(Parser, Debug)]
+pub struct Build {
+    /// Intermediate H3 resolution.
+    #[arg(short, long, default_value_t = 10)]
+    pub intermediate_resolution: u8,
+    /// H3 resolution of the output map.
+    #[arg(short, long, default_value_t = 8)]
+    pub resolution: u8,
+    /// Write the extended record format carrying summed per-hex
+    /// contribution counts.
+    #[arg(long)]
+    pub counts: bool,
+    /// Store values as fixed-point integers, given as `u16:SCALE` or
+    /// `u32:SCALE` steps per person (e.g. `u16:10`). Values are kept to
+    /// within half a step and writing fails for values out of range;
+    /// only `u16` shrinks the file.
+    #[arg(long)]
+    pub quantize: Option<Quantize>,
+    /// End the output with a CRC32 checksum of its records, checked
+    /// whenever it's read.
+    #[arg(long)]
+    pub checksum: bool,
+    /// Store each index as a varint delta from the previous one,
+    /// about 3 bytes instead of 8 for a dense map.
+    #[arg(long)]
+    pub delta: bool,
+    /// Input GPW ASCII files.
+    pub sources: Vec<std::path::PathBuf>,
     /// Output file.
     #[arg(short, long)]
     pub output: std::path::PathBuf,
 }
+
+/// Check that an h3tess file is well-formed, reporting the byte offset
+/// of the first bad record.
+#[derive(Parser, Debug)]
+pub struct Verify {
+    /// Also require indices to be in strictly increasing order.
+    #[arg(long)]
+    pub sorted: bool,
+    /// h3tess file to check.
+    pub file: std::path::PathBuf,
+}
+
+/// Summarize the distribution of per-cell values in an h3tess file
+/// with a histogram and percentiles, in two streaming passes.
+#[derive(Parser, Debug)]
+pub struct Hist {
+    /// Number of equal-width histogram buckets.
+    #[arg(long, default_value_t = 10)]
+    pub buckets: usize,
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = HistFormat::Csv)]
+    pub format: HistFormat,
+    /// Accept a file with a missing or mismatched checksum.
+    #[arg(long)]
+    pub skip_checksum: bool,
+    /// h3tess file to summarize.
+    pub file: std::path::PathBuf,
+}
+
+/// Convert `h3_index,value` CSV rows produced by other tools into an
+/// h3tess file `combine` accepts.
+#[derive(Parser, Debug)]
+pub struct ImportCsv {
+    /// End the output with a CRC32 checksum of its records, checked
+    /// whenever it's read.
+    #[arg(long)]
+    pub checksum: bool,
+    /// CSV file with one `h3_index,value` row per cell and an optional
+    /// header row. Indices may be hex or decimal.
+    pub file: std::path::PathBuf,
+    /// Output h3tess file.
+    #[arg(short, long)]
+    pub output: std::path::PathBuf,
+}
+
+/// Render an h3tess file as a PNG heatmap of log-population, for a
+/// one-glance check that population lands where expected.
+#[derive(Parser, Debug)]
+pub struct Heatmap {
+    /// Image width in pixels. The height keeps pixels square in
+    /// degrees.
+    #[arg(long, default_value_t = 1440)]
+    pub width: u32,
+    /// Only render `MIN_LAT,MIN_LNG,MAX_LAT,MAX_LNG` rather than the
+    /// whole globe.
+    #[arg(long, value_name = "MIN_LAT,MIN_LNG,MAX_LAT,MAX_LNG")]
+    pub bbox: Option<Extent>,
+    /// Color ramp, from least to most populated.
+    #[arg(long, value_enum, default_value_t = Colormap::Inferno)]
+    pub colormap: Colormap,
+    /// Accept a file with a missing or mismatched checksum.
+    #[arg(long)]
+    pub skip_checksum: bool,
+    /// h3tess file to render.
+    pub file: std::path::PathBuf,
+    /// Output PNG file.
+    #[arg(short, long)]
+    pub output: std::path::PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MapFormat {
+    /// A serialized h3tess map.
+    H3tess,
+    /// An SQLite database, for ad-hoc queries.
+    Sqlite,
+    /// A Parquet file, for dataframe libraries.
+    Parquet,
+}
+
+impl MapFormat {
+    /// Conventional file extension for the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MapFormat::H3tess => "h3idx",
+            MapFormat::Sqlite => "sqlite",
+            MapFormat::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HistFormat {
+    Csv,
+    Json,
+}
@@ -0,0 +1,458 @@
+//! Combining h3tess streams into a single H3 map.
+
+use crate::{error::GpwError, h3tess::H3TessReader};
+use hextree::{
+    compaction::Compactor,
+    h3ron::{H3Cell, Index},
+    HexTreeMap,
+};
+use std::io::Read;
+#[cfg(feature = "parallel")]
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{sync_channel, Receiver},
+    vec,
+};
+
+/// Records per batch a [`read_ahead`] thread hands over at a time.
+#[cfg(feature = "parallel")]
+const BATCH_RECORDS: usize = 4096;
+
+/// Batches a [`read_ahead`] thread may buffer before waiting on the
+/// consumer.
+#[cfg(feature = "parallel")]
+const BATCHES_AHEAD: usize = 4;
+
+/// How the values of seven complete children merge into their
+/// parent's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReduceMode {
+    /// The parent holds the children's total, as for population
+    /// counts.
+    #[default]
+    Sum,
+    /// The parent holds the smallest child value.
+    Min,
+    /// The parent holds the largest child value, as for peak density.
+    Max,
+}
+
+impl ReduceMode {
+    /// Reduces `values` to one. This is the single definition of each
+    /// mode, shared by compaction and by queries for a cell above a
+    /// map's cells, so the two always agree. An empty slice sums to
+    /// zero and has an infinite min or max.
+    pub fn apply(self, values: &[f32]) -> f32 {
+        match self {
+            ReduceMode::Sum => values.iter().sum(),
+            ReduceMode::Min => values.iter().copied().fold(f32::INFINITY, f32::min),
+            ReduceMode::Max => values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        }
+    }
+
+    /// Folds a (value, contributing cell count) pair into `acc`.
+    fn merge(self, (val, count): (f32, u32), (v, c): &(f32, u32)) -> (f32, u32) {
+        (self.apply(&[val, *v]), count + c)
+    }
+}
+
+/// Compacts seven complete children at or above `resolution` into
+/// their parent, reducing values by `mode` and summing contributing
+/// source cell counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReduceCompactor {
+    pub resolution: u8,
+    pub mode: ReduceMode,
+}
+
+impl Compactor<(f32, u32)> for ReduceCompactor {
+    fn compact(&mut self, res: u8, children: [Option<&(f32, u32)>; 7]) -> Option<(f32, u32)> {
+        if res < self.resolution {
+            return None;
+        }
+        let [Some(a), Some(b), Some(c), Some(d), Some(e), Some(f), Some(g)] = children else {
+            return None;
+        };
+        let children = [a, b, c, d, e, f, g];
+        Some((
+            self.mode.apply(&children.map(|(val, _)| *val)),
+            children.iter().map(|(_, count)| count).sum(),
+        ))
+    }
+}
+
+/// Inserts every (cell, value, count) record from an h3tess stream
+/// into `map`, calling `visit` with each inserted cell.
+pub fn insert_h3tess<C: Compactor<(f32, u32)>>(
+    map: &mut HexTreeMap<(f32, u32), C>,
+    rdr: &mut H3TessReader<impl Read>,
+    mut visit: impl FnMut(H3Cell) -> Result<(), GpwError>,
+) -> Result<(), GpwError> {
+    for record in rdr.counted() {
+        let (cell, val, count) = record?;
+        visit(cell)?;
+        map.insert(cell, (val, count));
+    }
+    Ok(())
+}
+
+/// Messages from a [`read_ahead`] thread.
+#[cfg(feature = "parallel")]
+enum ReadAheadMsg {
+    Records(Vec<(H3Cell, f32, u32)>),
+    Error(GpwError),
+    /// The source is exhausted; carries the reader's final state.
+    End {
+        offset: u64,
+        truncated: Option<usize>,
+    },
+}
+
+/// The (cell, value, count) records of one source, read on a
+/// background thread by [`read_ahead`].
+#[cfg(feature = "parallel")]
+pub struct ReadAhead {
+    rx: Receiver<ReadAheadMsg>,
+    batch: vec::IntoIter<(H3Cell, f32, u32)>,
+    offset: u64,
+    truncated: Option<usize>,
+}
+
+#[cfg(feature = "parallel")]
+impl ReadAhead {
+    /// Bytes the source's reader consumed, known once exhausted.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// See [`H3TessReader::truncated`], known once exhausted.
+    pub fn truncated(&self) -> Option<usize> {
+        self.truncated
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl Iterator for ReadAhead {
+    type Item = Result<(H3Cell, f32, u32), GpwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.batch.next() {
+                return Some(Ok(record));
+            }
+            match self.rx.recv().ok()? {
+                ReadAheadMsg::Records(batch) => self.batch = batch.into_iter(),
+                ReadAheadMsg::Error(e) => return Some(Err(e)),
+                ReadAheadMsg::End { offset, truncated } => {
+                    self.offset = offset;
+                    self.truncated = truncated;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Calls `f` with each source's index and records, in source order,
+/// while up to `files_ahead` later sources are read on background
+/// threads. Each thread buffers at most a few batches of records, so
+/// memory stays bounded however large the sources are.
+#[cfg(feature = "parallel")]
+pub fn read_ahead<R: Read + Send, E>(
+    readers: impl IntoIterator<Item = H3TessReader<R>>,
+    files_ahead: usize,
+    mut f: impl FnMut(usize, &mut ReadAhead) -> Result<(), E>,
+) -> Result<(), E> {
+    std::thread::scope(|scope| {
+        let mut readers = readers.into_iter();
+        let mut pending = VecDeque::new();
+        for id in 0.. {
+            while pending.len() <= files_ahead {
+                let Some(mut rdr) = readers.next() else {
+                    break;
+                };
+                let (tx, rx) = sync_channel(BATCHES_AHEAD);
+                scope.spawn(move || {
+                    let mut batch = Vec::with_capacity(BATCH_RECORDS);
+                    // Sends only fail once the consumer has given up.
+                    for record in rdr.counted() {
+                        match record {
+                            Ok(record) => batch.push(record),
+                            Err(e) => {
+                                let _ = tx.send(ReadAheadMsg::Error(e));
+                                return;
+                            }
+                        }
+                        if batch.len() == BATCH_RECORDS {
+                            let full =
+                                std::mem::replace(&mut batch, Vec::with_capacity(BATCH_RECORDS));
+                            if tx.send(ReadAheadMsg::Records(full)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    let _ = tx.send(ReadAheadMsg::Records(batch));
+                    let _ = tx.send(ReadAheadMsg::End {
+                        offset: rdr.offset(),
+                        truncated: rdr.truncated(),
+                    });
+                });
+                pending.push_back(ReadAhead {
+                    rx,
+                    batch: Vec::new().into_iter(),
+                    offset: 0,
+                    truncated: None,
+                });
+            }
+            let Some(mut records) = pending.pop_front() else {
+                break;
+            };
+            f(id, &mut records)?;
+        }
+        Ok(())
+    })
+}
+
+/// Returns a copy of `map` compacted to `resolution`.
+pub fn coarsen<C>(
+    map: &HexTreeMap<(f32, u32), C>,
+    resolution: u8,
+    mode: ReduceMode,
+) -> HexTreeMap<(f32, u32), ReduceCompactor> {
+    let mut coarse = HexTreeMap::with_compactor(ReduceCompactor { resolution, mode });
+    for (cell, val) in map.iter() {
+        coarse.insert(*cell, *val);
+    }
+    coarse
+}
+
+/// Returns a copy of `map` at `resolution` that also merges
+/// incomplete groups: every cell finer than `resolution` is reduced
+/// into its ancestor at `resolution` by `mode` as long as at least
+/// `min_cells` cells share that ancestor, with absent children
+/// contributing nothing. Groups with fewer cells are kept as they are,
+/// so `min_cells` above 1 leaves isolated cells alone.
+///
+/// [`ReduceCompactor`] only merges complete children because it runs
+/// during insertion: once a parent becomes a leaf, the map drops
+/// anything later inserted beneath it. This works on a finished map
+/// instead.
+pub fn compact_partial<C>(
+    map: &HexTreeMap<(f32, u32), C>,
+    resolution: u8,
+    mode: ReduceMode,
+    min_cells: usize,
+) -> Result<HexTreeMap<(f32, u32), ReduceCompactor>, GpwError> {
+    // Merges one group of cells sharing `ancestor`, or keeps them as
+    // they are if there are too few.
+    let merge_group =
+        |compacted: &mut HexTreeMap<_, _>, ancestor, group: &[(H3Cell, (f32, u32))]| {
+            if group.len() < min_cells {
+                for (cell, val) in group {
+                    compacted.insert(*cell, *val);
+                }
+            } else if let Some(((_, first), rest)) = group.split_first() {
+                let merged = rest
+                    .iter()
+                    .fold(*first, |acc, (_, val)| mode.merge(acc, val));
+                compacted.insert(ancestor, merged);
+            }
+        };
+
+    let mut compacted = HexTreeMap::with_compactor(ReduceCompactor { resolution, mode });
+    // Iteration is depth first, so cells sharing an ancestor are
+    // adjacent.
+    let mut ancestor = None;
+    let mut group = Vec::new();
+    for (cell, val) in map.iter() {
+        let cell_ancestor = if cell.resolution() > resolution {
+            Some(cell.get_parent(resolution)?)
+        } else {
+            None
+        };
+        if cell_ancestor != ancestor {
+            if let Some(ancestor) = ancestor {
+                merge_group(&mut compacted, ancestor, &group);
+            }
+            group.clear();
+            ancestor = cell_ancestor;
+        }
+        match cell_ancestor {
+            Some(_) => group.push((*cell, *val)),
+            None => compacted.insert(*cell, *val),
+        }
+    }
+    if let Some(ancestor) = ancestor {
+        merge_group(&mut compacted, ancestor, &group);
+    }
+    Ok(compacted)
+}
+
+/// Combines h3tess streams into a single map compacted to
+/// `resolution`, for programs that want a map in memory rather than
+/// a file.
+///
+/// Later sources overwrite earlier ones' values for the same cell.
+pub fn combine_readers(
+    sources: impl IntoIterator<Item = impl Read>,
+    resolution: u8,
+    reduce: ReduceMode,
+) -> Result<HexTreeMap<f32>, GpwError> {
+    let mut map = HexTreeMap::with_compactor(ReduceCompactor {
+        resolution,
+        mode: reduce,
+    });
+    for source in sources {
+        insert_h3tess(&mut map, &mut H3TessReader::new(source), |_| Ok(()))?;
+    }
+    Ok(map.iter().map(|(cell, (val, _))| (*cell, *val)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h3tess::{H3TessWriter, RECORD_LEN};
+    use geo::coord;
+
+    fn h3tess(pairs: &[(H3Cell, f32)]) -> Vec<u8> {
+        let mut wtr = H3TessWriter::new(Vec::new());
+        for (cell, val) in pairs {
+            wtr.write_pair(*cell, *val).unwrap();
+        }
+        wtr.finish().unwrap()
+    }
+
+    #[test]
+    fn test_combine_readers() {
+        let parent = H3Cell::from_coordinate(coord! {x: 0.5, y: 0.5}, 9).unwrap();
+        let children = parent.get_children(10).unwrap();
+        let children = children.iter().collect::<Vec<_>>();
+        let (west, east) = children.split_at(4);
+        let west = h3tess(&west.iter().map(|c| (*c, 1.0)).collect::<Vec<_>>());
+        let east = h3tess(&east.iter().map(|c| (*c, 2.0)).collect::<Vec<_>>());
+        let sources = || [west.as_slice(), east.as_slice()];
+
+        let summed = combine_readers(sources(), 9, ReduceMode::Sum).unwrap();
+        assert_eq!(summed.len(), 1);
+        assert_eq!(summed.get(parent), Some(&10.0));
+
+        let max = combine_readers(sources(), 9, ReduceMode::Max).unwrap();
+        assert_eq!(max.get(parent), Some(&2.0));
+        let min = combine_readers(sources(), 9, ReduceMode::Min).unwrap();
+        assert_eq!(min.get(parent), Some(&1.0));
+
+        // Nothing is compacted above the requested resolution.
+        let fine = combine_readers(sources(), 10, ReduceMode::Sum).unwrap();
+        assert_eq!(fine.len(), 7);
+        assert_eq!(fine.get(children[6]), Some(&2.0));
+
+        // A missing child keeps the parent from forming.
+        let partial = combine_readers([&west[RECORD_LEN..]], 9, ReduceMode::Sum).unwrap();
+        assert_eq!(partial.len(), 3);
+        assert_eq!(partial.get(parent), None);
+    }
+
+    #[test]
+    fn test_compact_partial() {
+        let parent = H3Cell::from_coordinate(coord! {x: 0.5, y: 0.5}, 9).unwrap();
+        let children = parent.get_children(10).unwrap();
+        let isolated = H3Cell::from_coordinate(coord! {x: 20.5, y: 0.5}, 10).unwrap();
+        let mut map = HexTreeMap::with_compactor(ReduceCompactor {
+            resolution: 9,
+            mode: ReduceMode::Sum,
+        });
+        for (i, child) in children.iter().take(3).enumerate() {
+            map.insert(child, (i as f32 + 1.0, 1));
+        }
+        map.insert(isolated, (5.0, 1));
+        // Three of seven children never compact on insertion.
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get(parent), None);
+
+        let partial = compact_partial(&map, 9, ReduceMode::Sum, 2).unwrap();
+        assert_eq!(partial.len(), 2);
+        assert_eq!(partial.get(parent), Some(&(6.0, 3)));
+        // The isolated cell is left alone unless groups of one merge.
+        assert_eq!(partial.get(isolated), Some(&(5.0, 1)));
+        let singles = compact_partial(&map, 9, ReduceMode::Sum, 1).unwrap();
+        assert_eq!(
+            singles.get(isolated.get_parent(9).unwrap()),
+            Some(&(5.0, 1))
+        );
+        assert_eq!(singles.len(), 2);
+
+        let max = compact_partial(&map, 9, ReduceMode::Max, 2).unwrap();
+        assert_eq!(max.get(parent), Some(&(3.0, 3)));
+        let strict = compact_partial(&map, 9, ReduceMode::Sum, 4).unwrap();
+        assert_eq!(strict.len(), 4);
+
+        // Coarser targets merge across levels.
+        let coarse = compact_partial(&map, 7, ReduceMode::Sum, 2).unwrap();
+        assert_eq!(coarse.get(parent.get_parent(7).unwrap()), Some(&(6.0, 3)));
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_read_ahead_matches_sequential() {
+        let parent = H3Cell::from_coordinate(coord! {x: 0.5, y: 0.5}, 5).unwrap();
+        let children = parent.get_children(10).unwrap();
+        let pairs = children
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| (cell, i as f32))
+            .collect::<Vec<_>>();
+        // Sources spanning several batches, a partial one and none.
+        let bufs = [
+            h3tess(&pairs[..BATCH_RECORDS * 2 + 1]),
+            h3tess(&pairs[..0]),
+            h3tess(&pairs[7..BATCH_RECORDS]),
+            h3tess(&pairs),
+        ];
+        let sequential = bufs
+            .iter()
+            .enumerate()
+            .flat_map(|(id, buf)| H3TessReader::new(buf.as_slice()).map(move |r| (id, r.unwrap())))
+            .collect::<Vec<_>>();
+        for files_ahead in [0, 1, 10] {
+            let mut concurrent = Vec::new();
+            let readers = bufs.iter().map(|buf| H3TessReader::new(buf.as_slice()));
+            read_ahead(
+                readers,
+                files_ahead,
+                |id, records| -> Result<(), GpwError> {
+                    for record in records.by_ref() {
+                        let (cell, val, _) = record?;
+                        concurrent.push((id, (cell, val)));
+                    }
+                    assert_eq!(records.offset(), bufs[id].len() as u64);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(concurrent, sequential);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_read_ahead_error() {
+        let cell = H3Cell::from_coordinate(coord! {x: 0.5, y: 0.5}, 10).unwrap();
+        let good = h3tess(&[(cell, 1.0)]);
+        let bad = &good[..RECORD_LEN - 1];
+        let readers = [good.as_slice(), bad, good.as_slice()].map(H3TessReader::new);
+        let mut visited = Vec::new();
+        let result = read_ahead(readers, 1, |id, records| -> Result<(), GpwError> {
+            visited.push(id);
+            records.try_for_each(|record| record.map(|_| ()))
+        });
+        assert!(result.is_err());
+        assert_eq!(visited, [0, 1]);
+    }
+
+    #[test]
+    fn test_combine_readers_bad_source() {
+        let cell = H3Cell::from_coordinate(coord! {x: 0.5, y: 0.5}, 10).unwrap();
+        let buf = h3tess(&[(cell, 1.0)]);
+        assert!(combine_readers([&buf[..RECORD_LEN - 1]], 10, ReduceMode::Sum).is_err());
+    }
+}
@@ -1,10 +1,137 @@
-use std::io;
+use hextree::h3ron;
+use std::{
+    fmt::{self, Display},
+    io,
+};
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum GpwError {
     Io(io::Error),
-    /// Generic parsing error
-    Parse(&'static str, Option<Box<dyn std::fmt::Debug>>),
+    /// An H3 operation failed, e.g. on an invalid index.
+    H3 {
+        source: h3ron::Error,
+        /// The offending index, if the failure was on one read from
+        /// input.
+        index: Option<u64>,
+        /// Byte offset of the record holding `index`.
+        offset: Option<u64>,
+    },
+    /// A required field was absent.
+    MissingField(&'static str),
+    /// A field or value failed to parse or validate.
+    FieldParse {
+        field: &'static str,
+        /// 1-based line of text input the error was found on, if any.
+        line: Option<usize>,
+        detail: String,
+    },
+}
+
+impl GpwError {
+    /// Attaches the line of text input a parse error was found on.
+    pub fn at_line(self, line: usize) -> Self {
+        match self {
+            GpwError::FieldParse { field, detail, .. } => GpwError::FieldParse {
+                field,
+                line: Some(line),
+                detail,
+            },
+            other => other,
+        }
+    }
+
+    /// Attaches the index an H3 error was raised on and the byte
+    /// offset of the record it was read from.
+    pub fn at_record(self, index: u64, offset: u64) -> Self {
+        match self {
+            GpwError::H3 { source, .. } => GpwError::H3 {
+                source,
+                index: Some(index),
+                offset: Some(offset),
+            },
+            other => other,
+        }
+    }
+}
+
+impl Display for GpwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpwError::Io(e) => write!(f, "{}", e),
+            GpwError::H3 {
+                source,
+                index: Some(index),
+                offset: Some(offset),
+            } => write!(f, "h3: {:x} at offset {}: {:?}", index, offset, source),
+            GpwError::H3 { source, .. } => write!(f, "h3: {:?}", source),
+            GpwError::MissingField(field) => write!(f, "missing field {}", field),
+            GpwError::FieldParse {
+                field,
+                line: Some(line),
+                detail,
+            } => write!(f, "line {}: {}: {}", line, field, detail),
+            GpwError::FieldParse {
+                field,
+                line: None,
+                detail,
+            } => write!(f, "{}: {}", field, detail),
+        }
+    }
+}
+
+/// Lets `?` turn a `GpwError` into an `anyhow::Error` in binaries.
+impl std::error::Error for GpwError {}
+
+/// For `Read` and `Write` impls that surface library errors. I/O
+/// errors pass through as they were; anything else is invalid data.
+impl From<GpwError> for io::Error {
+    fn from(e: GpwError) -> Self {
+        match e {
+            GpwError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/// `io::Error` and `h3ron::Error` aren't comparable, so I/O errors
+/// compare by kind and H3 errors by their debug representation.
+impl PartialEq for GpwError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (GpwError::Io(a), GpwError::Io(b)) => a.kind() == b.kind(),
+            (
+                GpwError::H3 {
+                    source: a,
+                    index: a_index,
+                    offset: a_offset,
+                },
+                GpwError::H3 {
+                    source: b,
+                    index: b_index,
+                    offset: b_offset,
+                },
+            ) => {
+                format!("{:?}", a) == format!("{:?}", b)
+                    && a_index == b_index
+                    && a_offset == b_offset
+            }
+            (GpwError::MissingField(a), GpwError::MissingField(b)) => a == b,
+            (
+                GpwError::FieldParse {
+                    field: a_field,
+                    line: a_line,
+                    detail: a_detail,
+                },
+                GpwError::FieldParse {
+                    field: b_field,
+                    line: b_line,
+                    detail: b_detail,
+                },
+            ) => a_field == b_field && a_line == b_line && a_detail == b_detail,
+            _ => false,
+        }
+    }
 }
 
 impl From<io::Error> for GpwError {
@@ -13,8 +140,22 @@ impl From<io::Error> for GpwError {
     }
 }
 
-impl<E: std::fmt::Debug + 'static> From<(&'static str, E)> for GpwError {
+impl From<h3ron::Error> for GpwError {
+    fn from(e: h3ron::Error) -> Self {
+        GpwError::H3 {
+            source: e,
+            index: None,
+            offset: None,
+        }
+    }
+}
+
+impl<E: Display> From<(&'static str, E)> for GpwError {
     fn from((field, e): (&'static str, E)) -> Self {
-        GpwError::Parse(field, Some(Box::new(e)))
+        GpwError::FieldParse {
+            field,
+            line: None,
+            detail: e.to_string(),
+        }
     }
 }
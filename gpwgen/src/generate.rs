@@ -1,74 +1,475 @@
-use crate::gpwascii::{GpwAscii, GpwAsciiHeader};
-use geo::{coord, line_string, Polygon};
-use hextree::h3ron;
+use crate::{
+    area::cell_area_km2,
+    error::GpwError,
+    gpwascii::{GpwAscii, GpwAsciiHeader},
+    h3tess::{Encoding, Format, H3TessWriter},
+};
+use clap::ValueEnum;
+use geo::{coord, Polygon};
+use hextree::h3ron::{self, FromH3Index, H3Cell};
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
-use std::io::Write;
-
-pub fn tessalate_grid(header: &GpwAsciiHeader, row: usize, col: usize) -> Vec<u64> {
-    let grid_bottom_degs = header.yllcorner + header.cellsize * (header.nrows - row - 1) as f64;
-    let grid_top_degs = grid_bottom_degs + header.cellsize;
-    let grid_left_degs = header.xllcorner + header.cellsize * col as f64;
-    let grid_right_degs = grid_left_degs + header.cellsize;
-
-    let grid_cell_poly = Polygon::new(
-        line_string![
-            // lower-left
-            coord! {x: grid_left_degs, y: grid_bottom_degs},
-            // lower-right
-            coord! {x: grid_right_degs, y: grid_bottom_degs},
-            // upper-right
-            coord! {x: grid_right_degs, y: grid_top_degs},
-            // upper-left
-            coord! {x: grid_left_degs, y: grid_top_degs},
-            // lower-left
-            coord! {x: grid_left_degs, y: grid_bottom_degs}
-        ],
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing::debug;
+
+pub fn tessalate_grid(
+    header: &GpwAsciiHeader,
+    row: usize,
+    col: usize,
+    resolution: u8,
+) -> Result<Vec<u64>, GpwError> {
+    let grid_cell_poly = cell_polygon(header, row, col);
+    let hexes = h3ron::polygon_to_cells(&grid_cell_poly, resolution)?;
+    Ok(hexes.iter().map(|hex| *hex).collect())
+}
+
+/// Returns the grid cell at `row`, `col` as a polygon, `dx` degrees
+/// wide and `dy` degrees tall.
+fn cell_polygon(header: &GpwAsciiHeader, row: usize, col: usize) -> Polygon<f64> {
+    let corners = header.cell_bounds(row, col);
+    // Close the ring back at the lower-left corner.
+    Polygon::new(
+        corners
+            .iter()
+            .chain(&corners[..1])
+            .map(|&(x, y)| coord! {x: x, y: y})
+            .collect(),
         vec![],
-    );
-    // Tesselate at res 10 so we can handle the two coordinate systems
-    // drifting.
-    let hexes = h3ron::polygon_to_cells(&grid_cell_poly, 10).unwrap();
-    hexes.iter().map(|hex| *hex).collect()
-}
-
-pub fn gen_to_disk(src: GpwAscii, dst: &mut impl Write) {
-    let (tx, rx) = std::sync::mpsc::channel::<(Vec<u64>, f32)>();
-
-    let handle = std::thread::spawn(move || {
-        let header = &src.header;
-        let data = &src.data;
-        data.into_par_iter()
-            .enumerate()
-            .for_each_with(tx, |tx, (row_idx, row)| {
-                row.par_iter()
+    )
+}
+
+/// How a grid cell's value is divided among the hexes whose centers
+/// fall inside it.
+///
+/// `Equal` and `AreaWeighted` conserve the value of every cell that
+/// contains at least one hex center; cells too small to contain any
+/// lose theirs. `CentroidOnly` conserves every cell's value. At
+/// resolutions coarser than the grid several cells land in one hex;
+/// [`gen_to_disk`] adds those up into a single record.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Distribution {
+    /// Split the value evenly among the cell's hexes.
+    #[default]
+    Equal,
+    /// Split the value among the cell's hexes in proportion to their
+    /// area.
+    AreaWeighted,
+    /// Give the whole value to the hex containing the cell's center.
+    /// Cells sharing a hex are summed.
+    CentroidOnly,
+}
+
+/// Returns the `(index, value)` pairs the grid cell at `row`, `col`
+/// holding `val` contributes at `resolution`.
+pub fn distribute(
+    header: &GpwAsciiHeader,
+    row: usize,
+    col: usize,
+    val: f32,
+    resolution: u8,
+    distribution: Distribution,
+) -> Result<Vec<(u64, f32)>, GpwError> {
+    match distribution {
+        Distribution::Equal => {
+            let hexes = tessalate_grid(header, row, col, resolution)?;
+            let share = val / hexes.len() as f32;
+            Ok(hexes.into_iter().map(|hex| (hex, share)).collect())
+        }
+        Distribution::AreaWeighted => {
+            let hexes = tessalate_grid(header, row, col, resolution)?
+                .into_iter()
+                .map(|hex| Ok((hex, cell_area_km2(H3Cell::from_h3index(hex))?)))
+                .collect::<Result<Vec<_>, GpwError>>()?;
+            let total_area: f64 = hexes.iter().map(|(_, area)| area).sum();
+            Ok(hexes
+                .into_iter()
+                .map(|(hex, area)| (hex, (f64::from(val) * area / total_area) as f32))
+                .collect())
+        }
+        Distribution::CentroidOnly => {
+            let (x, y) = header.cell_center(row, col);
+            let hex = H3Cell::from_coordinate(coord! {x: x, y: y}, resolution)?;
+            Ok(vec![(*hex, val)])
+        }
+    }
+}
+
+/// Settings for [`gen_to_disk`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenOptions {
+    /// H3 resolution grid cells are tessellated at.
+    pub resolution: u8,
+    /// Size of the thread pool used for tessellation. `None` uses one
+    /// thread per logical core. Without the `parallel` feature
+    /// tessellation always runs on the calling thread.
+    pub threads: Option<usize>,
+    /// Output encoding.
+    pub encoding: Encoding,
+    /// Write the extended h3tess format with per-hex contribution
+    /// counts.
+    pub counts: bool,
+    /// End the h3tess output with a checksum footer.
+    pub checksum: bool,
+    /// How each cell's value is split among its hexes.
+    pub distribution: Distribution,
+    /// Compare every cell's hex count against what its area predicts,
+    /// collecting [`DriftStats`]. Ignored with
+    /// [`Distribution::CentroidOnly`], which tessellates nothing.
+    pub drift_check: bool,
+    /// Write records in grid order, (row, col), instead of as threads
+    /// finish them, so identical inputs produce identical bytes. Every
+    /// record is buffered until tessellation is done, costing about 16
+    /// bytes per output hex on top of the grid itself.
+    pub deterministic: bool,
+}
+
+impl Default for GenOptions {
+    fn default() -> Self {
+        Self {
+            // Tesselate at res 10 so we can handle the two coordinate
+            // systems drifting.
+            resolution: 10,
+            threads: None,
+            encoding: Encoding::H3tess,
+            counts: false,
+            checksum: false,
+            distribution: Distribution::Equal,
+            drift_check: false,
+            deterministic: false,
+        }
+    }
+}
+
+/// Summary of a single [`gen_to_disk`] run.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GenStats {
+    /// Sum of all populated source cells. Every [`Distribution`]
+    /// conserves each cell's value, so barring cells that contain no
+    /// hex center this is also the total written.
+    pub total_population: f64,
+    /// Populated source cells.
+    pub input_cells: usize,
+    /// (index, value) records written.
+    pub pairs: u64,
+    /// Populated source cells containing no hex center, whose
+    /// population is left out of the output.
+    pub dropped: usize,
+    /// Hex count anomalies, if [`GenOptions::drift_check`] was set.
+    pub drift: Option<DriftStats>,
+    /// Whether the run was cancelled, leaving the output holding only
+    /// some of the grid's cells.
+    pub cancelled: bool,
+}
+
+/// Counts of populated grid cells whose hex count is far from what
+/// their area predicts, a sign of a misaligned geotransform or
+/// degenerate cell geometry.
+///
+/// Cells expected to cover fewer than [`DriftStats::MIN_EXPECTED`]
+/// hexes are too small to judge and only count towards `checked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DriftStats {
+    /// Populated cells examined.
+    pub checked: usize,
+    /// Cells that produced no hexes at all, dropping their population.
+    pub empty: usize,
+    /// Cells with fewer than a [`DriftStats::TOLERANCE`]th of their
+    /// expected hexes.
+    pub sparse: usize,
+    /// Cells with more than [`DriftStats::TOLERANCE`] times their
+    /// expected hexes.
+    pub dense: usize,
+}
+
+impl DriftStats {
+    /// Expected hex count below which a cell isn't judged.
+    pub const MIN_EXPECTED: f64 = 2.0;
+    /// Factor a cell's hex count may differ from its expected count
+    /// by. H3 cell areas alone vary by about 2x at a resolution.
+    pub const TOLERANCE: f64 = 4.0;
+
+    /// Total number of anomalous cells.
+    pub fn anomalies(&self) -> usize {
+        self.empty + self.sparse + self.dense
+    }
+
+    fn record(&mut self, hexes: usize, expected: f64) {
+        self.checked += 1;
+        if expected < Self::MIN_EXPECTED {
+            return;
+        }
+        let hexes = hexes as f64;
+        if hexes == 0.0 {
+            self.empty += 1;
+        } else if hexes < expected / Self::TOLERANCE {
+            self.sparse += 1;
+        } else if hexes > expected * Self::TOLERANCE {
+            self.dense += 1;
+        }
+    }
+}
+
+/// Mean Earth radius H3 computes areas with, in km.
+const EARTH_RADIUS_KM: f64 = 6371.007180918475;
+
+/// Number of `resolution` hexes expected to have their center in the
+/// grid cell at `row`, `col`: the cell's spherical area over the
+/// average hex area.
+fn expected_hexes(header: &GpwAsciiHeader, row: usize, col: usize, resolution: u8) -> f64 {
+    let [(left, bottom), _, (right, top), _] = header.cell_bounds(row, col);
+    let area_km2 = EARTH_RADIUS_KM.powi(2)
+        * (right - left).to_radians()
+        * (top.to_radians().sin() - bottom.to_radians().sin());
+    area_km2 / H3Cell::area_avg_km2(resolution).unwrap()
+}
+
+/// A tessellated grid cell: its row, column, pairs and value.
+type Tessellated = (usize, usize, Vec<(u64, f32)>, f32);
+
+/// Tessellates every populated cell of `src` on a pool of
+/// [`GenOptions::threads`] threads, calling `f` with each cell's row,
+/// column, pairs and value as they're done. A single thread, or
+/// [`GenOptions::deterministic`], keeps cells in grid order.
+///
+/// Once `cancel` is set, no further cells are tessellated or passed
+/// to `f`. Returns whether that happened. The first error, from
+/// tessellating a cell or from `f`, stops the pool and is returned.
+#[cfg(feature = "parallel")]
+fn tessellate_cells(
+    src: GpwAscii,
+    opts: &GenOptions,
+    cancel: &AtomicBool,
+    mut f: impl FnMut(usize, usize, Vec<(u64, f32)>, f32) -> Result<(), GpwError>,
+) -> Result<bool, GpwError> {
+    if opts.threads == Some(1) {
+        return tessellate_serial(&src, opts, cancel, f);
+    }
+    let (tx, rx) = std::sync::mpsc::channel::<Result<Tessellated, GpwError>>();
+
+    let resolution = opts.resolution;
+    let distribution = opts.distribution;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads.unwrap_or(0))
+        .build()
+        .map_err(|e| ("threads", e))?;
+    // Set on the first error, so the pool stops picking up cells.
+    let failed = AtomicBool::new(false);
+    let stopped = || cancel.load(Ordering::Relaxed) || failed.load(Ordering::Relaxed);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let header = &src.header;
+            let data = &src.data;
+            pool.install(|| {
+                data.into_par_iter()
                     .enumerate()
-                    .for_each_with(tx.clone(), |tx, (col_idx, sample)| {
-                        if let Some(val) = sample {
-                            let h3_indicies = tessalate_grid(header, row_idx, col_idx);
-                            tx.send((h3_indicies, *val)).unwrap();
-                        }
+                    .for_each_with(tx, |tx, (row_idx, row)| {
+                        debug!(row = row_idx, "tessellating row");
+                        row.par_iter().enumerate().for_each_with(
+                            tx.clone(),
+                            |tx, (col_idx, sample)| {
+                                let Some(val) = sample else {
+                                    return;
+                                };
+                                if stopped() {
+                                    return;
+                                }
+                                let cell = distribute(
+                                    header,
+                                    row_idx,
+                                    col_idx,
+                                    *val,
+                                    resolution,
+                                    distribution,
+                                )
+                                .map(|pairs| (row_idx, col_idx, pairs, *val));
+                                if cell.is_err() {
+                                    failed.store(true, Ordering::Relaxed);
+                                }
+                                // The receiver hangs up once cancelled
+                                // or failed.
+                                let _ = tx.send(cell);
+                            },
+                        )
                     })
             })
-    });
+        });
+
+        let result = (|| -> Result<(), GpwError> {
+            // Completion order depends on thread scheduling; in
+            // deterministic mode collect everything and put it back in
+            // grid order first.
+            let cells: Box<dyn Iterator<Item = Result<Tessellated, GpwError>>> =
+                if opts.deterministic {
+                    let mut cells = rx.into_iter().collect::<Result<Vec<_>, _>>()?;
+                    cells.sort_unstable_by_key(|(row, col, _, _)| (*row, *col));
+                    Box::new(cells.into_iter().map(Ok))
+                } else {
+                    Box::new(rx.into_iter())
+                };
+            for cell in cells {
+                if cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                let (row, col, pairs, val) = cell?;
+                f(row, col, pairs, val)?;
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            failed.store(true, Ordering::Relaxed);
+        }
+        result
+    })?;
+    // Cells skipped by the pool never reach the loop above, so a
+    // cancellation can only be detected after the fact.
+    Ok(cancel.load(Ordering::Relaxed))
+}
+
+/// Tessellates every populated cell of `src` on the calling thread.
+#[cfg(not(feature = "parallel"))]
+fn tessellate_cells(
+    src: GpwAscii,
+    opts: &GenOptions,
+    cancel: &AtomicBool,
+    f: impl FnMut(usize, usize, Vec<(u64, f32)>, f32) -> Result<(), GpwError>,
+) -> Result<bool, GpwError> {
+    tessellate_serial(&src, opts, cancel, f)
+}
+
+/// Tessellates every populated cell of `src` in grid order on the
+/// calling thread, calling `f` with each cell's row, column, pairs and
+/// value until `cancel` is set. Returns whether it was, or the first
+/// error.
+fn tessellate_serial(
+    src: &GpwAscii,
+    opts: &GenOptions,
+    cancel: &AtomicBool,
+    mut f: impl FnMut(usize, usize, Vec<(u64, f32)>, f32) -> Result<(), GpwError>,
+) -> Result<bool, GpwError> {
+    let mut prev_row = None;
+    for (row, col, val) in src.populated() {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        if prev_row != Some(row) {
+            debug!(row, "tessellating row");
+            prev_row = Some(row);
+        }
+        let pairs = distribute(
+            &src.header,
+            row,
+            col,
+            val,
+            opts.resolution,
+            opts.distribution,
+        )?;
+        f(row, col, pairs, val)?;
+    }
+    Ok(false)
+}
+
+pub fn gen_to_disk(
+    src: GpwAscii,
+    opts: &GenOptions,
+    dst: &mut impl Write,
+) -> Result<GenStats, GpwError> {
+    gen_to_disk_cancellable(src, opts, dst, &AtomicBool::new(false))
+}
 
-    while let Ok((h3_indicies, val)) = rx.recv() {
-        let scaled_val = val / h3_indicies.len() as f32;
-        let scaled_val_bytes = scaled_val.to_le_bytes();
-        for h3_index in h3_indicies {
-            dst.write_all(&h3_index.to_le_bytes()).unwrap();
-            dst.write_all(&scaled_val_bytes).unwrap();
+/// Like [`gen_to_disk`], stopping at the next grid cell once `cancel`
+/// is set. The output is still finished properly, checksum and all,
+/// so it reads back as a valid file holding the cells done so far;
+/// [`GenStats::cancelled`] tells it apart from a complete one.
+pub fn gen_to_disk_cancellable(
+    src: GpwAscii,
+    opts: &GenOptions,
+    dst: &mut impl Write,
+    cancel: &AtomicBool,
+) -> Result<GenStats, GpwError> {
+    let mut stats = GenStats {
+        drift: (opts.drift_check && opts.distribution != Distribution::CentroidOnly)
+            .then(DriftStats::default),
+        ..Default::default()
+    };
+    let header = src.header.clone();
+    // Centroid-only cells can share a hex. `combine` keeps a single
+    // record per hex, so sum them here, in f64, counting each cell,
+    // and write one record per hex once the grid is done.
+    let mut merged = (opts.distribution == Distribution::CentroidOnly).then(BTreeMap::new);
+    let mut wtr = H3TessWriter::with_encoding(
+        dst,
+        opts.encoding,
+        Format {
+            counts: opts.counts,
+            checksum: opts.checksum,
+            ..Default::default()
+        },
+    )?;
+    let cancelled = tessellate_cells(src, opts, cancel, |row, col, pairs, val| {
+        stats.total_population += f64::from(val);
+        stats.input_cells += 1;
+        stats.pairs += pairs.len() as u64;
+        if pairs.is_empty() {
+            stats.dropped += 1;
+        }
+        if let Some(drift) = stats.drift.as_mut() {
+            let expected = expected_hexes(&header, row, col, opts.resolution);
+            drift.record(pairs.len(), expected);
+            if pairs.is_empty() {
+                debug!(row, col, expected, "grid cell covers no hexes");
+            }
+        }
+        for (h3_index, share) in pairs {
+            if let Some(merged) = merged.as_mut() {
+                let (sum, count) = merged.entry(h3_index).or_insert((0.0_f64, 0_u32));
+                *sum += f64::from(share);
+                *count += 1;
+                continue;
+            }
+            // Each hex's center lies in exactly one grid cell, so a
+            // single cell contributes to it.
+            wtr.write_record(H3Cell::from_h3index(h3_index), share, 1)?;
+        }
+        Ok(())
+    })?;
+    if let Some(merged) = merged {
+        stats.pairs = merged.len() as u64;
+        for (h3_index, (sum, count)) in merged {
+            wtr.write_record(H3Cell::from_h3index(h3_index), sum as f32, count)?;
         }
     }
-    handle.join().unwrap();
+    stats.cancelled = cancelled;
+    wtr.finish()?;
+    Ok(stats)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{
-        fs::File,
-        io::{BufReader, BufWriter, Cursor},
-    };
+    use crate::h3tess::H3TessReader;
+    use byteorder::{LittleEndian as LE, ReadBytesExt};
+    use std::io::{BufReader, Cursor};
+
+    /// A 4 x 4 grid with a single populated cell.
+    const FIXTURE: &str = include_str!("../testdata/fixture.asc");
+
+    /// A small populated grid, with a couple of NODATA cells, for
+    /// comparing runs with different options.
+    const GRID_6X4: &str = r#"ncols 6
+nrows 4
+xllcorner 8
+yllcorner 47
+cellsize 0.0083333333333333
+NODATA_value -9999
+1 2 3 4 5 6
+7 -9999 9 10 11 12
+13 14 15 -9999 17 18
+19 20 21 22 23 24
+"#;
 
     #[test]
     fn test_parse_header() {
@@ -85,37 +486,433 @@ NODATA_value  -9999
 
     #[test]
     fn test_parse() {
-        let file = r#"ncols         4
-nrows         4
+        let mut rdr = BufReader::new(Cursor::new(FIXTURE));
+        GpwAscii::parse(&mut rdr).unwrap();
+    }
+
+    #[test]
+    fn test_gen_to_disk() {
+        let mut rdr = BufReader::new(Cursor::new(FIXTURE));
+        let data = GpwAscii::parse(&mut rdr).unwrap();
+        let expected_hexes = tessalate_grid(&data.header, 3, 2, 10).unwrap();
+        let mut dst = Vec::new();
+        gen_to_disk(data, &GenOptions::default(), &mut dst).unwrap();
+
+        // One 12 byte (u64 index, f32 value) record per hex covering
+        // the single populated grid cell.
+        assert_eq!(dst.len(), expected_hexes.len() * 12);
+        let mut rdr = Cursor::new(dst);
+        let mut hexes = Vec::new();
+        while let (Ok(h3_index), Ok(val)) = (rdr.read_u64::<LE>(), rdr.read_f32::<LE>()) {
+            assert_eq!(val, 0.123 / expected_hexes.len() as f32);
+            hexes.push(h3_index);
+        }
+        hexes.sort_unstable();
+        let mut expected_hexes = expected_hexes;
+        expected_hexes.sort_unstable();
+        assert_eq!(hexes, expected_hexes);
+    }
+
+    #[test]
+    fn test_gen_to_disk_total_population() {
+        let mut rdr = BufReader::new(Cursor::new(FIXTURE));
+        let data = GpwAscii::parse(&mut rdr).unwrap();
+        let hexes = tessalate_grid(&data.header, 3, 2, 10).unwrap().len();
+        let stats = gen_to_disk(data, &GenOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(stats.total_population, f64::from(0.123_f32));
+        assert_eq!(stats.input_cells, 1);
+        assert_eq!(stats.pairs, hexes as u64);
+        assert_eq!(stats.dropped, 0);
+    }
+
+    /// Collects output, setting `cancel` once more than `limit` bytes
+    /// have been written.
+    struct CancelAfter<'a> {
+        buf: Vec<u8>,
+        limit: usize,
+        cancel: &'a AtomicBool,
+    }
+
+    impl Write for CancelAfter<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            if self.buf.len() > self.limit {
+                self.cancel.store(true, Ordering::Relaxed);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_gen_to_disk_cancel() {
+        let file = r#"ncols         3
+nrows         2
 xllcorner     -180
 yllcorner     -4.2632564145606e-14
 cellsize      0.0083333333333333
 NODATA_value  -9999
--9999 -9999 -9999 -9999
--9999 -9999 -9999 -9999
--9999 -9999 -9999 -9999
--9999 -9999 0.123 -9999
+1 2 3
+4 -9999 6
 "#;
-        let mut rdr = BufReader::new(Cursor::new(file));
-        GpwAscii::parse(&mut rdr).unwrap();
+        let run = |threads, limit| {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(file))).unwrap();
+            let opts = GenOptions {
+                threads: Some(threads),
+                checksum: true,
+                ..Default::default()
+            };
+            let cancel = AtomicBool::new(false);
+            let mut dst = CancelAfter {
+                buf: Vec::new(),
+                limit,
+                cancel: &cancel,
+            };
+            let stats = gen_to_disk_cancellable(data, &opts, &mut dst, &cancel).unwrap();
+            let records = crate::h3tess::verify(dst.buf.as_slice(), false).unwrap();
+            (stats, records)
+        };
+
+        let (full, records) = run(1, usize::MAX);
+        assert!(!full.cancelled);
+        assert_eq!(full.input_cells, 5);
+        assert_eq!(records, full.pairs);
+
+        // Cancelled by the first cell's records, leaving a valid file
+        // with only those.
+        let (cancelled, records) = run(1, crate::h3tess::HEADER_LEN);
+        assert!(cancelled.cancelled);
+        assert_eq!(cancelled.input_cells, 1);
+        assert_eq!(records, cancelled.pairs);
+        assert!(records < full.pairs);
+
+        // Cancelled as soon as the header is out.
+        let (cancelled, records) = run(2, 0);
+        assert!(cancelled.cancelled);
+        assert_eq!(cancelled.input_cells, 0);
+        assert_eq!(records, 0);
+    }
+
+    /// Takes `room` bytes, then fails every write like a full disk.
+    struct Full {
+        room: usize,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() > self.room {
+                return Err(std::io::Error::other("no space left"));
+            }
+            self.room -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
-    fn test_gen_to_disk() {
-        let file = r#"ncols         4
-nrows         4
+    fn test_gen_to_disk_write_error() {
+        // The disk fills up a few records in.
+        for (threads, deterministic) in [(1, false), (4, false), (4, true)] {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(GRID_6X4))).unwrap();
+            let opts = GenOptions {
+                threads: Some(threads),
+                deterministic,
+                ..Default::default()
+            };
+            let err = gen_to_disk(data, &opts, &mut Full { room: 3 * 12 }).unwrap_err();
+            assert!(
+                matches!(&err, GpwError::Io(e) if e.to_string() == "no space left"),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_gen_to_disk_thread_count() {
+        let file = r#"ncols         3
+nrows         2
 xllcorner     -180
 yllcorner     -4.2632564145606e-14
 cellsize      0.0083333333333333
 NODATA_value  -9999
--9999 -9999 -9999 -9999
--9999 -9999 -9999 -9999
--9999 -9999 -9999 -9999
--9999 -9999 0.123 -9999
+1 2 3
+4 -9999 6
 "#;
-        let mut rdr = BufReader::new(Cursor::new(file));
-        let data = GpwAscii::parse(&mut rdr).unwrap();
-        let mut dst = BufWriter::new(File::create("/Users/jay/he/gpw/out.indicies").unwrap());
-        gen_to_disk(data, &mut dst);
+        let records = |threads| {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(file))).unwrap();
+            let opts = GenOptions {
+                threads: Some(threads),
+                ..Default::default()
+            };
+            let mut dst = Vec::new();
+            gen_to_disk(data, &opts, &mut dst).unwrap();
+            // Records arrive in completion order, so compare them as
+            // a set.
+            let mut records: Vec<&[u8]> = dst.chunks(12).collect();
+            records.sort_unstable();
+            records.concat()
+        };
+        assert_eq!(records(1), records(4));
+    }
+
+    #[test]
+    fn test_gen_to_disk_deterministic() {
+        let run = |threads| {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(GRID_6X4))).unwrap();
+            let opts = GenOptions {
+                threads: Some(threads),
+                deterministic: true,
+                ..Default::default()
+            };
+            let mut dst = Vec::new();
+            let stats = gen_to_disk(data, &opts, &mut dst).unwrap();
+            (dst, stats)
+        };
+        let (first, first_stats) = run(4);
+        assert!(!first.is_empty());
+        for threads in [4, 4, 1] {
+            let (dst, stats) = run(threads);
+            assert_eq!(dst, first);
+            assert_eq!(stats, first_stats);
+        }
+    }
+
+    #[test]
+    fn test_gen_to_disk_single_threaded() {
+        let run = |threads, deterministic| {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(GRID_6X4))).unwrap();
+            let opts = GenOptions {
+                threads: Some(threads),
+                deterministic,
+                drift_check: true,
+                ..Default::default()
+            };
+            let mut dst = Vec::new();
+            let stats = gen_to_disk(data, &opts, &mut dst).unwrap();
+            (dst, stats)
+        };
+        // A single thread works through the grid in order, just like
+        // the pool does in deterministic mode.
+        let (serial, serial_stats) = run(1, false);
+        let (parallel, parallel_stats) = run(4, true);
+        assert!(!serial.is_empty());
+        assert_eq!(serial, parallel);
+        assert_eq!(serial_stats, parallel_stats);
+    }
+
+    #[test]
+    fn test_gen_to_disk_text_encodings() {
+        let run = |encoding| {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(FIXTURE))).unwrap();
+            let opts = GenOptions {
+                encoding,
+                ..Default::default()
+            };
+            let mut dst = Vec::new();
+            gen_to_disk(data, &opts, &mut dst).unwrap();
+            dst
+        };
+        let binary = run(Encoding::H3tess);
+        let pairs = H3TessReader::new(binary.as_slice())
+            .map(|record| record.unwrap())
+            .collect::<Vec<_>>();
+        assert!(!pairs.is_empty());
+
+        // Records arrive in completion order, so compare rows as sets.
+        let sorted = |mut rows: Vec<String>| {
+            rows.sort_unstable();
+            rows
+        };
+        let csv = String::from_utf8(run(Encoding::Csv)).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("h3_index,value"));
+        assert_eq!(
+            sorted(lines.map(str::to_string).collect()),
+            sorted(
+                pairs
+                    .iter()
+                    .map(|(cell, val)| format!("{:x},{}", **cell, val))
+                    .collect()
+            )
+        );
+
+        let ndjson = String::from_utf8(run(Encoding::Ndjson)).unwrap();
+        let mut rows = ndjson
+            .lines()
+            .map(|line| {
+                let row: serde_json::Value = serde_json::from_str(line).unwrap();
+                let cell = u64::from_str_radix(row["h3_index"].as_str().unwrap(), 16).unwrap();
+                (cell, row["value"].as_f64().unwrap() as f32)
+            })
+            .collect::<Vec<_>>();
+        let mut expected = pairs
+            .iter()
+            .map(|(cell, val)| (**cell, *val))
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|(cell, _)| *cell);
+        expected.sort_by_key(|(cell, _)| *cell);
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_distributions() {
+        let src = "ncols 3\nnrows 2\nxllcorner 8\nyllcorner 47\ncellsize 0.0083333333333333\nNODATA_value -9999\n1 2 3\n4 -9999 6\n";
+        let run = |distribution| {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(src))).unwrap();
+            let opts = GenOptions {
+                distribution,
+                ..Default::default()
+            };
+            let mut dst = Vec::new();
+            gen_to_disk(data, &opts, &mut dst).unwrap();
+            H3TessReader::new(dst.as_slice())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let total = |pairs: &[(H3Cell, f32)]| pairs.iter().map(|(_, v)| f64::from(*v)).sum::<f64>();
+
+        let equal = run(Distribution::Equal);
+        let weighted = run(Distribution::AreaWeighted);
+        assert!((total(&equal) - 16.0).abs() < 1e-4, "{}", total(&equal));
+        assert!(
+            (total(&weighted) - 16.0).abs() < 1e-4,
+            "{}",
+            total(&weighted)
+        );
+        // Same hexes, differently weighted.
+        assert_eq!(equal.len(), weighted.len());
+        assert_ne!(equal, weighted);
+
+        let centroids = run(Distribution::CentroidOnly);
+        let header = GpwAscii::parse(&mut BufReader::new(Cursor::new(src)))
+            .unwrap()
+            .header;
+        let mut expected = [
+            (0, 0, 1.0),
+            (0, 1, 2.0),
+            (0, 2, 3.0),
+            (1, 0, 4.0),
+            (1, 2, 6.0),
+        ]
+        .map(|(row, col, val)| {
+            let (x, y) = header.cell_center(row, col);
+            (
+                H3Cell::from_coordinate(coord! {x: x, y: y}, 10).unwrap(),
+                val,
+            )
+        });
+        let mut centroids = centroids;
+        centroids.sort_by_key(|(cell, _)| **cell);
+        expected.sort_by_key(|(cell, _)| **cell);
+        assert_eq!(centroids, expected);
+    }
+
+    #[test]
+    fn test_centroid_only_shared_hex() {
+        use hextree::h3ron::ToCoordinate;
+        // Two ~1 km cells side by side, right at the center of a res 5
+        // hex (~250 km²), so both land in it.
+        let expected = H3Cell::from_coordinate(coord! {x: 8.5, y: 47.3}, 5).unwrap();
+        let center = expected.to_coordinate().unwrap();
+        let src = format!(
+            "ncols 2\nnrows 1\nxllcorner {}\nyllcorner {}\ncellsize 0.0083333333333333\nNODATA_value -9999\n1.5 2.5\n",
+            center.x - 0.0083333333333333,
+            center.y - 0.0041666666666667,
+        );
+
+        for threads in [1, 4] {
+            let data = GpwAscii::parse(&mut BufReader::new(Cursor::new(&src))).unwrap();
+            let opts = GenOptions {
+                resolution: 5,
+                threads: Some(threads),
+                counts: true,
+                distribution: Distribution::CentroidOnly,
+                ..Default::default()
+            };
+            let mut dst = Vec::new();
+            let stats = gen_to_disk(data, &opts, &mut dst).unwrap();
+            assert_eq!((stats.input_cells, stats.pairs), (2, 1));
+            let records = H3TessReader::new(dst.as_slice())
+                .counted()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(records, vec![(expected, 4.0, 2)]);
+        }
+    }
+
+    #[test]
+    fn test_non_square_cell() {
+        let header = GpwAsciiHeader {
+            ncols: 2,
+            nrows: 2,
+            xllcorner: 8.0,
+            yllcorner: 47.0,
+            cellsize: 0.02,
+            dx: Some(0.02),
+            dy: Some(0.005),
+            nodata_value: "-9999".to_string(),
+        };
+        let poly = cell_polygon(&header, 0, 1);
+        let bounds = geo::BoundingRect::bounding_rect(&poly).unwrap();
+        assert!((bounds.width() - 0.02).abs() < 1e-12);
+        assert!((bounds.height() - 0.005).abs() < 1e-12);
+        assert!((bounds.min().x - 8.02).abs() < 1e-12);
+        assert!((bounds.min().y - 47.005).abs() < 1e-12);
+
+        // Hexes covering the cell spread further east-west than
+        // north-south.
+        use hextree::h3ron::ToCoordinate;
+        let centers = tessalate_grid(&header, 0, 1, 12)
+            .unwrap()
+            .into_iter()
+            .map(|h3_index| H3Cell::from_h3index(h3_index).to_coordinate().unwrap())
+            .collect::<Vec<_>>();
+        let spread = |f: fn(&geo::Coord<f64>) -> f64| {
+            let (min, max) = centers
+                .iter()
+                .map(f)
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+                    (lo.min(v), hi.max(v))
+                });
+            max - min
+        };
+        assert!(spread(|c| c.x) > 2.0 * spread(|c| c.y));
+    }
+
+    #[test]
+    fn test_drift_check() {
+        let grid = |xllcorner: f64| {
+            let src = format!(
+                "ncols 4\nnrows 2\nxllcorner {}\nyllcorner 47\ncellsize 0.0083333333333333\nNODATA_value -9999\n1 1 1 1\n1 1 1 1\n",
+                xllcorner
+            );
+            GpwAscii::parse(&mut BufReader::new(Cursor::new(src))).unwrap()
+        };
+        let opts = GenOptions {
+            drift_check: true,
+            ..Default::default()
+        };
+
+        let stats = gen_to_disk(grid(8.0), &opts, &mut Vec::new()).unwrap();
+        let drift = stats.drift.unwrap();
+        assert_eq!(drift.checked, 8);
+        assert_eq!(drift.anomalies(), 0);
+
+        // A header placing the grid's last columns past the
+        // antimeridian leaves them with degenerate geometry.
+        let stats = gen_to_disk(grid(179.99), &opts, &mut Vec::new()).unwrap();
+        let drift = stats.drift.unwrap();
+        assert_eq!(drift.checked, 8);
+        assert!(drift.anomalies() > 0, "{:?}", drift);
+
+        let stats = gen_to_disk(grid(8.0), &GenOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(stats.drift, None);
     }
 }
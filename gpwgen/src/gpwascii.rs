@@ -1,5 +1,8 @@
 use crate::error::GpwError;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
+use tracing::warn;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
 
 // $ head -n6    gpw_v4_population_count_rev11_2020_30_sec_1.asc
 // ncols         10800
@@ -8,114 +11,302 @@ use std::io::{BufRead, BufReader};
 // yllcorner     -4.2632564145606e-14
 // cellsize      0.0083333333333333
 // NODATA_value  -9999
+//
+// Grids with non-square cells give `dx` and `dy` instead of (or as
+// well as) `cellsize`.
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct GpwAsciiHeader {
     pub ncols: usize,
     pub nrows: usize,
     pub xllcorner: f64,
     pub yllcorner: f64,
+    /// Cell size in degrees. Headers with only `dx`/`dy` take it from
+    /// `dx`.
     pub cellsize: f64,
+    /// East-west cell size in degrees, if it differs from `cellsize`.
+    pub dx: Option<f64>,
+    /// North-south cell size in degrees, if it differs from
+    /// `cellsize`.
+    pub dy: Option<f64>,
     pub nodata_value: String,
 }
 
 impl GpwAsciiHeader {
-    pub fn parse<R: std::io::Read>(rdr: &mut BufReader<R>) -> Result<Self, GpwError> {
+    /// Parses the header, which ends at the first line that looks
+    /// like data. Finding that line means reading it, so it's returned
+    /// along with the header for the caller to parse as the first row;
+    /// it's empty if the reader ran out first.
+    pub fn parse<R: std::io::Read>(rdr: &mut BufReader<R>) -> Result<(Self, Vec<u8>), GpwError> {
+        let (header, _, first_row) = Self::parse_counting_lines(rdr)?;
+        Ok((header, first_row))
+    }
+
+    /// Like [`Self::parse`], also returning the number of lines the
+    /// header took up and the first data line, which is empty if the
+    /// reader ran out first.
+    fn parse_counting_lines<R: std::io::Read>(
+        rdr: &mut BufReader<R>,
+    ) -> Result<(Self, usize, Vec<u8>), GpwError> {
         let mut ncols: Option<usize> = None;
         let mut nrows: Option<usize> = None;
         let mut xllcorner: Option<f64> = None;
         let mut yllcorner: Option<f64> = None;
         let mut cellsize: Option<f64> = None;
+        let mut dx: Option<f64> = None;
+        let mut dy: Option<f64> = None;
         let mut nodata_value: Option<String> = None;
 
-        for _ in 0..6 {
-            let mut line = String::new();
-            rdr.read_line(&mut line)?;
+        // Some tools prefix text exports with a UTF-8 byte order
+        // mark, which would otherwise stick to the first header key.
+        if rdr.fill_buf()?.starts_with(UTF8_BOM) {
+            rdr.consume(UTF8_BOM.len());
+        }
+
+        // Header lines are `key value` pairs in no particular order;
+        // the header ends at the first line that looks like data.
+        // Lines are read whole before deciding, as a short read can
+        // leave only a prefix such as `-` or `1e` in the buffer.
+        let mut line_no = 0;
+        let mut raw_line = Vec::new();
+        while 0 != rdr.read_until(b'\n', &mut raw_line)? {
+            if Self::is_first_data_line(&raw_line, nodata_value.as_deref(), ncols) {
+                break;
+            }
+            line_no += 1;
+            let at_line = |e: GpwError| e.at_line(line_no);
+            let line = std::str::from_utf8(&raw_line)
+                .map_err(|e| GpwError::from(("header", e)))
+                .map_err(at_line)?;
+            let line = trim_line_ending(line);
             let mut tokens = line.split_whitespace();
-            if let Some(token) = tokens.next() {
-                match token {
-                    "ncols" => {
-                        ncols = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("ncols", None))?
-                                .parse::<usize>()
-                                .map_err(|e| ("ncols", e))?,
-                        );
-                    }
-                    "nrows" => {
-                        nrows = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("nrows", None))?
-                                .parse::<usize>()
-                                .map_err(|e| ("ncols", e))?,
-                        );
-                    }
-                    "xllcorner" => {
-                        xllcorner = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("xllcorner", None))?
-                                .parse::<f64>()
-                                .map_err(|e| ("xllcorner", e))?,
-                        );
-                    }
-                    "yllcorner" => {
-                        yllcorner = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("yllcorner", None))?
-                                .parse::<f64>()
-                                .map_err(|e| ("yllcorner", e))?,
-                        );
-                    }
-                    "cellsize" => {
-                        cellsize = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("cellsize", None))?
-                                .parse::<f64>()
-                                .map_err(|e| ("cellsize", e))?,
-                        );
-                    }
-                    "NODATA_value" => {
-                        nodata_value = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("NODATA_value", None))?
-                                .to_string(),
-                        );
-                    }
-                    unexpected_token => {
-                        Err(("unexpected header token", unexpected_token.to_string()))?
-                    }
+            let (key, value) = (tokens.next(), tokens.next());
+            match key {
+                None => (),
+                Some(comment) if comment.starts_with('#') => (),
+                Some("ncols") => ncols = Some(field_value("ncols", value).map_err(at_line)?),
+                Some("nrows") => nrows = Some(field_value("nrows", value).map_err(at_line)?),
+                Some("xllcorner") => {
+                    xllcorner = Some(field_value("xllcorner", value).map_err(at_line)?)
+                }
+                Some("yllcorner") => {
+                    yllcorner = Some(field_value("yllcorner", value).map_err(at_line)?)
+                }
+                Some("cellsize") => {
+                    cellsize = Some(field_value("cellsize", value).map_err(at_line)?)
+                }
+                Some("dx") => dx = Some(field_value("dx", value).map_err(at_line)?),
+                Some("dy") => dy = Some(field_value("dy", value).map_err(at_line)?),
+                Some("NODATA_value") => {
+                    let rest = line.trim_start()["NODATA_value".len()..].trim();
+                    nodata_value = Some(nodata_field(rest).map_err(at_line)?)
+                }
+                Some(unknown_key) => {
+                    warn!("ignoring unknown header key {:?}", unknown_key);
                 }
             }
+            raw_line.clear();
         }
 
-        if let (
-            Some(ncols),
-            Some(nrows),
-            Some(xllcorner),
-            Some(yllcorner),
-            Some(cellsize),
-            Some(nodata_value),
-        ) = (ncols, nrows, xllcorner, yllcorner, cellsize, nodata_value)
-        {
-            Ok(Self {
-                ncols,
-                nrows,
-                xllcorner,
-                yllcorner,
-                cellsize,
-                nodata_value,
-            })
-        } else {
-            Err(GpwError::Parse("incomplete header", None))
+        let header = Self {
+            ncols: ncols.ok_or(GpwError::MissingField("ncols"))?,
+            nrows: nrows.ok_or(GpwError::MissingField("nrows"))?,
+            xllcorner: xllcorner.ok_or(GpwError::MissingField("xllcorner"))?,
+            yllcorner: yllcorner.ok_or(GpwError::MissingField("yllcorner"))?,
+            // Without a `cellsize`, both `dx` and `dy` are needed.
+            cellsize: cellsize
+                .or(dy.and(dx))
+                .ok_or(GpwError::MissingField("cellsize"))?,
+            dx,
+            dy,
+            nodata_value: nodata_value.ok_or(GpwError::MissingField("NODATA_value"))?,
+        };
+        header.validate()?;
+        Ok((header, line_no, raw_line))
+    }
+
+    /// Checks that the header describes a sane grid on the globe.
+    pub fn validate(&self) -> Result<(), GpwError> {
+        if self.ncols == 0 {
+            Err(("ncols", "must be nonzero"))?
+        }
+        if self.nrows == 0 {
+            Err(("nrows", "must be nonzero"))?
+        }
+        if !(-180.0..=180.0).contains(&self.xllcorner) {
+            Err((
+                "xllcorner",
+                format!("{} is outside [-180, 180]", self.xllcorner),
+            ))?
+        }
+        if !(-90.0..=90.0).contains(&self.yllcorner) {
+            Err((
+                "yllcorner",
+                format!("{} is outside [-90, 90]", self.yllcorner),
+            ))?
+        }
+        for (field, size) in [
+            ("cellsize", self.cellsize),
+            ("dx", self.dx()),
+            ("dy", self.dy()),
+        ] {
+            if size.is_nan() || size <= 0.0 {
+                Err((field, format!("{} is not positive", size)))?
+            }
+        }
+        Ok(())
+    }
+
+    /// East-west size of a cell in degrees.
+    pub fn dx(&self) -> f64 {
+        self.dx.unwrap_or(self.cellsize)
+    }
+
+    /// North-south size of a cell in degrees.
+    pub fn dy(&self) -> f64 {
+        self.dy.unwrap_or(self.cellsize)
+    }
+
+    /// Returns the header of this grid with every `factor` x `factor`
+    /// block of cells merged into one, covering the same extent.
+    ///
+    /// `factor` must divide both `nrows` and `ncols`. Otherwise the
+    /// last row and column of blocks would be smaller than the rest,
+    /// which a header can't describe, and padding them out to full
+    /// cells would spread their population past the grid's edges.
+    pub fn coarsen(&self, factor: usize) -> Result<Self, GpwError> {
+        if factor == 0 || !self.nrows.is_multiple_of(factor) || !self.ncols.is_multiple_of(factor) {
+            Err((
+                "coarsen",
+                format!(
+                    "{} does not divide a {} x {} grid",
+                    factor, self.nrows, self.ncols
+                ),
+            ))?
+        }
+        let header = Self {
+            ncols: self.ncols / factor,
+            nrows: self.nrows / factor,
+            cellsize: self.cellsize * factor as f64,
+            dx: self.dx.map(|dx| dx * factor as f64),
+            dy: self.dy.map(|dy| dy * factor as f64),
+            ..self.clone()
+        };
+        header.validate()?;
+        Ok(header)
+    }
+
+    /// Returns the `(longitude, latitude)` corners of the grid cell at
+    /// `row`, `col` as lower-left, lower-right, upper-right and
+    /// upper-left.
+    ///
+    /// Row 0 is the northernmost row, so a cell's bottom edge lies
+    /// `nrows - row - 1` cells above `yllcorner`.
+    pub fn cell_bounds(&self, row: usize, col: usize) -> [(f64, f64); 4] {
+        let bottom = self.yllcorner + self.dy() * (self.nrows - row - 1) as f64;
+        let top = bottom + self.dy();
+        let left = self.xllcorner + self.dx() * col as f64;
+        let right = left + self.dx();
+        [(left, bottom), (right, bottom), (right, top), (left, top)]
+    }
+
+    /// Returns the `(longitude, latitude)` center of the grid cell at
+    /// `row`, `col`.
+    pub fn cell_center(&self, row: usize, col: usize) -> (f64, f64) {
+        let [(left, bottom), _, (right, top), _] = self.cell_bounds(row, col);
+        ((left + right) / 2.0, (bottom + top) / 2.0)
+    }
+
+    /// Whether `line` is a row of data, ending the header: all its
+    /// tokens are numbers or NODATA. Keys such as `nan` or `inf` parse
+    /// as numbers too, so a line starting with a non-finite token only
+    /// counts once it has `ncols` tokens.
+    fn is_first_data_line(line: &[u8], nodata_value: Option<&str>, ncols: Option<usize>) -> bool {
+        let line = String::from_utf8_lossy(line);
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let numeric = |token: &str, is_number: fn(f64) -> bool| {
+            Some(token) == nodata_value || token.parse::<f64>().is_ok_and(is_number)
+        };
+        match tokens.first() {
+            None => false,
+            Some(first) => {
+                tokens.iter().all(|token| numeric(token, |_| true))
+                    && (numeric(first, f64::is_finite) || Some(tokens.len()) == ncols)
+            }
+        }
+    }
+}
+
+/// Parses the value token of header field `field`.
+fn field_value<T>(field: &'static str, value: Option<&str>) -> Result<T, GpwError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    Ok(value
+        .ok_or((field, "missing value"))?
+        .parse::<T>()
+        .map_err(|e| (field, e))?)
+}
+
+/// Whether `cells` is within rounding error of a whole number, for
+/// checking that tile corners line up.
+fn nearly_whole(cells: f64) -> bool {
+    (cells - cells.round()).abs() < 1e-6
+}
+
+/// Parses the rest of a `NODATA_value` line. Some dialects quote the
+/// value, in which case it's everything up to the closing quote;
+/// anything after an unquoted value is ignored.
+fn nodata_field(rest: &str) -> Result<String, GpwError> {
+    let value = match rest.chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            rest[1..]
+                .split_once(quote)
+                .ok_or(("NODATA_value", "unterminated quote"))?
+                .0
         }
+        _ => rest.split_whitespace().next().unwrap_or_default(),
+    };
+    if value.is_empty() {
+        Err(("NODATA_value", "missing value"))?
     }
+    Ok(value.to_string())
+}
+
+/// Whether a line of the data section holds a row. Blank lines and `#`
+/// comments don't; no data line starts with either.
+fn is_data_line(line: &[u8]) -> bool {
+    !matches!(
+        line.iter().find(|b| !b.is_ascii_whitespace()),
+        None | Some(b'#')
+    )
+}
+
+/// Longest prefix of a line quoted in error messages.
+const SNIPPET_LEN: usize = 40;
+
+/// Quotes the start of `line`, without its line ending, for error
+/// messages.
+fn snippet(line: &[u8]) -> String {
+    let text = String::from_utf8_lossy(line);
+    let text = trim_line_ending(&text);
+    match text.char_indices().nth(SNIPPET_LEN) {
+        Some((end, _)) => format!("{:?}...", &text[..end]),
+        None => format!("{:?}", text),
+    }
+}
+
+/// Strips a trailing `\n` or `\r\n` so files written on Windows
+/// parse the same as their Unix counterparts.
+fn trim_line_ending(line: &str) -> &str {
+    line.trim_end_matches(['\r', '\n'])
 }
 
+/// Rows [`GpwAscii::parse_parallel`] reads before parsing them
+/// together.
+#[cfg(feature = "parallel")]
+pub const PARALLEL_CHUNK_ROWS: usize = 256;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct GpwAscii {
     pub header: GpwAsciiHeader,
@@ -123,37 +314,1095 @@ pub struct GpwAscii {
     pub filename: Option<String>,
 }
 
+/// Knobs controlling how permissive [`GpwAscii::parse_with`] is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseOptions {
+    /// Largest `nrows * ncols` a header may claim before parsing
+    /// bails out instead of allocating storage for it.
+    pub max_cells: usize,
+    /// What to do with `nan`/`inf` cells.
+    pub non_finite: NonFinitePolicy,
+    /// Accept data that ends before `nrows` rows, as a truncated
+    /// download does, padding the missing rows with NODATA instead
+    /// of failing.
+    pub allow_partial: bool,
+}
+
+/// How to handle cells that parse to a non-finite float.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonFinitePolicy {
+    /// Fail parsing with an error naming the offending cell.
+    #[default]
+    Reject,
+    /// Treat the cell as if it held the NODATA value.
+    Nodata,
+}
+
+impl ParseOptions {
+    /// Cell count of the global GPW grid at 30 arc-second
+    /// resolution, the largest product we expect to see.
+    pub const DEFAULT_MAX_CELLS: usize = 43_200 * 21_600;
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            max_cells: Self::DEFAULT_MAX_CELLS,
+            non_finite: NonFinitePolicy::default(),
+            allow_partial: false,
+        }
+    }
+}
+
 impl GpwAscii {
     pub fn parse<R: std::io::Read>(rdr: &mut BufReader<R>) -> Result<Self, GpwError> {
-        let header = GpwAsciiHeader::parse(rdr)?;
+        Self::parse_with(rdr, &ParseOptions::default())
+    }
+
+    /// Parses one line of whitespace-separated cells.
+    fn parse_row(
+        line: &[u8],
+        row_idx: usize,
+        header: &GpwAsciiHeader,
+        opts: &ParseOptions,
+    ) -> Result<Vec<Option<f32>>, GpwError> {
+        let mut row = Vec::with_capacity(header.ncols);
+        let cells = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|cell| !cell.is_empty());
+        for (col_idx, cell) in cells.enumerate() {
+            let sample = if cell == header.nodata_value.as_bytes() {
+                None
+            } else {
+                let cell = std::str::from_utf8(cell).map_err(|e| {
+                    (
+                        "cell parse error",
+                        format!("row {}, col {}, err {}", row_idx, col_idx, e),
+                    )
+                })?;
+                let val = cell.parse::<f32>().map_err(|e| {
+                    (
+                        "cell parse error",
+                        format!("row {}, col {}, err {}", row_idx, col_idx, e),
+                    )
+                })?;
+                match (val.is_finite(), opts.non_finite) {
+                    (true, _) => Some(val),
+                    (false, NonFinitePolicy::Nodata) => {
+                        warn!(
+                            row = row_idx,
+                            col = col_idx,
+                            value = cell,
+                            "dropping non-finite cell"
+                        );
+                        None
+                    }
+                    (false, NonFinitePolicy::Reject) => Err((
+                        "non-finite cell",
+                        format!("row {}, col {}, value {}", row_idx, col_idx, cell),
+                    ))?,
+                }
+            };
+            row.push(sample);
+        }
+        if row.len() != header.ncols {
+            Err((
+                "column count",
+                format!(
+                    "data row {} (1-based) has {} columns, expected ncols {}: {}",
+                    row_idx + 1,
+                    row.len(),
+                    header.ncols,
+                    snippet(line)
+                ),
+            ))?
+        }
+        Ok(row)
+    }
+
+    pub fn parse_with<R: std::io::Read>(
+        rdr: &mut BufReader<R>,
+        opts: &ParseOptions,
+    ) -> Result<Self, GpwError> {
+        let (header, mut line_no, mut data_line) = GpwAsciiHeader::parse_counting_lines(rdr)?;
+        Self::check_grid_size(&header, opts)?;
         let mut data = Vec::with_capacity(header.nrows);
-        let mut data_line = String::new();
-        let mut row_idx = 0;
-        while 0 != rdr.read_line(&mut data_line)? {
-            let mut row = Vec::with_capacity(header.ncols);
-            for (col_idx, cell) in data_line.split_whitespace().enumerate() {
-                let sample = if cell == header.nodata_value {
-                    None
-                } else {
-                    Some(cell.parse::<f32>().map_err(|e| {
-                        (
-                            "cell parse error",
-                            format!("row {}, col {}, err {}", row_idx, col_idx, e),
-                        )
-                    })?)
-                };
-                row.push(sample);
-            }
-            assert_eq!(row.len(), header.ncols);
-            row_idx += 1;
-            data.push(row);
+        // Rows are read as bytes: the data section is ASCII, so only
+        // each numeric token is checked as UTF-8, not whole lines.
+        // The header parser already read the first one.
+        while !data_line.is_empty() || 0 != rdr.read_until(b'\n', &mut data_line)? {
+            line_no += 1;
+            if is_data_line(&data_line) {
+                data.push(
+                    Self::parse_row(&data_line, data.len(), &header, opts)
+                        .map_err(|e| e.at_line(line_no))?,
+                );
+            }
             data_line.clear();
         }
-        assert_eq!(data.len(), header.nrows);
+        Self::check_row_shortfall(&header, &mut data, opts).map_err(|e| e.at_line(line_no))?;
+        Self::from_rows(header, data)
+    }
+
+    /// Like [`Self::parse_with`], but parses batches of
+    /// [`PARALLEL_CHUNK_ROWS`] rows at a time on the rayon thread pool.
+    /// Results and errors are the same as parsing serially, at the
+    /// cost of holding one batch of raw lines in memory.
+    #[cfg(feature = "parallel")]
+    pub fn parse_parallel<R: std::io::Read>(
+        rdr: &mut BufReader<R>,
+        opts: &ParseOptions,
+    ) -> Result<Self, GpwError> {
+        use rayon::prelude::*;
+
+        let (header, mut line_no, mut first_line) = GpwAsciiHeader::parse_counting_lines(rdr)?;
+        Self::check_grid_size(&header, opts)?;
+        let mut data = Vec::with_capacity(header.nrows);
+        // Data lines along with their 1-based line numbers.
+        let mut chunk = Vec::with_capacity(PARALLEL_CHUNK_ROWS);
+        loop {
+            // The header parser already read the first data line.
+            let mut data_line = std::mem::take(&mut first_line);
+            let eof = data_line.is_empty() && rdr.read_until(b'\n', &mut data_line)? == 0;
+            if !eof {
+                line_no += 1;
+                if is_data_line(&data_line) {
+                    chunk.push((line_no, data_line));
+                }
+            }
+            if chunk.len() == PARALLEL_CHUNK_ROWS || (eof && !chunk.is_empty()) {
+                let first_row = data.len();
+                let rows = chunk
+                    .par_iter()
+                    .enumerate()
+                    .map(|(i, (line_no, line))| {
+                        Self::parse_row(line, first_row + i, &header, opts)
+                            .map_err(|e| e.at_line(*line_no))
+                    })
+                    .collect::<Vec<_>>();
+                // Keep the first error in row order, as parsing
+                // serially would report.
+                for row in rows {
+                    data.push(row?);
+                }
+                chunk.clear();
+            }
+            if eof {
+                break;
+            }
+        }
+        Self::check_row_shortfall(&header, &mut data, opts).map_err(|e| e.at_line(line_no))?;
+        Self::from_rows(header, data)
+    }
+
+    /// Fails if `header` claims more cells than `opts` allow, before
+    /// any storage is allocated for them.
+    fn check_grid_size(header: &GpwAsciiHeader, opts: &ParseOptions) -> Result<(), GpwError> {
+        match header.nrows.checked_mul(header.ncols) {
+            Some(cells) if cells <= opts.max_cells => Ok(()),
+            _ => Err((
+                "grid size",
+                format!(
+                    "{} rows x {} cols exceeds the limit of {} cells",
+                    header.nrows, header.ncols, opts.max_cells
+                ),
+            ))?,
+        }
+    }
+
+    /// Called at EOF: fails if fewer rows than `nrows` were read, or
+    /// with [`ParseOptions::allow_partial`] pads the missing southern
+    /// rows with NODATA.
+    fn check_row_shortfall(
+        header: &GpwAsciiHeader,
+        data: &mut Vec<Vec<Option<f32>>>,
+        opts: &ParseOptions,
+    ) -> Result<(), GpwError> {
+        if data.len() >= header.nrows {
+            return Ok(());
+        }
+        if !opts.allow_partial {
+            Err((
+                "row count",
+                format!(
+                    "data ends after {} rows, expected nrows {}; the file may be truncated",
+                    data.len(),
+                    header.nrows
+                ),
+            ))?
+        }
+        warn!(
+            rows = data.len(),
+            nrows = header.nrows,
+            "grid is truncated, treating missing rows as NODATA"
+        );
+        data.resize(header.nrows, vec![None; header.ncols]);
+        Ok(())
+    }
+
+    /// Assembles parsed rows into a grid, checking there are as many
+    /// as the header says.
+    pub(crate) fn from_rows(
+        header: GpwAsciiHeader,
+        data: Vec<Vec<Option<f32>>>,
+    ) -> Result<Self, GpwError> {
+        if data.len() != header.nrows {
+            Err((
+                "row count",
+                format!("found {} rows, expected {}", data.len(), header.nrows),
+            ))?
+        }
         Ok(Self {
             header,
             data,
             filename: None,
         })
     }
+
+    /// Returns the sample at `row`, `col`: `None` if either is outside
+    /// the grid, `Some(None)` for NODATA.
+    pub fn get_checked(&self, row: usize, col: usize) -> Option<Option<f32>> {
+        self.data.get(row)?.get(col).copied()
+    }
+
+    /// Returns the sample at `row`, `col`, `None` for NODATA.
+    ///
+    /// # Panics
+    ///
+    /// If `row` or `col` is outside the grid.
+    pub fn get(&self, row: usize, col: usize) -> Option<f32> {
+        self.get_checked(row, col).unwrap_or_else(|| {
+            panic!(
+                "cell ({}, {}) is outside the {} x {} grid",
+                row, col, self.header.nrows, self.header.ncols
+            )
+        })
+    }
+
+    /// See [`GpwAsciiHeader::cell_center`].
+    pub fn cell_center(&self, row: usize, col: usize) -> (f64, f64) {
+        self.header.cell_center(row, col)
+    }
+
+    /// See [`GpwAsciiHeader::cell_bounds`].
+    pub fn cell_bounds(&self, row: usize, col: usize) -> [(f64, f64); 4] {
+        self.header.cell_bounds(row, col)
+    }
+
+    /// Sums every populated cell in f64, skipping NODATA.
+    pub fn total_population(&self) -> f64 {
+        self.populated().map(|(_, _, val)| f64::from(val)).sum()
+    }
+
+    /// Returns an iterator over the `(row, col, value)` of every
+    /// populated cell in row-major order, skipping NODATA.
+    pub fn populated(&self) -> PopulatedCells<'_> {
+        PopulatedCells {
+            data: &self.data,
+            row: 0,
+            col: 0,
+        }
+    }
+
+    /// Merges every `factor` x `factor` block of cells into one
+    /// holding their sum, per [`GpwAsciiHeader::coarsen`]. A block is
+    /// NODATA only if all of its cells are, so the total is conserved.
+    pub fn coarsen(&self, factor: usize) -> Result<Self, GpwError> {
+        let header = self.header.coarsen(factor)?;
+        let mut data = vec![vec![None; header.ncols]; header.nrows];
+        for (row, col, val) in self.populated() {
+            let sum = data[row / factor][col / factor].get_or_insert(0.0_f64);
+            *sum += f64::from(val);
+        }
+        Ok(Self {
+            header,
+            data: data
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|sum| sum.map(|sum| sum as f32))
+                        .collect()
+                })
+                .collect(),
+            filename: self.filename.clone(),
+        })
+    }
+
+    /// Replaces negative (non-NODATA) samples with zero, returning
+    /// how many were clamped.
+    pub fn clamp_negative(&mut self) -> usize {
+        let mut clamped = 0;
+        for val in self.data.iter_mut().flatten().flatten() {
+            if *val < 0.0 {
+                *val = 0.0;
+                clamped += 1;
+            }
+        }
+        clamped
+    }
+
+    /// Pastes `tiles` of equal cell size into one grid just covering
+    /// all of them, NODATA wherever none has data. Where tiles overlap,
+    /// as neighbors repeating a border row or column do, each cell is
+    /// taken from the first tile with data for it, so its population
+    /// counts once. Returns the merged grid and the number of cells
+    /// left out for being populated in an earlier tile too.
+    ///
+    /// Tiles must line up on a common grid: corners a fraction of a
+    /// cell apart are an error rather than resampled.
+    pub fn merge_tiles(tiles: &[GpwAscii]) -> Result<(Self, usize), GpwError> {
+        let first = &tiles.first().ok_or(("tiles", "nothing to merge"))?.header;
+        let (dx, dy) = (first.dx(), first.dy());
+        let (mut west, mut south) = (f64::INFINITY, f64::INFINITY);
+        let (mut east, mut north) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for (i, tile) in tiles.iter().enumerate() {
+            let header = &tile.header;
+            if (header.dx() / dx - 1.0).abs() > 1e-9 || (header.dy() / dy - 1.0).abs() > 1e-9 {
+                Err((
+                    "cellsize",
+                    format!(
+                        "tile {} has {} x {} degree cells, expected {} x {}",
+                        i,
+                        header.dx(),
+                        header.dy(),
+                        dx,
+                        dy
+                    ),
+                ))?
+            }
+            if !nearly_whole((header.xllcorner - first.xllcorner) / dx) {
+                Err(("xllcorner", format!("tile {} is off the grid of tile 0", i)))?
+            }
+            if !nearly_whole((header.yllcorner - first.yllcorner) / dy) {
+                Err(("yllcorner", format!("tile {} is off the grid of tile 0", i)))?
+            }
+            west = west.min(header.xllcorner);
+            south = south.min(header.yllcorner);
+            east = east.max(header.xllcorner + dx * header.ncols as f64);
+            north = north.max(header.yllcorner + dy * header.nrows as f64);
+        }
+
+        let header = GpwAsciiHeader {
+            ncols: ((east - west) / dx).round() as usize,
+            nrows: ((north - south) / dy).round() as usize,
+            xllcorner: west,
+            yllcorner: south,
+            ..first.clone()
+        };
+        let mut data = vec![vec![None; header.ncols]; header.nrows];
+        let mut overlaps = 0;
+        for tile in tiles {
+            let top = tile.header.yllcorner + dy * tile.header.nrows as f64;
+            let row_offset = ((north - top) / dy).round() as usize;
+            let col_offset = ((tile.header.xllcorner - west) / dx).round() as usize;
+            for (row, col, val) in tile.populated() {
+                let merged = &mut data[row_offset + row][col_offset + col];
+                if merged.is_some() {
+                    overlaps += 1;
+                } else {
+                    *merged = Some(val);
+                }
+            }
+        }
+        Ok((
+            Self {
+                header,
+                data,
+                filename: None,
+            },
+            overlaps,
+        ))
+    }
+
+    /// Writes the grid in the ASCII format [`Self::parse`] reads, with
+    /// values printed exactly.
+    pub fn write(&self, wtr: &mut impl Write) -> std::io::Result<()> {
+        let header = &self.header;
+        writeln!(wtr, "ncols         {}", header.ncols)?;
+        writeln!(wtr, "nrows         {}", header.nrows)?;
+        writeln!(wtr, "xllcorner     {}", header.xllcorner)?;
+        writeln!(wtr, "yllcorner     {}", header.yllcorner)?;
+        writeln!(wtr, "cellsize      {}", header.cellsize)?;
+        if let Some(dx) = header.dx {
+            writeln!(wtr, "dx            {}", dx)?;
+        }
+        if let Some(dy) = header.dy {
+            writeln!(wtr, "dy            {}", dy)?;
+        }
+        writeln!(wtr, "NODATA_value  {}", header.nodata_value)?;
+        for row in &self.data {
+            for (col, sample) in row.iter().enumerate() {
+                if col > 0 {
+                    wtr.write_all(b" ")?;
+                }
+                match sample {
+                    Some(val) => write!(wtr, "{}", val)?,
+                    None => wtr.write_all(header.nodata_value.as_bytes())?,
+                }
+            }
+            writeln!(wtr)?;
+        }
+        Ok(())
+    }
+
+    /// Turns samples of exactly zero into NODATA, returning how many
+    /// were dropped.
+    pub fn drop_zero(&mut self) -> usize {
+        let mut dropped = 0;
+        for sample in self.data.iter_mut().flatten() {
+            if *sample == Some(0.0) {
+                *sample = None;
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+}
+
+impl<'a> IntoIterator for &'a GpwAscii {
+    type Item = (usize, usize, f32);
+    type IntoIter = PopulatedCells<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.populated()
+    }
+}
+
+/// Borrowing iterator over the populated cells of a [`GpwAscii`],
+/// returned by [`GpwAscii::populated`].
+#[derive(Debug, Clone)]
+pub struct PopulatedCells<'a> {
+    data: &'a [Vec<Option<f32>>],
+    row: usize,
+    col: usize,
+}
+
+impl Iterator for PopulatedCells<'_> {
+    type Item = (usize, usize, f32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let row = self.data.get(self.row)?;
+            match row.get(self.col) {
+                Some(cell) => {
+                    self.col += 1;
+                    if let Some(val) = cell {
+                        return Some((self.row, self.col - 1, *val));
+                    }
+                }
+                None => {
+                    self.row += 1;
+                    self.col = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    /// A 4 x 4 grid with a single populated cell, shared by the
+    /// library and binary tests.
+    const FIXTURE: &str = include_str!("../testdata/fixture.asc");
+
+    fn parse_str(src: &str) -> Result<GpwAscii, GpwError> {
+        GpwAscii::parse(&mut BufReader::new(Cursor::new(src.to_string())))
+    }
+
+    #[test]
+    fn test_parse_crlf() {
+        let lf = parse_str(FIXTURE).unwrap();
+        let crlf = parse_str(&FIXTURE.replace('\n', "\r\n")).unwrap();
+        assert_eq!(lf, crlf);
+        assert_eq!(crlf.header.nodata_value, "-9999");
+    }
+
+    #[test]
+    fn test_parse_extra_column() {
+        let extra = FIXTURE.replacen(
+            "-9999 -9999 -9999 -9999\n-9999 -9999 0.123",
+            "-9999 -9999 -9999 -9999 1.5\n-9999 -9999 0.123",
+            1,
+        );
+        assert_eq!(
+            parse_str(&extra).unwrap_err(),
+            GpwError::FieldParse {
+                field: "column count",
+                line: Some(9),
+                detail: r#"data row 3 (1-based) has 5 columns, expected ncols 4: "-9999 -9999 -9999 -9999 1.5""#
+                    .to_string(),
+            }
+        );
+
+        let long = "1 ".repeat(30);
+        assert_eq!(
+            snippet(long.as_bytes()),
+            format!("{:?}...", &long[..SNIPPET_LEN])
+        );
+    }
+
+    #[test]
+    fn test_parse_bom() {
+        let with_bom = parse_str(&format!("\u{feff}{}", FIXTURE)).unwrap();
+        assert_eq!(with_bom, parse_str(FIXTURE).unwrap());
+    }
+
+    #[test]
+    fn test_parse_reordered_header() {
+        let reordered = r#"NODATA_value  -9999
+cellsize      0.0083333333333333
+nrows         4
+ncols         4
+yllcorner     -4.2632564145606e-14
+xllcorner     -180
+-9999 -9999 -9999 -9999
+-9999 -9999 -9999 -9999
+-9999 -9999 -9999 -9999
+-9999 -9999 0.123 -9999
+"#;
+        assert_eq!(parse_str(reordered).unwrap(), parse_str(FIXTURE).unwrap());
+    }
+
+    #[test]
+    fn test_parse_quoted_nodata() {
+        for nodata in [
+            r#"NODATA_value  "-9999""#,
+            "NODATA_value  '-9999'",
+            "NODATA_value  -9999  ! missing",
+        ] {
+            let quirky = FIXTURE.replacen("NODATA_value  -9999", nodata, 1);
+            assert_eq!(parse_str(&quirky).unwrap(), parse_str(FIXTURE).unwrap());
+        }
+
+        let unterminated = FIXTURE.replacen("-9999", r#""-9999"#, 1);
+        assert_eq!(
+            parse_str(&unterminated).unwrap_err(),
+            GpwError::FieldParse {
+                field: "NODATA_value",
+                line: Some(6),
+                detail: "unterminated quote".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_header_extra_key() {
+        let extra = FIXTURE.replacen("NODATA_value", "byteorder     LSBFIRST\nNODATA_value", 1);
+        assert_eq!(parse_str(&extra).unwrap(), parse_str(FIXTURE).unwrap());
+
+        // Keys that happen to parse as numbers don't end the header.
+        for key in ["nan          5", "inf          1"] {
+            let extra = FIXTURE.replacen("NODATA_value", &format!("{}\nNODATA_value", key), 1);
+            assert_eq!(parse_str(&extra).unwrap(), parse_str(FIXTURE).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse_header_returns_first_row() {
+        let mut rdr = BufReader::new(Cursor::new(FIXTURE));
+        let (header, first_row) = GpwAsciiHeader::parse(&mut rdr).unwrap();
+        assert_eq!(header, parse_str(FIXTURE).unwrap().header);
+        assert_eq!(first_row, b"-9999 -9999 -9999 -9999\n");
+        let mut rest = String::new();
+        rdr.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_parse_header_invalid_bounds() {
+        for (valid, invalid) in [
+            ("ncols         4", "ncols         0"),
+            ("nrows         4", "nrows         0"),
+            ("xllcorner     -180", "xllcorner     1800"),
+            ("yllcorner     -4.2632564145606e-14", "yllcorner     91"),
+            ("cellsize      0.0083333333333333", "cellsize      -0.5"),
+        ] {
+            let src = FIXTURE.replacen(valid, invalid, 1);
+            let mut rdr = BufReader::new(Cursor::new(src));
+            assert!(GpwAsciiHeader::parse(&mut rdr).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_absurd_grid_size() {
+        let huge = FIXTURE.replacen("nrows         4", "nrows         1000000000000", 1);
+        assert!(parse_str(&huge).is_err());
+
+        let opts = ParseOptions {
+            max_cells: 15,
+            ..Default::default()
+        };
+        let mut rdr = BufReader::new(Cursor::new(FIXTURE));
+        assert!(GpwAscii::parse_with(&mut rdr, &opts).is_err());
+    }
+
+    /// Returns one byte per read, as pipes and gzip streams may.
+    struct OneByteReader<R>(R);
+
+    impl<R: std::io::Read> std::io::Read for OneByteReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_parse_short_reads() {
+        // Rows starting with tokens that aren't numbers on their own.
+        for src in [
+            FIXTURE.to_string(),
+            FIXTURE.replacen("-9999 -9999 -9999 -9999", "1e3 -9999 -9999 -9999", 1),
+            FIXTURE.replacen("-9999 -9999 -9999 -9999", "   -1 -9999 -9999 -9999", 1),
+        ] {
+            let expected = parse_str(&src).unwrap();
+            let mut rdr = BufReader::new(OneByteReader(Cursor::new(src.clone())));
+            assert_eq!(GpwAscii::parse(&mut rdr).unwrap(), expected, "{}", src);
+            #[cfg(feature = "parallel")]
+            {
+                let mut rdr = BufReader::new(OneByteReader(Cursor::new(src.clone())));
+                let parsed = GpwAscii::parse_parallel(&mut rdr, &ParseOptions::default());
+                assert_eq!(parsed.unwrap(), expected, "{}", src);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_non_finite() {
+        let src = FIXTURE
+            .replacen("-9999 -9999 -9999 -9999", "nan -9999 -9999 -9999", 1)
+            .replacen("0.123", "inf", 1);
+        assert!(parse_str(&src).is_err());
+
+        let opts = ParseOptions {
+            non_finite: NonFinitePolicy::Nodata,
+            ..Default::default()
+        };
+        let parsed = GpwAscii::parse_with(&mut BufReader::new(Cursor::new(src)), &opts).unwrap();
+        assert!(parsed.data.iter().flatten().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_clamp_negative() {
+        let mut parsed = parse_str(&FIXTURE.replacen("0.123", "-0.5", 1)).unwrap();
+        assert_eq!(parsed.clamp_negative(), 1);
+        assert_eq!(parsed.data[3][2], Some(0.0));
+        assert_eq!(parsed.data[0][0], None);
+        assert_eq!(parsed.clamp_negative(), 0);
+    }
+
+    #[test]
+    fn test_merge_tiles() {
+        // Two 2 x 3 tiles whose shared column is populated in both.
+        let tile = |xllcorner, rows: &str| {
+            let src = format!(
+                "ncols 3\nnrows 2\nxllcorner {}\nyllcorner 10\ncellsize 0.5\nNODATA_value -9999\n{}",
+                xllcorner, rows
+            );
+            parse_str(&src).unwrap()
+        };
+        let west = tile(1.0, "1 2 3\n4 -9999 6\n");
+        let east = tile(2.0, "3 7 8\n6 9 -9999\n");
+
+        let (merged, overlaps) = GpwAscii::merge_tiles(&[west.clone(), east.clone()]).unwrap();
+        assert_eq!(overlaps, 2);
+        assert_eq!((merged.header.ncols, merged.header.nrows), (5, 2));
+        assert_eq!(merged.header.xllcorner, 1.0);
+        let expected: Vec<Vec<Option<f32>>> = vec![
+            vec![Some(1.0), Some(2.0), Some(3.0), Some(7.0), Some(8.0)],
+            vec![Some(4.0), None, Some(6.0), Some(9.0), None],
+        ];
+        assert_eq!(merged.data, expected);
+        let shared = 3.0 + 6.0;
+        assert_eq!(
+            merged.total_population(),
+            west.total_population() + east.total_population() - shared
+        );
+
+        let mut written = Vec::new();
+        merged.write(&mut written).unwrap();
+        let reparsed = parse_str(std::str::from_utf8(&written).unwrap()).unwrap();
+        assert_eq!(reparsed.header, merged.header);
+        assert_eq!(reparsed.data, merged.data);
+
+        let misaligned = tile(1.25, "1 2 3\n4 5 6\n");
+        assert!(GpwAscii::merge_tiles(&[west, misaligned]).is_err());
+    }
+
+    #[test]
+    fn test_drop_zero() {
+        let mut parsed = parse_str(&FIXTURE.replacen("-9999 0.123", "0 0.123", 1)).unwrap();
+        assert_eq!(parsed.data[3][1], Some(0.0));
+        assert_eq!(parsed.drop_zero(), 1);
+        assert_eq!(parsed.data[3][1], None);
+        assert_eq!(parsed.data[3][2], Some(0.123));
+        assert_eq!(parsed.drop_zero(), 0);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parse_parallel_matches_serial() {
+        let nrows = 2 * PARALLEL_CHUNK_ROWS + 3;
+        let mut src = format!(
+            "ncols 5\nnrows {}\nxllcorner 8\nyllcorner 47\ncellsize 0.0083333333333333\nNODATA_value -9999\n",
+            nrows
+        );
+        for row in 0..nrows {
+            if row % 100 == 7 {
+                src.push_str("# a comment\n\n");
+            }
+            let cells = (0..5)
+                .map(|col| match (row * 5 + col) % 3 {
+                    0 => "-9999".to_string(),
+                    _ => format!("{}.{}", row, col),
+                })
+                .collect::<Vec<_>>();
+            src.push_str(&cells.join(" "));
+            src.push('\n');
+        }
+        let serial = |src: &str| GpwAscii::parse(&mut BufReader::new(Cursor::new(src.to_string())));
+        let parallel = |src: &str| {
+            GpwAscii::parse_parallel(
+                &mut BufReader::new(Cursor::new(src.to_string())),
+                &ParseOptions::default(),
+            )
+        };
+        assert_eq!(parallel(&src).unwrap(), serial(&src).unwrap());
+
+        // Errors carry the same global row and line numbers, and the
+        // first one wins.
+        let lines = src.lines().collect::<Vec<_>>();
+        let bad_line = lines.len() - 2;
+        let mut bad = lines.clone();
+        bad[bad_line] = "1 2 x 4 5";
+        bad[bad_line + 1] = "1 2 3";
+        let bad = bad.join("\n");
+        let err = parallel(&bad).unwrap_err();
+        assert_eq!(err, serial(&bad).unwrap_err());
+        match err {
+            GpwError::FieldParse { line, detail, .. } => {
+                assert_eq!(line, Some(bad_line + 1));
+                assert!(
+                    detail.starts_with(&format!("row {}, col 2", nrows - 2)),
+                    "{}",
+                    detail
+                );
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let short = lines[..lines.len() - 1].join("\n");
+        assert_eq!(parallel(&short).unwrap_err(), serial(&short).unwrap_err());
+    }
+
+    #[test]
+    fn test_get() {
+        let gpw = parse_str(FIXTURE).unwrap();
+        assert_eq!(gpw.get_checked(3, 2), Some(Some(0.123)));
+        assert_eq!(gpw.get(3, 2), Some(0.123));
+        assert_eq!(gpw.get_checked(0, 0), Some(None));
+        assert_eq!(gpw.get(0, 0), None);
+        assert_eq!(gpw.get_checked(4, 0), None);
+        assert_eq!(gpw.get_checked(0, 4), None);
+        assert!(std::panic::catch_unwind(|| gpw.get(0, 4)).is_err());
+    }
+
+    #[test]
+    fn test_coarsen() {
+        let gpw = parse_str(&FIXTURE.replace(
+            "-9999 -9999 -9999 -9999\n-9999 -9999 0.123",
+            "1 -9999 2 -9999\n-9999 -9999 0.123",
+        ))
+        .unwrap();
+        let near = |(x1, y1): (f64, f64), (x2, y2): (f64, f64)| {
+            (x1 - x2).abs() < 1e-9 && (y1 - y2).abs() < 1e-9
+        };
+
+        let coarse = gpw.coarsen(2).unwrap();
+        assert_eq!((coarse.header.nrows, coarse.header.ncols), (2, 2));
+        assert_eq!(coarse.header.cellsize, 2.0 * gpw.header.cellsize);
+        assert!(near(coarse.cell_bounds(0, 0)[3], gpw.cell_bounds(0, 0)[3]));
+        assert!(near(coarse.cell_bounds(1, 1)[1], gpw.cell_bounds(3, 3)[1]));
+        assert_eq!(coarse.data[0], vec![None, None]);
+        assert_eq!(coarse.data[1][0], Some(1.0));
+        assert!((coarse.total_population() - gpw.total_population()).abs() < 1e-6);
+
+        assert!(gpw.coarsen(3).is_err());
+        assert!(gpw.coarsen(0).is_err());
+    }
+
+    #[test]
+    fn test_coarsen_global() {
+        let header = |nrows: usize| GpwAsciiHeader {
+            ncols: 12,
+            nrows,
+            xllcorner: -180.0,
+            yllcorner: -90.0,
+            cellsize: 180.0 / nrows as f64,
+            dx: Some(30.0),
+            dy: None,
+            nodata_value: "-9999".to_string(),
+        };
+        // Still touching both poles.
+        let coarse = header(6).coarsen(2).unwrap();
+        assert_eq!((coarse.nrows, coarse.ncols), (3, 6));
+        assert_eq!(coarse.yllcorner, -90.0);
+        assert_eq!(coarse.cell_bounds(0, 0)[3], (-180.0, 90.0));
+
+        // Padding the last row of blocks out to full cells would reach
+        // past the south pole.
+        let err = header(6).coarsen(4).unwrap_err();
+        assert_eq!(err.to_string(), "coarsen: 4 does not divide a 6 x 12 grid");
+        assert!(header(9).coarsen(2).is_err());
+    }
+
+    #[test]
+    fn test_skip_blank_and_comment_lines() {
+        let (header, rows) = FIXTURE.split_at(FIXTURE.find("-9999 -9999").unwrap());
+        let rows = rows.lines().collect::<Vec<_>>();
+        let src = format!(
+            "{}# exported by some tool\n\n{}\n   \n{}\n\t\n# mid-grid note\n{}\n{}\n\n",
+            header.replacen("NODATA_value", "# comment\nNODATA_value", 1),
+            rows[0],
+            rows[1],
+            rows[2],
+            rows[3]
+        );
+        assert_eq!(parse_str(&src).unwrap(), parse_str(FIXTURE).unwrap());
+
+        // Genuine rows are still counted against nrows.
+        let short = src.replacen(rows[3], "", 1);
+        assert!(parse_str(&short).is_err());
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        // Drop the last two data rows, as a cut-off download would.
+        let lines = FIXTURE.lines().collect::<Vec<_>>();
+        let truncated = lines[..lines.len() - 2].join("\n");
+        match parse_str(&truncated) {
+            Err(GpwError::FieldParse { field, detail, .. }) => {
+                assert_eq!(field, "row count");
+                assert!(
+                    detail.contains("after 2 rows, expected nrows 4"),
+                    "{}",
+                    detail
+                );
+            }
+            other => panic!("expected a row count error, got {:?}", other),
+        }
+
+        let opts = ParseOptions {
+            allow_partial: true,
+            ..Default::default()
+        };
+        let partial =
+            GpwAscii::parse_with(&mut BufReader::new(Cursor::new(&truncated)), &opts).unwrap();
+        let full = parse_str(FIXTURE).unwrap();
+        assert_eq!(partial.header, full.header);
+        assert_eq!(partial.data[..2], full.data[..2]);
+        assert_eq!(partial.data[2..], vec![vec![None; 4]; 2]);
+        #[cfg(feature = "parallel")]
+        assert_eq!(
+            GpwAscii::parse_parallel(&mut BufReader::new(Cursor::new(&truncated)), &opts).unwrap(),
+            partial
+        );
+    }
+
+    /// The line-as-`String` row parser `parse_row` replaced.
+    fn parse_row_str(line: &str, nodata: &str) -> Option<Vec<Option<f32>>> {
+        trim_line_ending(line)
+            .split_whitespace()
+            .map(|cell| match cell {
+                cell if cell == nodata => Some(None),
+                cell => cell.parse::<f32>().ok().map(Some),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_row_matches_str() {
+        let header = parse_str(FIXTURE).unwrap().header;
+        let opts = ParseOptions::default();
+        for line in [
+            "-9999 -9999 0.123 -9999\n",
+            "1 2.5 -0 3e-7\r\n",
+            "\t 1.0e3   -9999\t4 0.000001   \n",
+            "340282350000000000000000000000000000000 1e-45 .5 7.",
+            "1 2 3",
+            "1 2 3 4 5\n",
+            "1 2 x 4\n",
+            "1 2 3 -9999.0\n",
+            "1 2 3 \u{e9}\n",
+        ] {
+            let by_bytes = GpwAscii::parse_row(line.as_bytes(), 0, &header, &opts).ok();
+            let by_str =
+                parse_row_str(line, &header.nodata_value).filter(|row| row.len() == header.ncols);
+            assert_eq!(by_bytes, by_str, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_missing_trailing_newline() {
+        let expected = parse_str(FIXTURE).unwrap();
+        let unterminated = FIXTURE.trim_end_matches('\n');
+        assert_eq!(parse_str(unterminated).unwrap(), expected);
+        let crlf = FIXTURE.replace('\n', "\r\n");
+        assert_eq!(parse_str(crlf.trim_end_matches("\r\n")).unwrap(), expected);
+        // An unterminated comment after the last row isn't a row.
+        let comment = format!("{}# end", FIXTURE);
+        assert_eq!(parse_str(&comment).unwrap(), expected);
+
+        // A missing last row is still caught when the one before it is
+        // unterminated.
+        let (short, _) = unterminated.rsplit_once('\n').unwrap();
+        assert!(parse_str(short).is_err());
+    }
+
+    #[test]
+    fn test_parse_header_missing_field() {
+        let missing = FIXTURE.replacen("cellsize      0.0083333333333333\n", "", 1);
+        assert!(parse_str(&missing).is_err());
+        let dx_only = FIXTURE.replacen("cellsize ", "dx       ", 1);
+        assert!(parse_str(&dx_only).is_err());
+    }
+
+    #[test]
+    fn test_parse_error_variants() {
+        let no_ncols = FIXTURE.replacen("ncols         4\n", "", 1);
+        assert_eq!(
+            parse_str(&no_ncols).unwrap_err(),
+            GpwError::MissingField("ncols")
+        );
+
+        let bad_ncols = FIXTURE.replacen("ncols         4", "ncols         four", 1);
+        assert_eq!(
+            parse_str(&bad_ncols).unwrap_err(),
+            GpwError::FieldParse {
+                field: "ncols",
+                line: Some(1),
+                detail: "invalid digit found in string".to_string(),
+            }
+        );
+
+        let bad_cell = FIXTURE.replacen("0.123", "0.1x3", 1);
+        assert_eq!(
+            parse_str(&bad_cell).unwrap_err(),
+            GpwError::FieldParse {
+                field: "cell parse error",
+                line: Some(10),
+                detail: "row 3, col 2, err invalid float literal".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_header_dx_dy() {
+        let src = FIXTURE.replacen(
+            "cellsize      0.0083333333333333",
+            "dx            0.5\ndy            0.25",
+            1,
+        );
+        let header = parse_str(&src).unwrap().header;
+        assert_eq!((header.dx(), header.dy()), (0.5, 0.25));
+        assert_eq!(header.cellsize, 0.5);
+        let [ll, _, ur, _] = header.cell_bounds(3, 2);
+        assert_eq!(ll, (-179.0, -4.2632564145606e-14));
+        assert_eq!(ur, (-178.5, 0.25 - 4.2632564145606e-14));
+
+        // Square grids report cellsize for both.
+        let square = parse_str(FIXTURE).unwrap().header;
+        assert_eq!(square.dx(), square.cellsize);
+        assert_eq!(square.dy(), square.cellsize);
+
+        let negative = src.replacen("dy            0.25", "dy            -0.25", 1);
+        assert!(parse_str(&negative).is_err());
+    }
+
+    #[test]
+    fn test_cell_geotransform() {
+        let gpw = parse_str(FIXTURE).unwrap();
+        let cellsize = 0.0083333333333333;
+        let yll = -4.2632564145606e-14;
+        // The populated cell sits in the bottom row, third column.
+        let [ll, lr, ur, ul] = gpw.cell_bounds(3, 2);
+        assert_eq!(ll, (-180.0 + 2.0 * cellsize, yll));
+        assert_eq!(lr, (-180.0 + 3.0 * cellsize, yll));
+        assert_eq!(ur, (-180.0 + 3.0 * cellsize, yll + cellsize));
+        assert_eq!(ul, (-180.0 + 2.0 * cellsize, yll + cellsize));
+        let (lng, lat) = gpw.cell_center(3, 2);
+        assert!((lng - (-180.0 + 2.5 * cellsize)).abs() < 1e-12);
+        assert!((lat - (yll + 0.5 * cellsize)).abs() < 1e-12);
+
+        // Row 0 is the northernmost row.
+        let (_, top_lat) = gpw.cell_center(0, 0);
+        assert!((top_lat - (yll + 3.5 * cellsize)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_total_population() {
+        let gpw = parse_str(FIXTURE).unwrap();
+        assert_eq!(gpw.total_population(), f64::from(0.123_f32));
+    }
+
+    #[test]
+    fn test_populated_cells() {
+        let gpw = parse_str(FIXTURE).unwrap();
+        let cells: Vec<_> = gpw.into_iter().collect();
+        assert_eq!(cells, vec![(3, 2, 0.123)]);
+
+        let gpw = parse_str(&FIXTURE.replace(
+            "-9999 -9999 -9999 -9999\n-9999 -9999 0.123",
+            "1 -9999 2 -9999\n-9999 -9999 0.123",
+        ))
+        .unwrap();
+        let cells: Vec<_> = gpw.populated().collect();
+        assert_eq!(cells, vec![(2, 0, 1.0), (2, 2, 2.0), (3, 2, 0.123)]);
+    }
+
+    /// Collects formatted log output for assertions.
+    #[derive(Clone, Default)]
+    struct Logs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for Logs {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_warn_on_dropped_cell() {
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_ansi(false)
+            .finish();
+        let opts = ParseOptions {
+            non_finite: NonFinitePolicy::Nodata,
+            ..Default::default()
+        };
+        let src = FIXTURE.replacen("0.123", "nan", 1);
+        tracing::subscriber::with_default(subscriber, || {
+            GpwAscii::parse_with(&mut BufReader::new(Cursor::new(src)), &opts).unwrap();
+        });
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("WARN"), "{}", logs);
+        assert!(logs.contains("dropping non-finite cell"), "{}", logs);
+        assert!(logs.contains("row=3 col=2"), "{}", logs);
+    }
 }
@@ -0,0 +1,248 @@
+//! Reading GPW population grids distributed as NetCDF.
+//!
+//! The population variable's last two dimensions must be latitude and
+//! longitude, in that order, each with a coordinate variable of the
+//! same name holding evenly spaced cell centers. Latitude may run
+//! either way; grids stored south to north are flipped into the
+//! north-first row order of [`GpwAscii`]. Any leading dimensions, such
+//! as a band or time, must have length one.
+
+use crate::{
+    error::GpwError,
+    gpwascii::{GpwAscii, GpwAsciiHeader},
+};
+use std::path::Path;
+
+/// Names recognized for the latitude and longitude dimensions.
+const LAT_NAMES: [&str; 3] = ["lat", "latitude", "y"];
+const LNG_NAMES: [&str; 3] = ["lon", "longitude", "x"];
+
+/// Reads only the header `variable` in the NetCDF file at `path` would
+/// have as a GPW ASCII grid.
+pub fn read_header(path: &Path, variable: &str) -> Result<GpwAsciiHeader, GpwError> {
+    let file = netcdf::open(path).map_err(|e| ("netcdf", e))?;
+    Ok(layout(&file, variable)?.header)
+}
+
+/// Reads `variable` from the NetCDF file at `path` as a grid. Cells
+/// equal to the variable's `_FillValue` or `missing_value`, or NaN,
+/// are NODATA.
+pub fn read(path: &Path, variable: &str) -> Result<GpwAscii, GpwError> {
+    let file = netcdf::open(path).map_err(|e| ("netcdf", e))?;
+    let Layout {
+        var,
+        header,
+        lat_ascending,
+        fill,
+    } = layout(&file, variable)?;
+    let values = var.get_values::<f32, _>(..).map_err(|e| ("netcdf", e))?;
+    let mut data = values
+        .chunks_exact(header.ncols)
+        .map(|row| {
+            row.iter()
+                .map(|&val| {
+                    let nodata = val.is_nan() || fill.is_some_and(|fill| f64::from(val) == fill);
+                    (!nodata).then_some(val)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    if lat_ascending {
+        data.reverse();
+    }
+    GpwAscii::from_rows(header, data)
+}
+
+/// A population variable along with where its grid sits, worked out
+/// from its coordinate variables.
+struct Layout<'f> {
+    var: netcdf::Variable<'f>,
+    header: GpwAsciiHeader,
+    /// Whether the first row is the southernmost.
+    lat_ascending: bool,
+    fill: Option<f64>,
+}
+
+fn layout<'f>(file: &'f netcdf::File, variable: &str) -> Result<Layout<'f>, GpwError> {
+    let var = file.variable(variable).ok_or_else(|| {
+        GpwError::from((
+            "netcdf variable",
+            format!("no variable named {:?}", variable),
+        ))
+    })?;
+    let dims = var.dimensions();
+    let [leading @ .., lat_dim, lng_dim] = dims else {
+        return Err(GpwError::from((
+            "netcdf dimensions",
+            format!("{:?} needs latitude and longitude dimensions", variable),
+        )));
+    };
+    if let Some(dim) = leading.iter().find(|dim| dim.len() != 1) {
+        Err((
+            "netcdf dimensions",
+            format!(
+                "leading dimension {:?} has length {}, expected 1",
+                dim.name(),
+                dim.len()
+            ),
+        ))?
+    }
+    let (lat_name, lng_name) = (lat_dim.name(), lng_dim.name());
+    if !LAT_NAMES.contains(&lat_name.to_lowercase().as_str())
+        || !LNG_NAMES.contains(&lng_name.to_lowercase().as_str())
+    {
+        Err((
+            "netcdf dimensions",
+            format!(
+                "expected (latitude, longitude) as the last dimensions, found ({}, {})",
+                lat_name, lng_name
+            ),
+        ))?
+    }
+    let lats = coordinates(file, &lat_name)?;
+    let lngs = coordinates(file, &lng_name)?;
+    let (dy, dx) = (spacing(&lat_name, &lats)?, spacing(&lng_name, &lngs)?);
+    if dx < 0.0 {
+        Err(("netcdf coordinates", "longitude must increase eastward"))?
+    }
+    let south = lats[0].min(lats[lats.len() - 1]);
+    let fill = ["_FillValue", "missing_value"]
+        .into_iter()
+        .find_map(|name| var.attribute_value(name))
+        .transpose()
+        .map_err(|e| ("netcdf", e))?
+        .map(attribute_f64)
+        .transpose()?;
+    let header = GpwAsciiHeader {
+        ncols: lngs.len(),
+        nrows: lats.len(),
+        xllcorner: lngs[0] - dx / 2.0,
+        yllcorner: south - dy.abs() / 2.0,
+        cellsize: dx,
+        dx: None,
+        dy: ((dy.abs() - dx).abs() > 1e-9).then_some(dy.abs()),
+        nodata_value: fill.map_or_else(|| "NaN".to_string(), |fill| fill.to_string()),
+    };
+    header.validate()?;
+    Ok(Layout {
+        var,
+        header,
+        lat_ascending: dy > 0.0,
+        fill,
+    })
+}
+
+/// Reads the coordinate variable for dimension `name`.
+fn coordinates(file: &netcdf::File, name: &str) -> Result<Vec<f64>, GpwError> {
+    file.variable(name)
+        .ok_or_else(|| {
+            GpwError::from((
+                "netcdf coordinates",
+                format!("no coordinate variable for dimension {:?}", name),
+            ))
+        })?
+        .get_values::<f64, _>(..)
+        .map_err(|e| ("netcdf", e).into())
+}
+
+/// Returns the signed step between evenly spaced coordinates.
+fn spacing(name: &str, coords: &[f64]) -> Result<f64, GpwError> {
+    let [first, .., last] = coords else {
+        return Err(GpwError::from((
+            "netcdf coordinates",
+            format!("{:?} needs at least two values to give a cell size", name),
+        )));
+    };
+    let step = (last - first) / (coords.len() - 1) as f64;
+    let uneven = coords
+        .windows(2)
+        .any(|pair| ((pair[1] - pair[0]) - step).abs() > step.abs() * 1e-3);
+    if step == 0.0 || uneven {
+        Err((
+            "netcdf coordinates",
+            format!("{:?} values are not evenly spaced", name),
+        ))?
+    }
+    Ok(step)
+}
+
+fn attribute_f64(value: netcdf::AttributeValue) -> Result<f64, GpwError> {
+    use netcdf::AttributeValue::*;
+    match value {
+        Double(v) => Ok(v),
+        Float(v) => Ok(f64::from(v)),
+        Int(v) => Ok(f64::from(v)),
+        Short(v) => Ok(f64::from(v)),
+        Uint(v) => Ok(f64::from(v)),
+        Ushort(v) => Ok(f64::from(v)),
+        Schar(v) => Ok(f64::from(v)),
+        Uchar(v) => Ok(f64::from(v)),
+        other => Err(("netcdf fill value", format!("unsupported {:?}", other)))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    const ASCII: &str = "ncols 3
+nrows 2
+xllcorner 0
+yllcorner 0
+cellsize 1
+NODATA_value -9999
+1 -9999 3
+4 5.5 6
+";
+
+    /// Writes a 2x3 grid with the rows in the order of `lats`.
+    fn write_nc(path: &Path, lats: [f64; 2], rows: [[f32; 3]; 2]) {
+        let mut file = netcdf::create(path).unwrap();
+        file.add_dimension("band", 1).unwrap();
+        file.add_dimension("lat", 2).unwrap();
+        file.add_dimension("lon", 3).unwrap();
+        file.add_variable::<f64>("lat", &["lat"])
+            .unwrap()
+            .put_values(&lats, ..)
+            .unwrap();
+        file.add_variable::<f64>("lon", &["lon"])
+            .unwrap()
+            .put_values(&[0.5, 1.5, 2.5], ..)
+            .unwrap();
+        let mut pop = file
+            .add_variable::<f32>("population", &["band", "lat", "lon"])
+            .unwrap();
+        pop.set_fill_value(-9999f32).unwrap();
+        pop.put_values(&rows.concat(), ..).unwrap();
+    }
+
+    #[test]
+    fn test_read() {
+        let expected = GpwAscii::parse(&mut BufReader::new(Cursor::new(ASCII))).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let descending = dir.path().join("descending.nc");
+        write_nc(
+            &descending,
+            [1.5, 0.5],
+            [[1.0, -9999.0, 3.0], [4.0, 5.5, 6.0]],
+        );
+        assert_eq!(read(&descending, "population").unwrap(), expected);
+
+        let ascending = dir.path().join("ascending.nc");
+        write_nc(
+            &ascending,
+            [0.5, 1.5],
+            [[4.0, 5.5, 6.0], [1.0, -9999.0, 3.0]],
+        );
+        assert_eq!(read(&ascending, "population").unwrap(), expected);
+        assert_eq!(
+            read_header(&ascending, "population").unwrap(),
+            expected.header
+        );
+
+        assert!(read(&ascending, "density").is_err());
+        assert!(read(&ascending, "lat").is_err());
+    }
+}
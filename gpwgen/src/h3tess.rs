@@ -0,0 +1,1466 @@
+//! Reading and writing `.h3tess` files.
+//!
+//! A plain h3tess file is a flat sequence of little-endian `(u64 H3
+//! index, f32 value)` records with no header or padding.
+//!
+//! Files using any optional [`Format`] feature instead start with a
+//! [`HEADER_LEN`] byte header: the 8 byte [`MAGIC`], a version byte,
+//! a flags byte, two zero bytes, and a little-endian `u32` scale
+//! factor for [quantized](Quantize) values (zero otherwise). Files
+//! that record their number of records follow the header with it as
+//! a little-endian `u64`. The magic can't be mistaken for the first
+//! index of a plain file, so readers detect the layout on their own.
+//! Formats with [checksums](Format::checksum) end in a
+//! [`CHECKSUM_LEN`] byte footer after the last record.
+//!
+//! [Delta-encoded](Format::delta) files store each record's index as
+//! an unsigned LEB128 varint of its difference from the previous
+//! record's index (from zero for the first), followed by the value and
+//! count as usual. Indices must be strictly increasing, so a zero
+//! delta can't occur in a record and instead marks the end of the
+//! records, ahead of any checksum footer.
+
+use crate::error::GpwError;
+use byteorder::{ByteOrder, LittleEndian as LE};
+use clap::ValueEnum;
+use crc32fast::Hasher;
+use hextree::h3ron::H3Cell;
+use std::{
+    convert::TryFrom,
+    io::{ErrorKind, Read, Write},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::warn;
+
+/// Size in bytes of one plain `(index, value)` record.
+pub const RECORD_LEN: usize = 12;
+
+/// First bytes of an h3tess file with a header. Read as a
+/// little-endian u64 its reserved high bit is set, which no valid H3
+/// index has.
+pub const MAGIC: [u8; 8] = *b"H3TESS\x00\xff";
+
+/// Size in bytes of the optional file header.
+pub const HEADER_LEN: usize = 16;
+
+/// Size in bytes of the checksum footer.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Current header version. Version 2 added quantized values,
+/// checksums and record counts, and version 3 delta-encoded indices;
+/// files are written with the oldest version that has what they use
+/// so older readers accept them.
+pub const VERSION: u8 = 3;
+
+const FLAG_COUNTS: u8 = 1;
+const FLAG_U16: u8 = 2;
+const FLAG_U32: u8 = 4;
+const FLAG_CHECKSUM: u8 = 8;
+const FLAG_RECORD_COUNT: u8 = 16;
+const FLAG_DELTA: u8 = 32;
+
+/// Longest LEB128 encoding of a `u64`.
+const MAX_VARINT_LEN: usize = 10;
+
+/// Largest record any format produces.
+const MAX_RECORD_LEN: usize = MAX_VARINT_LEN + 8;
+
+/// Index delta that ends the records of a delta-encoded stream.
+const END_OF_RECORDS: u8 = 0;
+
+/// Attempts an [`H3TessReader`] makes at a read that keeps failing
+/// with a transient error, by default.
+pub const DEFAULT_READ_ATTEMPTS: u32 = 5;
+
+/// Wait before retrying a failed read, doubling with each further
+/// attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Record layout of an h3tess stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Format {
+    /// Each record carries a trailing `u32` count of the source grid
+    /// cells that contributed to it.
+    pub counts: bool,
+    /// Values are stored as fixed-point integers instead of `f32`.
+    pub quantize: Option<Quantize>,
+    /// The records are followed by a little-endian CRC32 of all
+    /// record bytes, to catch silent corruption.
+    pub checksum: bool,
+    /// Indices are stored as varint deltas from the previous record's
+    /// index, and must be strictly increasing. Consecutive cells of a
+    /// sorted map share long index prefixes, so a dense region at a
+    /// single resolution takes about 3 bytes per index instead of 8.
+    pub delta: bool,
+}
+
+impl Format {
+    /// Size in bytes of one record in this format, or the most one
+    /// can take for [delta](Self::delta) formats.
+    pub fn record_len(&self) -> usize {
+        let index_len = if self.delta { MAX_VARINT_LEN } else { 8 };
+        index_len + self.payload_len()
+    }
+
+    /// Size in bytes of the value and count following a record's
+    /// index.
+    fn payload_len(&self) -> usize {
+        let value_len = self.quantize.map_or(4, |q| q.value_len());
+        let count_len = if self.counts { 4 } else { 0 };
+        value_len + count_len
+    }
+
+    /// The default format is written without a header so plain files
+    /// stay readable by older tools.
+    fn has_header(&self) -> bool {
+        *self != Format::default()
+    }
+
+    fn version(&self) -> u8 {
+        if self.delta {
+            3
+        } else if self.quantize.is_some() || self.checksum {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let counts = if self.counts { FLAG_COUNTS } else { 0 };
+        let quantize = match self.quantize {
+            None => 0,
+            Some(Quantize::U16 { .. }) => FLAG_U16,
+            Some(Quantize::U32 { .. }) => FLAG_U32,
+        };
+        let checksum = if self.checksum { FLAG_CHECKSUM } else { 0 };
+        let delta = if self.delta { FLAG_DELTA } else { 0 };
+        counts | quantize | checksum | delta
+    }
+
+    /// Decodes a record's value and count from the `payload_len`
+    /// bytes following its index.
+    fn decode_payload(&self, payload: &[u8]) -> (f32, u32) {
+        let (val, count_at) = match self.quantize {
+            None => (LE::read_f32(&payload[..4]), 4),
+            Some(q @ Quantize::U16 { .. }) => (q.decode(u32::from(LE::read_u16(&payload[..2]))), 2),
+            Some(q @ Quantize::U32 { .. }) => (q.decode(LE::read_u32(&payload[..4])), 4),
+        };
+        let count = if self.counts {
+            LE::read_u32(&payload[count_at..count_at + 4])
+        } else {
+            1
+        };
+        (val, count)
+    }
+}
+
+/// Fixed-point storage for record values.
+///
+/// A value `v` is stored as the integer `round(v * scale)` and read
+/// back as that integer divided by `scale`, so decoded values are
+/// within half of `1 / scale` of the original. In exchange, values
+/// must lie in `0..=MAX / scale` for the integer type's `MAX`, and
+/// writing anything else fails.
+///
+/// Records keep their 8 byte index, so `U16` shrinks a record from 12
+/// to 10 bytes while `U32` only trades precision for a fixed step.
+/// `U16` suits low-density or coarse maps: at a scale of 10 it holds
+/// values up to 6553.5 to the nearest 0.05.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantize {
+    U16 { scale: u32 },
+    U32 { scale: u32 },
+}
+
+impl Quantize {
+    /// Number of integer steps per unit of value.
+    pub fn scale(&self) -> u32 {
+        match *self {
+            Quantize::U16 { scale } | Quantize::U32 { scale } => scale,
+        }
+    }
+
+    fn value_len(&self) -> usize {
+        match self {
+            Quantize::U16 { .. } => 2,
+            Quantize::U32 { .. } => 4,
+        }
+    }
+
+    fn max(&self) -> u32 {
+        match self {
+            Quantize::U16 { .. } => u32::from(u16::MAX),
+            Quantize::U32 { .. } => u32::MAX,
+        }
+    }
+
+    fn encode(&self, value: f32) -> Result<u32, GpwError> {
+        let steps = (f64::from(value) * f64::from(self.scale())).round();
+        if !(0.0..=f64::from(self.max())).contains(&steps) {
+            Err((
+                "value out of quantized range",
+                format!("{} with {:?}", value, self),
+            ))?
+        }
+        Ok(steps as u32)
+    }
+
+    fn decode(&self, steps: u32) -> f32 {
+        (f64::from(steps) / f64::from(self.scale())) as f32
+    }
+}
+
+/// Parses `u16:SCALE` or `u32:SCALE`, e.g. `u16:10`.
+impl FromStr for Quantize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, scale) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected u16:SCALE or u32:SCALE, got {:?}", s))?;
+        let scale = scale
+            .parse::<u32>()
+            .ok()
+            .filter(|scale| *scale > 0)
+            .ok_or_else(|| format!("invalid scale {:?}", scale))?;
+        match width {
+            "u16" => Ok(Quantize::U16 { scale }),
+            "u32" => Ok(Quantize::U32 { scale }),
+            _ => Err(format!("invalid width {:?}, expected u16 or u32", width)),
+        }
+    }
+}
+
+/// Iterates over the `(cell, value)` records of an h3tess stream.
+///
+/// Yields an error for an invalid H3 index or a partial record at the
+/// end of the stream, and `None` once the stream ends cleanly on a
+/// record boundary. Use [`Self::counted`] to also get each record's
+/// contribution count.
+pub struct H3TessReader<R> {
+    rdr: R,
+    format: Option<Format>,
+    offset: u64,
+    tolerate_truncation: bool,
+    truncated: Option<usize>,
+    skip_checksum: bool,
+    crc: Hasher,
+    ended: bool,
+    record_count: Option<u64>,
+    /// First bytes of a plain stream, read while looking for a header.
+    lookahead: ([u8; MAGIC.len()], usize),
+    /// Index of the last record, which delta-encoded indices build on.
+    prev_index: u64,
+    read_attempts: u32,
+}
+
+impl<R: Read> H3TessReader<R> {
+    pub fn new(rdr: R) -> Self {
+        Self {
+            rdr,
+            format: None,
+            offset: 0,
+            tolerate_truncation: false,
+            truncated: None,
+            skip_checksum: false,
+            crc: Hasher::new(),
+            ended: false,
+            record_count: None,
+            lookahead: ([0; MAGIC.len()], 0),
+            prev_index: 0,
+            read_attempts: DEFAULT_READ_ATTEMPTS,
+        }
+    }
+
+    /// Retries reads failing with `Interrupted` or `WouldBlock`, as
+    /// reads from a flaky network mount can, with exponential backoff
+    /// until `attempts` in a row have failed, then yields the error.
+    /// Defaults to [`DEFAULT_READ_ATTEMPTS`].
+    pub fn read_attempts(mut self, attempts: u32) -> Self {
+        self.read_attempts = attempts;
+        self
+    }
+
+    /// Accepts a missing or mismatched checksum footer instead of
+    /// yielding an error for it.
+    pub fn skip_checksum(mut self, skip: bool) -> Self {
+        self.skip_checksum = skip;
+        self
+    }
+
+    /// Ends iteration at a trailing partial record instead of
+    /// yielding an error for it. Check [`Self::truncated`] afterwards
+    /// to learn whether anything was dropped.
+    pub fn tolerate_truncation(mut self, tolerate: bool) -> Self {
+        self.tolerate_truncation = tolerate;
+        self
+    }
+
+    /// The stream's layout, known once the first record (or header)
+    /// has been read.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// Number of records the header says the stream holds, known once
+    /// the header has been read.
+    pub fn record_count(&self) -> Option<u64> {
+        self.record_count
+    }
+
+    /// Reads the header, if any, without consuming any records, and
+    /// returns the stream's layout.
+    pub fn read_header(&mut self) -> Result<Format, GpwError> {
+        if self.format.is_none() {
+            self.detect_format()?;
+        }
+        Ok(self.format.unwrap_or_default())
+    }
+
+    /// Byte offset of the next record in the stream.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Number of bytes in a trailing partial record that was dropped
+    /// because truncation is tolerated. Delta-encoded streams cut off
+    /// between records report `Some(0)`, having lost their end marker.
+    pub fn truncated(&self) -> Option<usize> {
+        self.truncated
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rdr
+    }
+
+    /// Iterates over `(cell, value, count)` records. Formats without
+    /// counts report a count of 1 for every record.
+    pub fn counted(&mut self) -> impl Iterator<Item = Result<(H3Cell, f32, u32), GpwError>> + '_ {
+        std::iter::from_fn(move || self.read_record().transpose())
+    }
+
+    /// Reads into `buf` until it's full or the stream ends, returning
+    /// the number of bytes read.
+    fn fill(&mut self, buf: &mut [u8]) -> Result<usize, GpwError> {
+        let mut filled = 0;
+        let mut failures = 0;
+        while filled < buf.len() {
+            match self.rdr.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    filled += n;
+                    failures = 0;
+                }
+                Err(e)
+                    if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock)
+                        && failures + 1 < self.read_attempts =>
+                {
+                    warn!("retrying read at offset {}: {}", self.offset, e);
+                    std::thread::sleep(RETRY_BACKOFF * 2_u32.pow(failures.min(10)));
+                    failures += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Consumes the header, if any, leaving the first bytes of a
+    /// plain stream's first record in `lookahead`.
+    fn detect_format(&mut self) -> Result<(), GpwError> {
+        let mut start = [0_u8; MAGIC.len()];
+        let filled = self.fill(&mut start)?;
+        if start[..filled] != MAGIC {
+            self.format = Some(Format::default());
+            self.lookahead = (start, filled);
+            return Ok(());
+        }
+        let mut rest = [0_u8; HEADER_LEN - MAGIC.len()];
+        if self.fill(&mut rest)? != rest.len() {
+            Err(("truncated header", "stream ends inside the header"))?
+        }
+        if !(1..=VERSION).contains(&rest[0]) {
+            Err(("unsupported version", rest[0]))?
+        }
+        let flags = rest[1];
+        let scale = LE::read_u32(&rest[4..8]);
+        let quantize = match flags & (FLAG_U16 | FLAG_U32) {
+            0 => None,
+            _ if scale == 0 => Err(("invalid header", "zero quantization scale"))?,
+            FLAG_U16 => Some(Quantize::U16 { scale }),
+            FLAG_U32 => Some(Quantize::U32 { scale }),
+            _ => Err(("invalid header", "conflicting quantization flags"))?,
+        };
+        self.offset = HEADER_LEN as u64;
+        if flags & FLAG_RECORD_COUNT != 0 {
+            let mut count = [0_u8; 8];
+            if self.fill(&mut count)? != count.len() {
+                Err(("truncated header", "stream ends inside the record count"))?
+            }
+            self.record_count = Some(LE::read_u64(&count));
+            self.offset += count.len() as u64;
+        }
+        self.format = Some(Format {
+            counts: flags & FLAG_COUNTS != 0,
+            quantize,
+            checksum: flags & FLAG_CHECKSUM != 0,
+            delta: flags & FLAG_DELTA != 0,
+        });
+        Ok(())
+    }
+
+    fn read_record(&mut self) -> Result<Option<(H3Cell, f32, u32)>, GpwError> {
+        if self.ended {
+            return Ok(None);
+        }
+        let format = self.read_header()?;
+        if format.delta {
+            return self.read_delta_record(format);
+        }
+        let mut record = [0_u8; MAX_RECORD_LEN];
+        let (lookahead, mut filled) = std::mem::take(&mut self.lookahead);
+        record[..filled].copy_from_slice(&lookahead[..filled]);
+        let record_len = format.record_len();
+        filled += self.fill(&mut record[filled..record_len])?;
+
+        let record_offset = self.offset;
+        self.offset += filled as u64;
+        match filled {
+            0 if format.checksum && !self.skip_checksum => {
+                self.ended = true;
+                Err((
+                    "missing checksum",
+                    format!("stream ends at offset {} without one", record_offset),
+                ))?
+            }
+            0 => Ok(None),
+            // Records are always longer than the footer, so a footer
+            // sized remainder can't be a partial record.
+            CHECKSUM_LEN if format.checksum => {
+                self.ended = true;
+                let expected = LE::read_u32(&record[..CHECKSUM_LEN]);
+                let actual = self.crc.clone().finalize();
+                if expected != actual && !self.skip_checksum {
+                    Err((
+                        "checksum mismatch",
+                        format!(
+                            "footer has {:08x}, records hash to {:08x}",
+                            expected, actual
+                        ),
+                    ))?
+                }
+                Ok(None)
+            }
+            partial if partial < record_len && self.tolerate_truncation => {
+                self.truncated = Some(partial);
+                Ok(None)
+            }
+            partial if partial < record_len => Err((
+                "truncated record",
+                format!("{} trailing bytes at offset {}", partial, record_offset),
+            ))?,
+            _ => {
+                self.crc.update(&record[..record_len]);
+                let h3_index = LE::read_u64(&record[..8]);
+                let (val, count) = format.decode_payload(&record[8..record_len]);
+                let cell = Self::cell(h3_index, record_offset)?;
+                Ok(Some((cell, val, count)))
+            }
+        }
+    }
+
+    /// Reads the next record of a delta-encoded stream, checking the
+    /// checksum once the end marker is reached.
+    fn read_delta_record(
+        &mut self,
+        format: Format,
+    ) -> Result<Option<(H3Cell, f32, u32)>, GpwError> {
+        let record_offset = self.offset;
+        let mut record = [0_u8; MAX_RECORD_LEN];
+        let mut delta = 0_u64;
+        let mut varint_len = 0;
+        loop {
+            if varint_len == MAX_VARINT_LEN {
+                self.ended = true;
+                Err((
+                    "invalid delta",
+                    format!("varint at offset {} is too long", record_offset),
+                ))?
+            }
+            let filled = self.fill(&mut record[varint_len..varint_len + 1])?;
+            self.offset += filled as u64;
+            if filled == 0 {
+                return self.end_delta_records(record_offset, varint_len);
+            }
+            let byte = record[varint_len];
+            delta |= u64::from(byte & 0x7f) << (7 * varint_len);
+            varint_len += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if varint_len == 1 && record[0] == END_OF_RECORDS {
+            self.ended = true;
+            return self.read_delta_footer(format);
+        }
+        let record_len = varint_len + format.payload_len();
+        let filled = self.fill(&mut record[varint_len..record_len])?;
+        self.offset += filled as u64;
+        if varint_len + filled < record_len {
+            return self.end_delta_records(record_offset, varint_len + filled);
+        }
+        self.crc.update(&record[..record_len]);
+        let h3_index = self
+            .prev_index
+            .checked_add(delta)
+            .filter(|_| delta != 0)
+            .ok_or_else(|| {
+                GpwError::from((
+                    "invalid delta",
+                    format!("{} at offset {}", delta, record_offset),
+                ))
+            })?;
+        self.prev_index = h3_index;
+        let (val, count) = format.decode_payload(&record[varint_len..record_len]);
+        let cell = Self::cell(h3_index, record_offset)?;
+        Ok(Some((cell, val, count)))
+    }
+
+    /// Handles a delta-encoded stream ending before its end marker,
+    /// `partial` bytes into the record at `record_offset`.
+    fn end_delta_records(
+        &mut self,
+        record_offset: u64,
+        partial: usize,
+    ) -> Result<Option<(H3Cell, f32, u32)>, GpwError> {
+        self.ended = true;
+        if self.tolerate_truncation {
+            self.truncated = Some(partial);
+            return Ok(None);
+        }
+        Err((
+            "truncated record",
+            format!(
+                "{} trailing bytes at offset {} and no end marker",
+                partial, record_offset
+            ),
+        ))?
+    }
+
+    /// Checks the checksum footer, if any, after a delta-encoded
+    /// stream's end marker.
+    fn read_delta_footer(
+        &mut self,
+        format: Format,
+    ) -> Result<Option<(H3Cell, f32, u32)>, GpwError> {
+        if !format.checksum || self.skip_checksum {
+            return Ok(None);
+        }
+        let footer_offset = self.offset;
+        let mut footer = [0_u8; CHECKSUM_LEN];
+        let filled = self.fill(&mut footer)?;
+        self.offset += filled as u64;
+        if filled < CHECKSUM_LEN {
+            Err((
+                "missing checksum",
+                format!("stream ends at offset {} without one", footer_offset),
+            ))?
+        }
+        let expected = LE::read_u32(&footer);
+        let actual = self.crc.clone().finalize();
+        if expected != actual {
+            Err((
+                "checksum mismatch",
+                format!(
+                    "footer has {:08x}, records hash to {:08x}",
+                    expected, actual
+                ),
+            ))?
+        }
+        Ok(None)
+    }
+
+    /// Checks that the index of the record at `offset` is a valid cell.
+    fn cell(h3_index: u64, offset: u64) -> Result<H3Cell, GpwError> {
+        H3Cell::try_from(h3_index).map_err(|e| GpwError::from(e).at_record(h3_index, offset))
+    }
+}
+
+impl<R: Read> Iterator for H3TessReader<R> {
+    type Item = Result<(H3Cell, f32), GpwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record()
+            .map(|record| record.map(|(cell, val, _count)| (cell, val)))
+            .transpose()
+    }
+}
+
+/// Scans an h3tess stream for the first malformed record and returns
+/// the number of records read if there are none.
+///
+/// A stream is well-formed when its length is a whole number of
+/// records and every index is a valid H3 cell. With `sorted`, indices
+/// must also be strictly increasing.
+pub fn verify(rdr: impl Read, sorted: bool) -> Result<u64, GpwError> {
+    let mut rdr = H3TessReader::new(rdr);
+    rdr.read_header()?;
+    let mut prev: Option<u64> = None;
+    let mut count = 0;
+    let mut offset = rdr.offset();
+    while let Some(record) = rdr.next() {
+        let (cell, _) = record?;
+        if let Some(prev) = prev.filter(|prev| sorted && *prev >= *cell) {
+            Err((
+                "unsorted record",
+                format!(
+                    "{:x} at offset {} does not follow {:x}",
+                    *cell, offset, prev
+                ),
+            ))?
+        }
+        prev = Some(*cell);
+        count += 1;
+        offset = rdr.offset();
+    }
+    Ok(count)
+}
+
+/// Cheaply checks that an h3tess stream `len` bytes long looks intact
+/// without reading all of it: the header parses, the first record
+/// decodes, and the length is a whole number of records plus any
+/// checksum footer, matching the recorded count if there is one.
+///
+/// Honors `rdr`'s [`H3TessReader::tolerate_truncation`] and
+/// [`H3TessReader::skip_checksum`] settings. Records of
+/// [delta](Format::delta) formats vary in length, so for those only
+/// the header and first record are checked.
+pub fn preflight<R: Read>(mut rdr: H3TessReader<R>, len: u64) -> Result<Format, GpwError> {
+    let format = rdr.read_header()?;
+    if format.delta {
+        rdr.next().transpose()?;
+        return Ok(format);
+    }
+    let record_len = format.record_len() as u64;
+    let body = len.checked_sub(rdr.offset()).ok_or((
+        "invalid length",
+        format!(
+            "{} bytes is shorter than the {} read for the header",
+            len,
+            rdr.offset()
+        ),
+    ))?;
+    let footer = if format.checksum {
+        CHECKSUM_LEN as u64
+    } else {
+        0
+    };
+    let fits = |footer: u64| {
+        body.checked_sub(footer)
+            .filter(|records| records % record_len == 0)
+    };
+    let records = match fits(footer) {
+        Some(records) => Some(records),
+        None if format.checksum && rdr.skip_checksum => fits(0),
+        None => None,
+    };
+    match (records, rdr.record_count) {
+        (None, _) if body < footer => Err((
+            "missing checksum",
+            format!("stream ends at offset {} without one", len),
+        ))?,
+        (None, _) if !rdr.tolerate_truncation => {
+            let partial = (body - footer) % record_len;
+            Err((
+                "truncated record",
+                format!(
+                    "{} trailing bytes at offset {}",
+                    partial,
+                    len - footer - partial
+                ),
+            ))?
+        }
+        (Some(records), Some(count)) if records / record_len != count => Err((
+            "record count mismatch",
+            format!(
+                "header says {} records, file holds {}",
+                count,
+                records / record_len
+            ),
+        ))?,
+        _ => {}
+    }
+    if body >= record_len {
+        rdr.next().transpose()?;
+    }
+    Ok(format)
+}
+
+/// How [`H3TessWriter`] encodes records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Encoding {
+    /// The binary h3tess layout described by a [`Format`].
+    #[default]
+    H3tess,
+    /// `h3_index,value` CSV with a header row, plus a `count` column
+    /// for formats with counts.
+    Csv,
+    /// One `{"h3_index": ..., "value": ...}` JSON object per line,
+    /// plus a `count` for formats with counts.
+    Ndjson,
+}
+
+impl Encoding {
+    /// Conventional file extension for the encoding.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::H3tess => "h3tess",
+            Encoding::Csv => "csv",
+            Encoding::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Writes `(cell, value)` records in the h3tess layout, or as text
+/// for the other [`Encoding`]s. Text records write indices in hex, as
+/// H3 tools conventionally show them.
+pub struct H3TessWriter<W: Write> {
+    wtr: W,
+    encoding: Encoding,
+    format: Format,
+    crc: Hasher,
+    record_count: Option<u64>,
+    written: u64,
+    prev_index: u64,
+}
+
+impl<W: Write> H3TessWriter<W> {
+    /// Creates a writer for plain, headerless `(index, value)` records.
+    pub fn new(wtr: W) -> Self {
+        Self {
+            wtr,
+            encoding: Encoding::H3tess,
+            format: Format::default(),
+            crc: Hasher::new(),
+            record_count: None,
+            written: 0,
+            prev_index: 0,
+        }
+    }
+
+    /// Creates a writer for `format`, writing the file header if the
+    /// format needs one.
+    pub fn with_format(wtr: W, format: Format) -> Result<Self, GpwError> {
+        Self::create(wtr, format, None)
+    }
+
+    /// Creates a writer that records up front that exactly
+    /// `record_count` records follow, so readers can size progress
+    /// bars and allocations without guessing from the file size. Always
+    /// writes a header; [`Self::finish`] fails if a different number
+    /// of records was written.
+    pub fn with_record_count(wtr: W, format: Format, record_count: u64) -> Result<Self, GpwError> {
+        Self::create(wtr, format, Some(record_count))
+    }
+
+    /// Creates a writer for `format` in `encoding`. Text encodings
+    /// honor [`Format::counts`] but can't quantize, checksum or
+    /// delta-encode.
+    pub fn with_encoding(mut wtr: W, encoding: Encoding, format: Format) -> Result<Self, GpwError> {
+        let header = match encoding {
+            Encoding::H3tess => return Self::with_format(wtr, format),
+            _ if format.quantize.is_some() || format.checksum || format.delta => Err((
+                "encoding",
+                format!(
+                    "{:?} output can't be quantized, checksummed or delta-encoded",
+                    encoding
+                ),
+            ))?,
+            Encoding::Csv if format.counts => "h3_index,value,count\n",
+            Encoding::Csv => "h3_index,value\n",
+            Encoding::Ndjson => "",
+        };
+        wtr.write_all(header.as_bytes())?;
+        Ok(Self {
+            wtr,
+            encoding,
+            format,
+            crc: Hasher::new(),
+            record_count: None,
+            written: 0,
+            prev_index: 0,
+        })
+    }
+
+    fn create(mut wtr: W, format: Format, record_count: Option<u64>) -> Result<Self, GpwError> {
+        if format.has_header() || record_count.is_some() {
+            let mut header = [0_u8; HEADER_LEN];
+            header[..MAGIC.len()].copy_from_slice(&MAGIC);
+            header[MAGIC.len()] = match record_count {
+                Some(_) => format.version().max(2),
+                None => format.version(),
+            };
+            header[MAGIC.len() + 1] = match record_count {
+                Some(_) => format.flags() | FLAG_RECORD_COUNT,
+                None => format.flags(),
+            };
+            if let Some(quantize) = format.quantize {
+                LE::write_u32(&mut header[MAGIC.len() + 4..], quantize.scale());
+            }
+            wtr.write_all(&header)?;
+        }
+        if let Some(record_count) = record_count {
+            wtr.write_all(&record_count.to_le_bytes())?;
+        }
+        Ok(Self {
+            wtr,
+            encoding: Encoding::H3tess,
+            format,
+            crc: Hasher::new(),
+            record_count,
+            written: 0,
+            prev_index: 0,
+        })
+    }
+
+    pub fn write_pair(&mut self, cell: H3Cell, value: f32) -> Result<(), GpwError> {
+        self.write_record(cell, value, 1)
+    }
+
+    /// Writes a record along with the number of source cells that
+    /// contributed to it. The count is dropped for formats without
+    /// counts. Fails without writing anything for a value that a
+    /// quantized format can't represent, or for a delta format, an
+    /// index that doesn't follow the previous one.
+    pub fn write_record(&mut self, cell: H3Cell, value: f32, count: u32) -> Result<(), GpwError> {
+        match (self.encoding, self.format.counts) {
+            (Encoding::H3tess, _) => return self.write_binary_record(cell, value, count),
+            (Encoding::Csv, false) => writeln!(self.wtr, "{:x},{}", *cell, value)?,
+            (Encoding::Csv, true) => writeln!(self.wtr, "{:x},{},{}", *cell, value, count)?,
+            (Encoding::Ndjson, false) => writeln!(
+                self.wtr,
+                r#"{{"h3_index":"{:x}","value":{}}}"#,
+                *cell, value
+            )?,
+            (Encoding::Ndjson, true) => writeln!(
+                self.wtr,
+                r#"{{"h3_index":"{:x}","value":{},"count":{}}}"#,
+                *cell, value, count
+            )?,
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn write_binary_record(
+        &mut self,
+        cell: H3Cell,
+        value: f32,
+        count: u32,
+    ) -> Result<(), GpwError> {
+        let steps = self.format.quantize.map(|q| q.encode(value)).transpose()?;
+        let mut record = [0_u8; MAX_RECORD_LEN];
+        let index_len = if self.format.delta {
+            if *cell <= self.prev_index {
+                Err((
+                    "unsorted record",
+                    format!(
+                        "{:x} does not follow {:x} in a delta-encoded stream",
+                        *cell, self.prev_index
+                    ),
+                ))?
+            }
+            write_varint(&mut record, *cell - self.prev_index)
+        } else {
+            LE::write_u64(&mut record[..8], *cell);
+            8
+        };
+        let payload = &mut record[index_len..];
+        let count_at = match (self.format.quantize, steps) {
+            (Some(Quantize::U16 { .. }), Some(steps)) => {
+                LE::write_u16(&mut payload[..2], steps as u16);
+                2
+            }
+            (Some(Quantize::U32 { .. }), Some(steps)) => {
+                LE::write_u32(&mut payload[..4], steps);
+                4
+            }
+            _ => {
+                LE::write_f32(&mut payload[..4], value);
+                4
+            }
+        };
+        if self.format.counts {
+            LE::write_u32(&mut payload[count_at..count_at + 4], count);
+        }
+        let record = &record[..index_len + self.format.payload_len()];
+        self.crc.update(record);
+        self.wtr.write_all(record)?;
+        self.prev_index = *cell;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Pushes all records written so far through to the underlying
+    /// writer, so they survive a later crash.
+    pub fn flush(&mut self) -> Result<(), GpwError> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+
+    /// Writes the checksum footer, if the format has one, flushes all
+    /// written records and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, GpwError> {
+        if let Some(expected) = self.record_count.filter(|n| *n != self.written) {
+            Err((
+                "record count mismatch",
+                format!("header promises {}, wrote {}", expected, self.written),
+            ))?
+        }
+        if self.format.delta {
+            self.wtr.write_all(&[END_OF_RECORDS])?;
+        }
+        if self.format.checksum {
+            let mut footer = [0_u8; CHECKSUM_LEN];
+            LE::write_u32(&mut footer, self.crc.clone().finalize());
+            self.wtr.write_all(&footer)?;
+        }
+        self.wtr.flush()?;
+        Ok(self.wtr)
+    }
+}
+
+/// Writes `val` as an unsigned LEB128 varint at the start of `buf`,
+/// returning its length.
+fn write_varint(buf: &mut [u8], mut val: u64) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            buf[len] = byte;
+            return len + 1;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hextree::h3ron::{self, FromH3Index};
+
+    fn records(pairs: &[(u64, f32)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (h3_index, val) in pairs {
+            buf.extend_from_slice(&h3_index.to_le_bytes());
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Fails each read with `errors` in turn before reading from
+    /// `inner`.
+    struct Flaky<R> {
+        inner: R,
+        errors: Vec<ErrorKind>,
+    }
+
+    impl<R: Read> Read for Flaky<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.errors.pop() {
+                Some(kind) => Err(kind.into()),
+                None => self.inner.read(buf),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_retries() {
+        let buf = records(&[(0x8a1fb46622dffff, 1.5)]);
+        let flaky = |errors| Flaky {
+            inner: buf.as_slice(),
+            errors,
+        };
+
+        let rdr = H3TessReader::new(flaky(vec![ErrorKind::Interrupted]));
+        let pairs = rdr.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].1, 1.5);
+
+        let rdr = H3TessReader::new(flaky(vec![ErrorKind::WouldBlock; 2])).read_attempts(3);
+        assert_eq!(rdr.count(), 1);
+
+        // Giving up after the last attempt, or at once for other errors.
+        let mut rdr = H3TessReader::new(flaky(vec![ErrorKind::WouldBlock; 3])).read_attempts(3);
+        assert!(matches!(rdr.next(), Some(Err(GpwError::Io(_)))));
+        let mut rdr = H3TessReader::new(flaky(vec![ErrorKind::NotFound]));
+        assert!(matches!(rdr.next(), Some(Err(GpwError::Io(_)))));
+    }
+
+    #[test]
+    fn test_read_to_clean_eof() {
+        let buf = records(&[(0x8a1fb46622dffff, 1.5), (0x8a1fb46622d7fff, 2.5)]);
+        let pairs = H3TessReader::new(buf.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                (H3Cell::from_h3index(0x8a1fb46622dffff), 1.5),
+                (H3Cell::from_h3index(0x8a1fb46622d7fff), 2.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_truncated_record() {
+        let mut buf = records(&[(0x8a1fb46622dffff, 1.5), (0x8a1fb46622d7fff, 2.5)]);
+        buf.truncate(RECORD_LEN + 8);
+        let mut rdr = H3TessReader::new(buf.as_slice());
+        assert!(rdr.next().unwrap().is_ok());
+        assert!(rdr.next().unwrap().is_err());
+        assert_eq!(rdr.offset(), buf.len() as u64);
+        assert!(rdr.next().is_none());
+    }
+
+    #[test]
+    fn test_tolerate_truncated_record() {
+        let mut buf = records(&[(0x8a1fb46622dffff, 1.5), (0x8a1fb46622d7fff, 2.5)]);
+        buf.truncate(RECORD_LEN + 8);
+        let mut rdr = H3TessReader::new(buf.as_slice()).tolerate_truncation(true);
+        assert_eq!(rdr.by_ref().count(), 1);
+        assert_eq!(rdr.truncated(), Some(8));
+    }
+
+    #[test]
+    fn test_verify() {
+        let good = records(&[(0x8a1fb46622d7fff, 2.5), (0x8a1fb46622dffff, 1.5)]);
+        assert_eq!(verify(good.as_slice(), true).unwrap(), 2);
+
+        let bad_length = &good[..good.len() - 1];
+        assert!(verify(bad_length, false).is_err());
+
+        let invalid_index = records(&[(0x8a1fb46622d7fff, 2.5), (u64::MAX, 1.5)]);
+        let err = verify(invalid_index.as_slice(), false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("ffffffffffffffff at offset {}", RECORD_LEN)),
+            "{}",
+            err
+        );
+
+        let unsorted = records(&[(0x8a1fb46622dffff, 1.5), (0x8a1fb46622d7fff, 2.5)]);
+        assert!(verify(unsorted.as_slice(), false).is_ok());
+        assert!(verify(unsorted.as_slice(), true).is_err());
+    }
+
+    #[test]
+    fn test_preflight() {
+        let good = records(&[(0x8a1fb46622d7fff, 2.5), (0x8a1fb46622dffff, 1.5)]);
+        let check = |buf: &[u8]| preflight(H3TessReader::new(buf), buf.len() as u64);
+        assert_eq!(check(&good).unwrap(), Format::default());
+        assert_eq!(check(&[]).unwrap(), Format::default());
+
+        let truncated = &good[..good.len() - 1];
+        assert!(check(truncated).is_err());
+        let tolerant = H3TessReader::new(truncated).tolerate_truncation(true);
+        assert!(preflight(tolerant, truncated.len() as u64).is_ok());
+
+        let invalid_index = records(&[(u64::MAX, 1.5), (0x8a1fb46622d7fff, 2.5)]);
+        assert!(check(&invalid_index).is_err());
+
+        let format = Format {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut wtr = H3TessWriter::with_record_count(Vec::new(), format, 2).unwrap();
+        wtr.write_pair(H3Cell::from_h3index(0x8a1fb46622d7fff), 2.5)
+            .unwrap();
+        wtr.write_pair(H3Cell::from_h3index(0x8a1fb46622dffff), 1.5)
+            .unwrap();
+        let mut checksummed = wtr.finish().unwrap();
+        assert_eq!(check(&checksummed).unwrap(), format);
+        checksummed.truncate(checksummed.len() - CHECKSUM_LEN);
+        assert!(check(&checksummed).is_err());
+        let skipping = H3TessReader::new(checksummed.as_slice()).skip_checksum(true);
+        assert!(preflight(skipping, checksummed.len() as u64).is_ok());
+        checksummed.truncate(checksummed.len() - RECORD_LEN);
+        let skipping = H3TessReader::new(checksummed.as_slice()).skip_checksum(true);
+        let msg = format!(
+            "{:?}",
+            preflight(skipping, checksummed.len() as u64).unwrap_err()
+        );
+        assert!(msg.contains("record count mismatch"), "{}", msg);
+
+        // A length shorter than the header already read.
+        let short = preflight(H3TessReader::new(checksummed.as_slice()), 4).unwrap_err();
+        assert!(
+            matches!(
+                short,
+                GpwError::FieldParse {
+                    field: "invalid length",
+                    ..
+                }
+            ),
+            "{:?}",
+            short
+        );
+    }
+
+    #[test]
+    fn test_text_encodings() {
+        let records = [
+            (H3Cell::from_h3index(0x8a1fb46622dffff), 1.5, 1),
+            (H3Cell::from_h3index(0x8a1fb46622d7fff), 0.25, 7),
+        ];
+        let encode = |encoding, counts| {
+            let format = Format {
+                counts,
+                ..Default::default()
+            };
+            let mut wtr = H3TessWriter::with_encoding(Vec::new(), encoding, format).unwrap();
+            for (cell, val, count) in records {
+                wtr.write_record(cell, val, count).unwrap();
+            }
+            String::from_utf8(wtr.finish().unwrap()).unwrap()
+        };
+        assert_eq!(
+            encode(Encoding::Csv, false),
+            "h3_index,value\n8a1fb46622dffff,1.5\n8a1fb46622d7fff,0.25\n"
+        );
+        assert_eq!(
+            encode(Encoding::Csv, true),
+            "h3_index,value,count\n8a1fb46622dffff,1.5,1\n8a1fb46622d7fff,0.25,7\n"
+        );
+        assert_eq!(
+            encode(Encoding::Ndjson, false),
+            concat!(
+                "{\"h3_index\":\"8a1fb46622dffff\",\"value\":1.5}\n",
+                "{\"h3_index\":\"8a1fb46622d7fff\",\"value\":0.25}\n",
+            )
+        );
+        assert_eq!(
+            encode(Encoding::Ndjson, true),
+            concat!(
+                "{\"h3_index\":\"8a1fb46622dffff\",\"value\":1.5,\"count\":1}\n",
+                "{\"h3_index\":\"8a1fb46622d7fff\",\"value\":0.25,\"count\":7}\n",
+            )
+        );
+
+        let checksummed = Format {
+            checksum: true,
+            ..Default::default()
+        };
+        assert!(H3TessWriter::with_encoding(Vec::new(), Encoding::Csv, checksummed).is_err());
+    }
+
+    #[test]
+    fn test_invalid_index_error() {
+        let buf = records(&[(0x8a1fb46622d7fff, 2.5), (u64::MAX, 1.5)]);
+        let mut rdr = H3TessReader::new(buf.as_slice());
+        assert!(rdr.next().unwrap().is_ok());
+        assert_eq!(
+            rdr.next().unwrap().unwrap_err(),
+            GpwError::H3 {
+                source: h3ron::Error::CellInvalid,
+                index: Some(u64::MAX),
+                offset: Some(RECORD_LEN as u64),
+            }
+        );
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        let pairs = vec![
+            (H3Cell::from_h3index(0x8a1fb46622dffff), 1.5),
+            (H3Cell::from_h3index(0x8a1fb46622d7fff), 2.5),
+        ];
+        let mut wtr = H3TessWriter::new(Vec::new());
+        for (cell, val) in &pairs {
+            wtr.write_pair(*cell, *val).unwrap();
+        }
+        let buf = wtr.finish().unwrap();
+        assert_eq!(buf.len(), pairs.len() * RECORD_LEN);
+        let read_back = H3TessReader::new(buf.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read_back, pairs);
+    }
+
+    #[test]
+    fn test_counts_round_trip() {
+        let expected = vec![
+            (H3Cell::from_h3index(0x8a1fb46622dffff), 1.5, 1),
+            (H3Cell::from_h3index(0x8a1fb46622d7fff), 2.5, 7),
+        ];
+        let format = Format {
+            counts: true,
+            ..Default::default()
+        };
+        let mut wtr = H3TessWriter::with_format(Vec::new(), format).unwrap();
+        for (cell, val, count) in &expected {
+            wtr.write_record(*cell, *val, *count).unwrap();
+        }
+        let buf = wtr.finish().unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + expected.len() * format.record_len());
+
+        let mut rdr = H3TessReader::new(buf.as_slice());
+        let read_back = rdr.counted().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(read_back, expected);
+        assert_eq!(rdr.format(), Some(format));
+
+        // Plain files read as a count of 1 per record.
+        let plain = records(&[(0x8a1fb46622dffff, 1.5)]);
+        let mut rdr = H3TessReader::new(plain.as_slice());
+        let read_back = rdr.counted().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(read_back, vec![(expected[0].0, 1.5, 1)]);
+    }
+
+    #[test]
+    fn test_quantized_round_trip() {
+        let cells =
+            [0x8a1fb46622dffff, 0x8a1fb46622d7fff, 0x8a1fb46622c7fff].map(H3Cell::from_h3index);
+        let values = [0.0, 1.234_567, 6553.5];
+        for (quantize, record_len) in [
+            (Quantize::U16 { scale: 10 }, 10),
+            (Quantize::U32 { scale: 1000 }, 12),
+        ] {
+            let format = Format {
+                quantize: Some(quantize),
+                ..Default::default()
+            };
+            let mut wtr = H3TessWriter::with_format(Vec::new(), format).unwrap();
+            for (cell, val) in cells.iter().zip(values) {
+                wtr.write_pair(*cell, val).unwrap();
+            }
+            let buf = wtr.finish().unwrap();
+            assert_eq!(format.record_len(), record_len);
+            assert_eq!(buf.len(), HEADER_LEN + cells.len() * record_len);
+
+            let mut rdr = H3TessReader::new(buf.as_slice());
+            let read_back = rdr.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(rdr.format(), Some(format));
+            let step = 1.0 / quantize.scale() as f32;
+            for ((cell, val), (read_cell, read_val)) in cells.iter().zip(values).zip(read_back) {
+                assert_eq!(*cell, read_cell);
+                assert!(
+                    (val - read_val).abs() <= step / 2.0,
+                    "{} vs {}",
+                    val,
+                    read_val
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_out_of_range() {
+        let format = Format {
+            quantize: Some(Quantize::U16 { scale: 10 }),
+            ..Default::default()
+        };
+        let mut wtr = H3TessWriter::with_format(Vec::new(), format).unwrap();
+        let cell = H3Cell::from_h3index(0x8a1fb46622dffff);
+        assert!(wtr.write_pair(cell, 6553.6).is_err());
+        assert!(wtr.write_pair(cell, -1.0).is_err());
+        assert!(wtr.write_pair(cell, f32::NAN).is_err());
+        assert_eq!(wtr.finish().unwrap().len(), HEADER_LEN);
+    }
+
+    #[test]
+    fn test_parse_quantize() {
+        assert_eq!("u16:10".parse(), Ok(Quantize::U16 { scale: 10 }));
+        assert_eq!("u32:1000".parse(), Ok(Quantize::U32 { scale: 1000 }));
+        assert!("u16:0".parse::<Quantize>().is_err());
+        assert!("u8:10".parse::<Quantize>().is_err());
+        assert!("10".parse::<Quantize>().is_err());
+    }
+
+    fn checksummed(pairs: &[(u64, f32)]) -> Vec<u8> {
+        let format = Format {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut wtr = H3TessWriter::with_format(Vec::new(), format).unwrap();
+        for (h3_index, val) in pairs {
+            wtr.write_pair(H3Cell::from_h3index(*h3_index), *val)
+                .unwrap();
+        }
+        wtr.finish().unwrap()
+    }
+
+    #[test]
+    fn test_checksum_matches() {
+        let pairs = [(0x8a1fb46622dffff, 1.5), (0x8a1fb46622d7fff, 2.5)];
+        let buf = checksummed(&pairs);
+        assert_eq!(
+            buf.len(),
+            HEADER_LEN + pairs.len() * RECORD_LEN + CHECKSUM_LEN
+        );
+        let mut rdr = H3TessReader::new(buf.as_slice());
+        assert_eq!(rdr.by_ref().count(), 2);
+        assert!(rdr.format().unwrap().checksum);
+        assert_eq!(verify(buf.as_slice(), false).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut buf = checksummed(&[(0x8a1fb46622dffff, 1.5), (0x8a1fb46622d7fff, 2.5)]);
+        // Flip a bit in the second record's value.
+        buf[HEADER_LEN + RECORD_LEN + 8] ^= 1;
+        let results = H3TessReader::new(buf.as_slice()).collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert!(results[..2].iter().all(Result::is_ok));
+        assert!(results[2].is_err());
+        assert!(verify(buf.as_slice(), false).is_err());
+
+        let skipped = H3TessReader::new(buf.as_slice()).skip_checksum(true);
+        assert_eq!(skipped.collect::<Result<Vec<_>, _>>().unwrap().len(), 2);
+
+        // Losing the footer is an error too.
+        buf.truncate(buf.len() - CHECKSUM_LEN);
+        let mut rdr = H3TessReader::new(buf.as_slice());
+        assert!(rdr.by_ref().last().unwrap().is_err());
+        assert!(rdr.next().is_none());
+    }
+
+    #[test]
+    fn test_record_count() {
+        let format = Format {
+            quantize: Some(Quantize::U16 { scale: 10 }),
+            ..Default::default()
+        };
+        let cells = [0x8a1fb46622dffff, 0x8a1fb46622d7fff].map(H3Cell::from_h3index);
+        let mut wtr = H3TessWriter::with_record_count(Vec::new(), format, 2).unwrap();
+        for cell in cells {
+            wtr.write_pair(cell, 1.5).unwrap();
+        }
+        let buf = wtr.finish().unwrap();
+
+        let mut rdr = H3TessReader::new(buf.as_slice());
+        assert_eq!(rdr.read_header().unwrap(), format);
+        assert_eq!(rdr.record_count(), Some(2));
+        assert_eq!(rdr.offset(), HEADER_LEN as u64 + 8);
+        assert_eq!(rdr.count(), 2);
+
+        // Headerless streams have no recorded count, and reading the
+        // header up front doesn't lose their first record.
+        let plain = records(&[(0x8a1fb46622dffff, 1.5)]);
+        let mut rdr = H3TessReader::new(plain.as_slice());
+        assert_eq!(rdr.read_header().unwrap(), Format::default());
+        assert_eq!(rdr.record_count(), None);
+        assert_eq!(rdr.next().unwrap().unwrap(), (cells[0], 1.5));
+
+        let mut wtr = H3TessWriter::with_record_count(Vec::new(), format, 3).unwrap();
+        wtr.write_pair(cells[0], 1.5).unwrap();
+        assert!(wtr.finish().is_err());
+    }
+
+    /// Every res 10 cell under a res 5 cell in Zurich, in index order,
+    /// with made-up values and counts.
+    fn dense_region() -> Vec<(H3Cell, f32, u32)> {
+        let parent = H3Cell::from_coordinate(geo::coord! {x: 8.5, y: 47.4}, 5).unwrap();
+        let mut cells = parent.get_children(10).unwrap().iter().collect::<Vec<_>>();
+        cells.sort_unstable_by_key(|cell| **cell);
+        cells
+            .into_iter()
+            .enumerate()
+            .map(|(i, cell)| (cell, (i % 100) as f32 / 4.0, (i % 7) as u32 + 1))
+            .collect()
+    }
+
+    fn write_all(format: Format, records: &[(H3Cell, f32, u32)]) -> Vec<u8> {
+        let mut wtr = H3TessWriter::with_format(Vec::new(), format).unwrap();
+        for (cell, val, count) in records {
+            wtr.write_record(*cell, *val, *count).unwrap();
+        }
+        wtr.finish().unwrap()
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let records = dense_region();
+        for (counts, quantize, checksum) in [
+            (false, None, false),
+            (true, None, true),
+            (false, Some(Quantize::U16 { scale: 4 }), true),
+        ] {
+            let format = Format {
+                counts,
+                quantize,
+                checksum,
+                delta: true,
+            };
+            let buf = write_all(format, &records);
+            let mut rdr = H3TessReader::new(buf.as_slice());
+            let read_back = rdr.counted().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(rdr.format(), Some(format));
+            assert_eq!(rdr.offset(), buf.len() as u64);
+            let expected = records
+                .iter()
+                .map(|(cell, val, count)| (*cell, *val, if counts { *count } else { 1 }))
+                .collect::<Vec<_>>();
+            assert_eq!(read_back, expected);
+            assert_eq!(verify(buf.as_slice(), true).unwrap(), records.len() as u64);
+            assert_eq!(
+                preflight(H3TessReader::new(buf.as_slice()), buf.len() as u64).unwrap(),
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_size() {
+        let records = dense_region();
+        let plain = write_all(Format::default(), &records);
+        let delta = write_all(
+            Format {
+                delta: true,
+                ..Default::default()
+            },
+            &records,
+        );
+        // Sibling res 10 indices are 2^15 apart, so nearly every index
+        // takes a 3 byte varint, and only the few where a coarser
+        // digit rolls over need 4.
+        assert_eq!(plain.len(), records.len() * RECORD_LEN);
+        let per_record = (delta.len() - HEADER_LEN) as f64 / records.len() as f64;
+        assert!(per_record < 7.01, "{} bytes per record", per_record);
+    }
+
+    #[test]
+    fn test_delta_errors() {
+        let records = dense_region();
+        let format = Format {
+            checksum: true,
+            delta: true,
+            ..Default::default()
+        };
+        let mut wtr = H3TessWriter::with_format(Vec::new(), format).unwrap();
+        wtr.write_pair(records[1].0, 1.0).unwrap();
+        assert!(wtr.write_pair(records[0].0, 1.0).is_err());
+        assert!(wtr.write_pair(records[1].0, 1.0).is_err());
+        assert!(H3TessWriter::with_encoding(Vec::new(), Encoding::Csv, format).is_err());
+
+        let buf = write_all(format, &records[..3]);
+        // Losing the end marker and footer, or part of a record.
+        for (cut, whole, partial) in [(CHECKSUM_LEN + 1, 3, 0), (CHECKSUM_LEN + 3, 2, 5)] {
+            let truncated = &buf[..buf.len() - cut];
+            let results = H3TessReader::new(truncated).collect::<Vec<_>>();
+            assert!(results.last().unwrap().is_err());
+            let mut rdr = H3TessReader::new(truncated).tolerate_truncation(true);
+            assert_eq!(rdr.by_ref().count(), whole);
+            assert_eq!(rdr.truncated(), Some(partial));
+        }
+
+        let mut corrupt = buf;
+        corrupt[HEADER_LEN + 10] ^= 1;
+        assert!(verify(corrupt.as_slice(), false).is_err());
+        let skipping = H3TessReader::new(corrupt.as_slice()).skip_checksum(true);
+        assert_eq!(skipping.count(), 3);
+    }
+}
@@ -0,0 +1,222 @@
+//! Rasterizing maps into heatmap images for a quick visual check.
+
+use clap::ValueEnum;
+use std::str::FromStr;
+
+/// Color ramps from low to high population.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Colormap {
+    /// Black to white.
+    Gray,
+    /// Black through purple and orange to pale yellow.
+    #[default]
+    Inferno,
+    /// Dark purple through teal to yellow.
+    Viridis,
+}
+
+impl Colormap {
+    /// Evenly spaced colors the ramp interpolates between.
+    fn stops(self) -> &'static [[u8; 3]] {
+        match self {
+            Colormap::Gray => &[[0, 0, 0], [255, 255, 255]],
+            Colormap::Inferno => &[
+                [0, 0, 4],
+                [87, 16, 110],
+                [188, 55, 84],
+                [249, 142, 9],
+                [252, 255, 164],
+            ],
+            Colormap::Viridis => &[
+                [68, 1, 84],
+                [59, 82, 139],
+                [33, 145, 140],
+                [94, 201, 98],
+                [253, 231, 37],
+            ],
+        }
+    }
+
+    /// Returns the color at `t`, clamped to `[0, 1]`.
+    pub fn color(self, t: f64) -> [u8; 3] {
+        let stops = self.stops();
+        let pos = t.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+        let lo = (pos.floor() as usize).min(stops.len() - 2);
+        let frac = pos - lo as f64;
+        let (a, b) = (stops[lo], stops[lo + 1]);
+        [0, 1, 2]
+            .map(|i| (f64::from(a[i]) + (f64::from(b[i]) - f64::from(a[i])) * frac).round() as u8)
+    }
+}
+
+/// A lat/lng box in degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extent {
+    pub min_lat: f64,
+    pub min_lng: f64,
+    pub max_lat: f64,
+    pub max_lng: f64,
+}
+
+impl Extent {
+    pub const WORLD: Extent = Extent {
+        min_lat: -90.0,
+        min_lng: -180.0,
+        max_lat: 90.0,
+        max_lng: 180.0,
+    };
+}
+
+impl FromStr for Extent {
+    type Err = String;
+
+    /// Parses `MIN_LAT,MIN_LNG,MAX_LAT,MAX_LNG`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|val| val.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{:?}: {}", s, e))?;
+        let [min_lat, min_lng, max_lat, max_lng] = values[..] else {
+            return Err(format!(
+                "expected MIN_LAT,MIN_LNG,MAX_LAT,MAX_LNG, got {:?}",
+                s
+            ));
+        };
+        if !(-90.0..=90.0).contains(&min_lat)
+            || !(-90.0..=90.0).contains(&max_lat)
+            || !(-180.0..=180.0).contains(&min_lng)
+            || !(-180.0..=180.0).contains(&max_lng)
+            || min_lat >= max_lat
+            || min_lng >= max_lng
+        {
+            return Err(format!("{:?} is not a box on the globe", s));
+        }
+        Ok(Extent {
+            min_lat,
+            min_lng,
+            max_lat,
+            max_lng,
+        })
+    }
+}
+
+/// Population summed into the pixels of an equirectangular image,
+/// binning each cell by its center.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Raster {
+    extent: Extent,
+    width: u32,
+    height: u32,
+    /// Row-major sums, north row first.
+    sums: Vec<f64>,
+}
+
+impl Raster {
+    /// Creates an empty raster `width` pixels wide covering `extent`,
+    /// as tall as keeps pixels square.
+    pub fn new(extent: Extent, width: u32) -> Self {
+        let width = width.max(1);
+        let aspect = (extent.max_lat - extent.min_lat) / (extent.max_lng - extent.min_lng);
+        let height = ((f64::from(width) * aspect).round() as u32).max(1);
+        Self {
+            extent,
+            width,
+            height,
+            sums: vec![0.0; width as usize * height as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Adds `val` to the pixel containing `(lat, lng)`. Points outside
+    /// the extent are ignored.
+    pub fn push(&mut self, lat: f64, lng: f64, val: f64) {
+        let Extent {
+            min_lat,
+            min_lng,
+            max_lat,
+            max_lng,
+        } = self.extent;
+        if !(min_lat..=max_lat).contains(&lat) || !(min_lng..=max_lng).contains(&lng) {
+            return;
+        }
+        let col = ((lng - min_lng) / (max_lng - min_lng) * f64::from(self.width)) as u32;
+        let row = ((max_lat - lat) / (max_lat - min_lat) * f64::from(self.height)) as u32;
+        let (col, row) = (col.min(self.width - 1), row.min(self.height - 1));
+        self.sums[row as usize * self.width as usize + col as usize] += val;
+    }
+
+    /// Renders 8-bit RGBA pixels, row-major from the north. Pixels are
+    /// colored by `ln(1 + population)` relative to the fullest pixel;
+    /// unpopulated pixels are transparent.
+    pub fn rgba(&self, colormap: Colormap) -> Vec<u8> {
+        let max = self.sums.iter().map(|sum| sum.ln_1p()).fold(0.0, f64::max);
+        let mut pixels = Vec::with_capacity(self.sums.len() * 4);
+        for sum in &self.sums {
+            if *sum > 0.0 {
+                let t = if max > 0.0 { sum.ln_1p() / max } else { 1.0 };
+                pixels.extend(colormap.color(t));
+                pixels.push(u8::MAX);
+            } else {
+                pixels.extend([0; 4]);
+            }
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colormap() {
+        for colormap in [Colormap::Gray, Colormap::Inferno, Colormap::Viridis] {
+            let stops = colormap.stops();
+            assert_eq!(colormap.color(0.0), stops[0]);
+            assert_eq!(colormap.color(1.0), stops[stops.len() - 1]);
+            assert_eq!(colormap.color(2.0), stops[stops.len() - 1]);
+        }
+        assert_eq!(Colormap::Gray.color(0.5), [128, 128, 128]);
+    }
+
+    #[test]
+    fn test_raster() {
+        assert_eq!(
+            "0,10,5,20".parse::<Extent>(),
+            Ok(Extent {
+                min_lat: 0.0,
+                min_lng: 10.0,
+                max_lat: 5.0,
+                max_lng: 20.0,
+            })
+        );
+        assert!("5,10,0,20".parse::<Extent>().is_err());
+        assert!("0,10,5".parse::<Extent>().is_err());
+
+        let mut raster = Raster::new("0,10,5,20".parse().unwrap(), 4);
+        assert_eq!((raster.width(), raster.height()), (4, 2));
+        // Top-left and bottom-right corners, the far corner included,
+        // and a point outside that's dropped.
+        raster.push(4.9, 10.1, 1.0);
+        raster.push(4.9, 10.2, 1.0);
+        raster.push(0.0, 20.0, 1.0);
+        raster.push(-1.0, 15.0, 100.0);
+        let pixels = raster.rgba(Colormap::Gray);
+        assert_eq!(pixels.len(), 4 * 2 * 4);
+        assert_eq!(&pixels[..4], &[255, 255, 255, 255]);
+        let dim = (255.0 * 2f64.ln() / 3f64.ln()).round() as u8;
+        assert_eq!(&pixels[pixels.len() - 4..], &[dim, dim, dim, 255]);
+        assert_eq!(
+            pixels.iter().skip(3).step_by(4).filter(|a| **a > 0).count(),
+            2
+        );
+    }
+}
@@ -1,4 +1,36 @@
+//! Tessellating GPW population grids into H3 cells and combining them
+//! into maps.
+//!
+//! # Features
+//!
+//! - `parallel` (default): tessellates on a rayon thread pool and
+//!   reads combine sources ahead on background threads. Without it,
+//!   [`generate::gen_to_disk`] works through the grid on the calling
+//!   thread.
+//! - `cli` (default): the `gpwgen` binary and its dependencies.
+//!   Implies `parallel`.
+//! - `netcdf`: reads grids from NetCDF files with
+//!   [`gpwnetcdf`](crate::gpwnetcdf). Links the system's libnetcdf.
+//!
+//! With neither, the library builds for targets without threads, such
+//! as `wasm32-unknown-unknown`:
+//!
+//! ```text
+//! cargo build -p gpwgen --lib --no-default-features --target wasm32-unknown-unknown
+//! ```
+//!
+//! H3 itself is C, compiled by `hextree`'s build script, so this also
+//! needs a C compiler that targets wasm32, such as clang.
+
+pub mod area;
 pub mod args;
+pub mod combine;
 pub mod error;
 pub mod generate;
 pub mod gpwascii;
+#[cfg(feature = "netcdf")]
+pub mod gpwnetcdf;
+pub mod h3tess;
+pub mod heatmap;
+pub mod naming;
+pub mod stats;
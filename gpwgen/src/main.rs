@@ -1,34 +1,89 @@
-use anyhow::{anyhow, Result};
-use byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt};
+use anyhow::{anyhow, Context, Result};
+use arrow_array::{ArrayRef, Float32Array, RecordBatch, UInt32Array, UInt64Array};
+use arrow_schema::{DataType, Field, Schema};
 use clap::Parser;
+use flate2::read::MultiGzDecoder;
 use gpwgen::{
-    args::{Args, Combine, Tessellate},
-    generate::gen_to_disk,
-    gpwascii::GpwAscii,
+    args::{
+        Args, Build, Combine, Heatmap, Hist, HistFormat, ImportCsv, MapFormat, MergeTiles,
+        Tessellate, Verify,
+    },
+    combine::{coarsen, read_ahead, ReduceCompactor, ReduceMode},
+    error::GpwError,
+    generate::{gen_to_disk, gen_to_disk_cancellable, tessalate_grid, GenOptions, GenStats},
+    gpwascii::{GpwAscii, GpwAsciiHeader, NonFinitePolicy, ParseOptions},
+    h3tess::{self, Encoding, Format, H3TessReader, H3TessWriter, RECORD_LEN},
+    heatmap::{Extent, Raster},
+    naming::{self, tessellation_path},
+    stats::{Histogram, P2Quantile},
 };
 use hextree::{
     compaction::Compactor,
-    h3ron::{FromH3Index, H3Cell},
+    h3ron::{H3Cell, Index, ToCoordinate},
     HexTreeMap,
 };
+use parquet::arrow::ArrowWriter;
 use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
     fs::File,
-    io::{BufReader, BufWriter, ErrorKind},
-    path::PathBuf,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
+use tracing::{info, info_span, warn};
+use tracing_subscriber::EnvFilter;
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+/// Set by Ctrl-C during `tessellate` and `combine`, which then stop at
+/// the next grid cell or record and remove their unfinished outputs.
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+/// Routes the first Ctrl-C to [`CANCEL`]. A second one exits at once.
+fn cancel_on_ctrl_c() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if CANCEL.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        warn!("cancelling; press Ctrl-C again to exit at once");
+    })?;
+    Ok(())
+}
 
 fn main() -> Result<()> {
+    // Log at info by default; RUST_LOG overrides, e.g.
+    // `RUST_LOG=gpwgen=debug` for per-row progress.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
     let args = Args::parse();
     match args {
-        Args::Tessellate(tess_args) => tessellate(tess_args)?,
-        Args::Combine(combine_args) => combine(combine_args)?,
+        Args::Tessellate(tess_args) => {
+            cancel_on_ctrl_c()?;
+            tessellate(tess_args)?
+        }
+        Args::MergeTiles(merge_args) => merge_tiles(merge_args)?,
+        Args::Combine(combine_args) => {
+            cancel_on_ctrl_c()?;
+            combine(combine_args, &mut std::io::stdout().lock())?
+        }
+        Args::Build(build_args) => build(build_args)?,
+        Args::Verify(verify_args) => verify(verify_args)?,
+        Args::Hist(hist_args) => hist(hist_args, &mut std::io::stdout().lock())?,
+        Args::ImportCsv(import_args) => import_csv(import_args)?,
+        Args::Heatmap(heatmap_args) => heatmap(heatmap_args)?,
     };
     Ok(())
 }
@@ -38,48 +93,990 @@ fn tessellate(
         resolution,
         sources,
         outdir,
+        non_finite_as_nodata,
+        allow_partial,
+        clamp_negative,
+        drop_zero,
+        coarsen,
+        threads,
+        parallel_files,
+        recursive,
+        format: encoding,
+        counts,
+        checksum,
+        distribution,
+        drift_check,
+        deterministic,
+        skip_existing,
+        no_clobber,
+        netcdf_variable,
+        report,
+        dry_run,
     }: Tessellate,
 ) -> Result<()> {
+    if resolution.len() != 1 && resolution.len() != sources.len() {
+        Err(anyhow!(
+            "--resolution lists {} resolutions for {} sources; give one, or one per source",
+            resolution.len(),
+            sources.len()
+        ))?
+    }
+
+    // Pair every source with its (relative) output path and
+    // resolution, descending into directories when asked to. Grids
+    // found under a directory share its resolution.
+    let mut jobs = Vec::new();
+    for (i, src_path) in sources.iter().enumerate() {
+        let res = if resolution.len() == 1 {
+            resolution[0]
+        } else {
+            resolution[i]
+        };
+        if recursive && src_path.is_dir() {
+            let mut found = Vec::new();
+            find_grids(src_path, PathBuf::new(), &mut found)?;
+            jobs.extend(
+                found
+                    .into_iter()
+                    .map(|(src_path, rel_path)| (src_path, rel_path, res)),
+            );
+        } else {
+            let src_filename = src_path
+                .file_name()
+                .ok_or_else(|| anyhow!(format!("Not a file {:?}", src_path)))?;
+            jobs.push((src_path.clone(), PathBuf::from(src_filename), res));
+        }
+    }
+
+    if checksum && encoding != Encoding::H3tess {
+        Err(anyhow!("--checksum only applies to h3tess output"))?
+    }
+    if coarsen == Some(0) {
+        Err(anyhow!("--coarsen must be at least 1"))?
+    }
+    if skip_existing {
+        let before = jobs.len();
+        jobs.retain(|(_, rel_path, res)| {
+            let dst_path = tessellation_path(&outdir, rel_path, *res, encoding);
+            !is_complete(&dst_path, encoding)
+        });
+        info!(
+            "skipping {} of {} sources with existing outputs",
+            before - jobs.len(),
+            before
+        );
+    }
+    if no_clobber {
+        for (_, rel_path, res) in &jobs {
+            let dst_path = tessellation_path(&outdir, rel_path, *res, encoding);
+            if dst_path.exists() {
+                Err(anyhow!(
+                    "{} already exists; remove it or drop --no-clobber",
+                    dst_path.display()
+                ))?
+            }
+        }
+    }
+    let format = Format {
+        counts,
+        checksum,
+        ..Default::default()
+    };
+    if dry_run {
+        // Text rows vary in length; size them for a 15 digit index, a
+        // 10 character value and a 4 digit count.
+        let (bound, record_len) = match (encoding, counts) {
+            (Encoding::H3tess, _) => ("at most", format.record_len()),
+            (Encoding::Csv, false) => ("about", 27),
+            (Encoding::Csv, true) => ("about", 32),
+            (Encoding::Ndjson, false) => ("about", 47),
+            (Encoding::Ndjson, true) => ("about", 60),
+        };
+        let mut total = 0;
+        for (src_path, rel_path, res) in jobs {
+            let mut header = if is_netcdf(&src_path) {
+                read_netcdf_header(&src_path, netcdf_variable.as_deref())
+            } else {
+                GpwAsciiHeader::parse(&mut BufReader::new(open_grid(&src_path)?))
+                    .map(|(header, _)| header)
+                    .map_err(anyhow::Error::from)
+            }
+            .with_context(|| src_path.display().to_string())?;
+            if let Some(factor) = coarsen {
+                header = header
+                    .coarsen(factor)
+                    .with_context(|| src_path.display().to_string())?;
+            }
+            let dst_path = tessellation_path(&outdir, &rel_path, res, encoding);
+            check_writable(&dst_path)?;
+            // Upper bound assuming every cell is populated and covers
+            // as many hexes as the grid's middle cell.
+            let hexes_per_cell =
+                tessalate_grid(&header, header.nrows / 2, header.ncols / 2, res)?.len();
+            let estimate = (header.nrows * header.ncols * hexes_per_cell * record_len) as u64;
+            total += estimate;
+            println!(
+                "{} -> {}: {} x {} cells, {} {} bytes",
+                src_path.display(),
+                dst_path.display(),
+                header.nrows,
+                header.ncols,
+                bound,
+                estimate
+            );
+        }
+        println!("Estimated output: {} {} bytes", bound, total);
+        return Ok(());
+    }
+
     // Open all source and destination files at the same time,
     // otherwise fail fast.
-    let files = sources
-        .iter()
-        .map(|src_path| -> Result<(File, File)> {
-            let src_file = File::open(src_path)?;
-
-            // Create the path to the output file with H3 resolution added and
-            // gpwh3 extension.
-            let dst_path = {
-                let src_filename = src_path
-                    .file_name()
-                    .ok_or_else(|| anyhow!(format!("Not a file {:?}", src_path)))?;
-                let mut dst = PathBuf::new();
-                dst.push(&outdir);
-                dst.push(src_filename);
-                dst.set_extension(format!("res{}.h3tess", resolution));
-                dst
+    let files = jobs
+        .into_iter()
+        .map(|(src_path, rel_path, res)| -> Result<TessellateJob> {
+            let src_file = open_grid(&src_path)?;
+            let dst_path = tessellation_path(&outdir, &rel_path, res, encoding);
+            if let Some(parent) = dst_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let dst_file = File::create(&dst_path)?;
+            Ok((src_path, src_file, dst_path, dst_file, res))
+        })
+        .collect::<Result<Vec<TessellateJob>>>()?;
+
+    let parse_opts = ParseOptions {
+        non_finite: if non_finite_as_nodata {
+            NonFinitePolicy::Nodata
+        } else {
+            NonFinitePolicy::Reject
+        },
+        allow_partial,
+        ..Default::default()
+    };
+
+    // Workers pull files off a shared queue so at most
+    // `parallel_files` grids are in memory at once.
+    let file_count = files.len();
+    let queue = Mutex::new(files.into_iter().enumerate());
+    let worker = || -> Result<Vec<(usize, TessellateSummary)>> {
+        let mut summaries = Vec::new();
+        loop {
+            let next = queue.lock().expect("queue lock poisoned").next();
+            let Some((id, (src_path, src_file, dst_path, dst_file, res))) = next else {
+                return Ok(summaries);
+            };
+            if CANCEL.load(Ordering::Relaxed) {
+                drop(dst_file);
+                std::fs::remove_file(&dst_path)?;
+                continue;
+            }
+            let _span = info_span!("tessellate", file = %src_path.display()).entered();
+            let start = Instant::now();
+            let mut dst = BufWriter::new(dst_file);
+            let mut data = if is_netcdf(&src_path) {
+                read_netcdf(&src_path, netcdf_variable.as_deref())
+            } else {
+                GpwAscii::parse_parallel(&mut BufReader::new(src_file), &parse_opts)
+                    .map_err(anyhow::Error::from)
+            }
+            .with_context(|| src_path.display().to_string())?;
+            let mut clamped = 0;
+            if clamp_negative {
+                clamped = data.clamp_negative();
+                if clamped > 0 {
+                    warn!("clamped {} negative cells to zero", clamped);
+                }
+            }
+            let mut zeros = 0;
+            if drop_zero {
+                zeros = data.drop_zero();
+                info!("dropped {} zero cells", zeros);
+            }
+            if let Some(factor) = coarsen {
+                data = data
+                    .coarsen(factor)
+                    .with_context(|| src_path.display().to_string())?;
+            }
+            let gen_opts = GenOptions {
+                resolution: res,
+                threads,
+                encoding,
+                counts,
+                checksum,
+                distribution,
+                drift_check,
+                deterministic,
             };
-            let dst_file = File::create(dst_path)?;
-            Ok((src_file, dst_file))
+            let stats = gen_to_disk_cancellable(data, &gen_opts, &mut dst, &CANCEL)
+                .map_err(anyhow::Error::from)
+                .with_context(|| src_path.display().to_string());
+            let stats = match stats {
+                Ok(stats) => stats,
+                Err(e) => {
+                    // Like a cancelled one, a failed output mustn't be
+                    // mistaken for a finished one.
+                    drop(dst);
+                    let _ = std::fs::remove_file(&dst_path);
+                    return Err(e);
+                }
+            };
+            if stats.cancelled {
+                // A partial output reads back as complete, which would
+                // fool --skip-existing on a rerun.
+                drop(dst);
+                std::fs::remove_file(&dst_path)?;
+                warn!("cancelled, removed {}", dst_path.display());
+                continue;
+            }
+            info!("total population {:.0}", stats.total_population);
+            if let Some(drift) = stats.drift.filter(|drift| drift.anomalies() > 0) {
+                warn!(
+                    empty = drift.empty,
+                    sparse = drift.sparse,
+                    dense = drift.dense,
+                    "{} of {} populated cells tessellated to an unexpected number of hexes; \
+                     check the header's geotransform",
+                    drift.anomalies(),
+                    drift.checked
+                );
+            }
+            summaries.push((
+                id,
+                TessellateSummary {
+                    source: src_path,
+                    output: dst_path,
+                    resolution: res,
+                    stats,
+                    clamped,
+                    zeros,
+                    elapsed: start.elapsed(),
+                },
+            ));
+        }
+    };
+    let mut summaries = std::thread::scope(|scope| {
+        let workers = (0..parallel_files.max(1))
+            .map(|_| scope.spawn(worker))
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|handle| handle.join().expect("tessellation worker panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    summaries.sort_unstable_by_key(|(id, _)| *id);
+    if CANCEL.load(Ordering::Relaxed) {
+        Err(anyhow!(
+            "cancelled after {} of {} sources; rerun with --skip-existing to finish",
+            summaries.len(),
+            file_count
+        ))?
+    }
+
+    if clamp_negative {
+        let clamped = summaries
+            .iter()
+            .map(|(_, summary)| summary.clamped)
+            .sum::<usize>();
+        info!("clamped {} negative cells to zero in total", clamped);
+    }
+    if drop_zero {
+        let zeros = summaries
+            .iter()
+            .map(|(_, summary)| summary.zeros)
+            .sum::<usize>();
+        info!("dropped {} zero cells in total", zeros);
+    }
+    if let Some(report) = report {
+        let sources = summaries
+            .iter()
+            .map(|(_, summary)| summary.to_json())
+            .collect::<Vec<_>>();
+        write_report(&report, &serde_json::json!({ "sources": sources }))?;
+    }
+
+    Ok(())
+}
+
+/// A source grid, the path of its tessellation and that file, created
+/// up front, and the resolution to tessellate it at.
+type TessellateJob = (PathBuf, Box<dyn Read + Send>, PathBuf, File, u8);
+
+/// What tessellating one source produced, for `--report`.
+struct TessellateSummary {
+    source: PathBuf,
+    output: PathBuf,
+    resolution: u8,
+    stats: GenStats,
+    clamped: usize,
+    /// Cells dropped by `--drop-zero`.
+    zeros: usize,
+    elapsed: Duration,
+}
+
+impl TessellateSummary {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "source": self.source,
+            "output": self.output,
+            "resolution": self.resolution,
+            "input_cells": self.stats.input_cells,
+            "pairs": self.stats.pairs,
+            "dropped_cells": self.stats.dropped,
+            "clamped_cells": self.clamped,
+            "zero_cells": self.zeros,
+            "total_population": self.stats.total_population,
+            "seconds": self.elapsed.as_secs_f64(),
         })
-        .collect::<Result<Vec<(File, File)>>>()?;
+    }
+}
+
+/// Writes a `--report` summary as pretty-printed JSON.
+fn write_report(path: &Path, report: &serde_json::Value) -> Result<()> {
+    let mut out = BufWriter::new(create_output(path)?);
+    serde_json::to_writer_pretty(&mut out, report)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
 
-    for (src_file, dst_file) in files {
-        let mut rdr = BufReader::new(src_file);
-        let mut dst = BufWriter::new(dst_file);
-        let data = GpwAscii::parse(&mut rdr).unwrap();
-        gen_to_disk(data, &mut dst)
+/// Recursively collects GPW grids under `dir`, along with their paths
+/// relative to the directory tessellation started from.
+fn find_grids(dir: &Path, rel_dir: PathBuf, found: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let rel_path = rel_dir.join(entry.file_name());
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        if path.is_dir() {
+            find_grids(&path, rel_path, found)?;
+        } else if name.ends_with(".asc") || name.ends_with(".asc.gz") || name.ends_with(".nc") {
+            found.push((path, rel_path));
+        } else if name.ends_with(".tif") || name.ends_with(".tiff") {
+            warn!(
+                "skipping {}: GeoTIFF input is not supported",
+                path.display()
+            );
+        } else {
+            warn!("skipping {}: not a GPW grid", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Whether a previous run left a complete tessellation at `path`: a
+/// non-empty file that, for h3tess output, reads back without errors,
+/// checksum included if it has one.
+fn is_complete(path: &Path, encoding: Encoding) -> bool {
+    match File::open(path) {
+        Ok(file) if file.metadata().is_ok_and(|meta| meta.len() > 0) => {
+            encoding != Encoding::H3tess || h3tess::verify(BufReader::new(file), false).is_ok()
+        }
+        _ => false,
     }
+}
+
+/// Whether an output path is the `-` sentinel for stdout.
+fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Creates the output file at `path`, or locks stdout for `-`. Stdout
+/// is line buffered, so callers should wrap either in a `BufWriter` to
+/// keep binary output in large writes.
+fn create_output(path: &Path) -> Result<Box<dyn Write>> {
+    if is_stdout(path) {
+        Ok(Box::new(std::io::stdout().lock()))
+    } else {
+        Ok(Box::new(File::create(path)?))
+    }
+}
 
+/// Fails unless `path`, or the closest existing directory it would be
+/// created under, is writable. Creates nothing.
+fn check_writable(path: &Path) -> Result<()> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    if existing.metadata()?.permissions().readonly() {
+        Err(anyhow!("{} is not writable", existing.display()))?
+    }
     Ok(())
 }
 
+/// Whether `path` names a NetCDF grid rather than a GPW ASCII one.
+fn is_netcdf(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("nc"))
+}
+
+/// Reads the `--netcdf-variable` variable of a NetCDF source as a
+/// grid.
+#[cfg(feature = "netcdf")]
+fn read_netcdf(path: &Path, variable: Option<&str>) -> Result<GpwAscii> {
+    let variable = variable.ok_or_else(|| anyhow!("NetCDF sources need --netcdf-variable"))?;
+    Ok(gpwgen::gpwnetcdf::read(path, variable)?)
+}
+
+#[cfg(not(feature = "netcdf"))]
+fn read_netcdf(_path: &Path, _variable: Option<&str>) -> Result<GpwAscii> {
+    Err(anyhow!(
+        "NetCDF sources need gpwgen built with the netcdf feature"
+    ))
+}
+
+/// Like [`read_netcdf`], reading only the grid's header.
+#[cfg(feature = "netcdf")]
+fn read_netcdf_header(path: &Path, variable: Option<&str>) -> Result<GpwAsciiHeader> {
+    let variable = variable.ok_or_else(|| anyhow!("NetCDF sources need --netcdf-variable"))?;
+    Ok(gpwgen::gpwnetcdf::read_header(path, variable)?)
+}
+
+#[cfg(not(feature = "netcdf"))]
+fn read_netcdf_header(_path: &Path, _variable: Option<&str>) -> Result<GpwAsciiHeader> {
+    Err(anyhow!(
+        "NetCDF sources need gpwgen built with the netcdf feature"
+    ))
+}
+
+/// Opens a GPW ASCII grid, transparently decompressing `.gz` files.
+fn open_grid(path: &Path) -> Result<Box<dyn Read + Send>> {
+    let file = File::open(path)?;
+    if path.extension() == Some(OsStr::new("gz")) {
+        Ok(Box::new(MultiGzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
 fn combine(
     Combine {
         resolution,
+        resolutions,
+        tolerate_truncation,
+        skip_checksum,
+        counts,
+        quantize,
+        checksum,
+        delta,
+        compact_partial,
+        provenance,
+        report,
+        format: map_format,
+        dry_run,
         sources,
         output,
     }: Combine,
+    stdout: &mut impl Write,
+) -> Result<()> {
+    // Without --resolutions there's a single map whose name can carry
+    // its resolution; with it, each map's name gets its own below.
+    let output = match output {
+        Some(output) => output,
+        None if resolutions.is_empty() => {
+            naming::with_resolution(&naming::map_path(map_format.extension()), resolution)
+        }
+        None => naming::map_path(map_format.extension()),
+    };
+    if map_format != MapFormat::H3tess {
+        if quantize.is_some() || checksum || delta {
+            Err(anyhow!(
+                "--quantize, --checksum and --delta only apply to h3tess output"
+            ))?
+        }
+        if is_stdout(&output) {
+            Err(anyhow!("only h3tess output can use stdout"))?
+        }
+    }
+    if delta && compact_partial.is_some() {
+        Err(anyhow!(
+            "--delta needs cells in index order, which --compact-partial's mixed resolutions aren't"
+        ))?
+    }
+    let finest = resolutions.iter().copied().max().unwrap_or(resolution);
+    if dry_run {
+        return combine_dry_run(
+            &sources,
+            &output,
+            &resolutions,
+            finest,
+            provenance.as_deref(),
+        );
+    }
+
+    // Open all source files at the same time, otherwise fail fast.
+    let sources = sources
+        .iter()
+        .map(|path| Ok((path, File::open(path)?)))
+        .collect::<std::io::Result<Vec<(&PathBuf, File)>>>()?;
+
+    // Check every source looks intact before inserting anything, so a
+    // bad file late in the list doesn't abort after minutes of work.
+    let mut invalid = Vec::new();
+    for (path, source) in &sources {
+        let mut source = source;
+        let rdr = H3TessReader::new(BufReader::new(source))
+            .tolerate_truncation(tolerate_truncation)
+            .skip_checksum(skip_checksum);
+        let checked = h3tess::preflight(rdr, source.metadata()?.len())
+            .map_err(anyhow::Error::from)
+            .and_then(|_| {
+                source.rewind()?;
+                check_source_resolution(source, finest)
+            });
+        if let Err(e) = checked {
+            invalid.push(format!("{}: {:?}", path.display(), e));
+        }
+        source.rewind()?;
+    }
+    if !invalid.is_empty() {
+        Err(anyhow!(
+            "{} of {} sources are invalid:\n  {}",
+            invalid.len(),
+            sources.len(),
+            invalid.join("\n  ")
+        ))?
+    }
+
+    // Likewise create every output file before doing any work.
+    let outputs = if resolutions.is_empty() {
+        let created = if is_stdout(&output) {
+            MapOutput::H3tess(Box::new(stdout))
+        } else {
+            MapOutput::create(&output, map_format)?
+        };
+        vec![(resolution, output, created)]
+    } else if is_stdout(&output) {
+        Err(anyhow!(
+            "--resolutions writes several maps and can't use stdout"
+        ))?
+    } else {
+        resolutions
+            .iter()
+            .map(|&res| -> Result<(u8, PathBuf, MapOutput)> {
+                let path = naming::with_resolution(&output, res);
+                let created = MapOutput::create(&path, map_format)?;
+                Ok((res, path, created))
+            })
+            .collect::<Result<Vec<(u8, PathBuf, MapOutput)>>>()?
+    };
+    let mut map: HexTreeMap<(f32, u32), _> = HexTreeMap::with_compactor(ReduceCompactor {
+        resolution: finest,
+        mode: ReduceMode::Sum,
+    });
+
+    let mut provenance = provenance
+        .map(|path| -> Result<_> { Ok((File::create(path)?, Provenance::new(finest))) })
+        .transpose()?;
+
+    // Later sources are read in the background while earlier ones are
+    // inserted, but insertion still follows the source order.
+    let readers = sources.iter().map(|(_, source)| {
+        H3TessReader::new(BufReader::new(source))
+            .tolerate_truncation(tolerate_truncation)
+            .skip_checksum(skip_checksum)
+    });
+    let mut source_reports = Vec::new();
+    let inserted = read_ahead(readers, READ_AHEAD_FILES, |id, records| -> Result<()> {
+        let path = sources[id].0;
+        let _span = info_span!("combine", file = %path.display()).entered();
+        let start = Instant::now();
+        let (mut count, mut population) = (0_u64, 0.0);
+        for record in records.by_ref() {
+            if CANCEL.load(Ordering::Relaxed) {
+                Err(anyhow!("cancelled while inserting {}", path.display()))?
+            }
+            let (cell, val, cells) = record.with_context(|| path.display().to_string())?;
+            if let Some((_, provenance)) = provenance.as_mut() {
+                provenance.record(cell, id as u32)?;
+            }
+            map.insert(cell, (val, cells));
+            count += 1;
+            population += f64::from(val);
+        }
+        if let Some(partial) = records.truncated() {
+            warn!(
+                "dropped {} trailing bytes of a partial record at offset {}",
+                partial,
+                records.offset() - partial as u64
+            );
+        }
+        info!("inserted {} bytes", records.offset());
+        source_reports.push(serde_json::json!({
+            "source": path,
+            "records": count,
+            "dropped_bytes": records.truncated().unwrap_or(0),
+            "total_population": population,
+            "seconds": start.elapsed().as_secs_f64(),
+        }));
+        Ok(())
+    });
+    if let Err(e) = inserted {
+        if CANCEL.load(Ordering::Relaxed) {
+            for (_, path, output) in outputs {
+                drop(output);
+                if !is_stdout(&path) {
+                    std::fs::remove_file(&path)?;
+                }
+            }
+        }
+        return Err(e);
+    }
+
+    let format = Format {
+        counts,
+        quantize,
+        checksum,
+        delta,
+    };
+    let mut output_reports = Vec::new();
+    for (res, path, output) in outputs {
+        let pairs = match compact_partial {
+            Some(min_cells) => {
+                let partial =
+                    gpwgen::combine::compact_partial(&map, res, ReduceMode::Sum, min_cells)?;
+                output.write(&partial, format)?;
+                partial.len()
+            }
+            None if res == finest => {
+                output.write(&map, format)?;
+                map.len()
+            }
+            None => {
+                let coarse = coarsen(&map, res, ReduceMode::Sum);
+                output.write(&coarse, format)?;
+                coarse.len()
+            }
+        };
+        output_reports.push(serde_json::json!({
+            "output": path,
+            "resolution": res,
+            "pairs": pairs,
+        }));
+    }
+
+    if let Some((provenance_file, provenance)) = provenance {
+        let paths = sources.iter().map(|(path, _)| *path).collect::<Vec<_>>();
+        provenance.write(&paths, &mut BufWriter::new(provenance_file))?;
+    }
+    if let Some(report) = report {
+        write_report(
+            &report,
+            &serde_json::json!({ "sources": source_reports, "outputs": output_reports }),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Sources `combine` reads ahead of the one it's inserting.
+const READ_AHEAD_FILES: usize = 2;
+
+/// Checks that a combine source was tessellated at `finest` or a finer
+/// resolution, judging by its first record. Coarser cells have no
+/// children for the compactor to aggregate, so they'd pass through
+/// into the map at their own resolution.
+fn check_source_resolution(source: impl Read, finest: u8) -> Result<()> {
+    let first = H3TessReader::new(BufReader::new(source))
+        .skip_checksum(true)
+        .next()
+        .transpose()?;
+    match first.map(|(cell, _)| cell.resolution()) {
+        Some(res) if res < finest => Err(anyhow!(
+            "tessellated at res {}, coarser than the combine resolution {}; \
+             tessellate at res {} or finer, or combine at res {} or coarser",
+            res,
+            finest,
+            finest,
+            res
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that every combine source is a readable h3tess file at a
+/// fine enough resolution for `finest` and every output is writable,
+/// and prints the plan.
+fn combine_dry_run(
+    sources: &[PathBuf],
+    output: &Path,
+    resolutions: &[u8],
+    finest: u8,
+    provenance: Option<&Path>,
+) -> Result<()> {
+    let mut total = 0;
+    for path in sources {
+        let mut file = File::open(path).with_context(|| path.display().to_string())?;
+        let len = file.metadata()?.len();
+        let format = h3tess::preflight(H3TessReader::new(BufReader::new(&file)), len)
+            .map_err(anyhow::Error::from)
+            .and_then(|format| {
+                file.rewind()?;
+                check_source_resolution(&file, finest)?;
+                Ok(format)
+            })
+            .with_context(|| path.display().to_string())?;
+        println!(
+            "{}: {} bytes, {} records",
+            path.display(),
+            len,
+            if format.counts { "counted" } else { "plain" },
+        );
+        total += len;
+    }
+    let mut outputs = if resolutions.is_empty() {
+        vec![output.to_path_buf()]
+    } else {
+        resolutions
+            .iter()
+            .map(|&res| naming::with_resolution(output, res))
+            .collect()
+    };
+    outputs.extend(provenance.map(Path::to_path_buf));
+    if is_stdout(output) && !resolutions.is_empty() {
+        Err(anyhow!(
+            "--resolutions writes several maps and can't use stdout"
+        ))?
+    }
+    for path in &outputs {
+        if is_stdout(path) {
+            println!("-> stdout");
+            continue;
+        }
+        check_writable(path)?;
+        println!("-> {}", path.display());
+    }
+    // Combining only merges records, so each map is at most as large
+    // as all of its sources together.
+    println!("Estimated output: at most {} bytes per map", total);
+    Ok(())
+}
+
+fn merge_tiles(MergeTiles { sources, output }: MergeTiles) -> Result<()> {
+    let tiles = sources
+        .iter()
+        .map(|path| -> Result<GpwAscii> {
+            GpwAscii::parse_parallel(
+                &mut BufReader::new(open_grid(path)?),
+                &ParseOptions::default(),
+            )
+            .with_context(|| path.display().to_string())
+        })
+        .collect::<Result<Vec<GpwAscii>>>()?;
+    let (merged, overlaps) = GpwAscii::merge_tiles(&tiles)?;
+    info!(
+        "merged {} tiles into {} x {} cells, counting {} overlapping cells once",
+        tiles.len(),
+        merged.header.ncols,
+        merged.header.nrows,
+        overlaps
+    );
+    let mut wtr = BufWriter::new(create_output(&output)?);
+    merged.write(&mut wtr)?;
+    wtr.flush()?;
+    Ok(())
+}
+
+fn verify(Verify { sorted, file }: Verify) -> Result<()> {
+    let rdr = BufReader::new(File::open(&file)?);
+    let count = h3tess::verify(rdr, sorted).with_context(|| file.display().to_string())?;
+    println!("{}: ok, {} records", file.display(), count);
+    Ok(())
+}
+
+fn import_csv(
+    ImportCsv {
+        checksum,
+        file,
+        output,
+    }: ImportCsv,
+) -> Result<()> {
+    let rdr = BufReader::new(File::open(&file)?);
+    let format = Format {
+        checksum,
+        ..Default::default()
+    };
+    let mut wtr = H3TessWriter::with_format(BufWriter::new(File::create(&output)?), format)?;
+    let mut count = 0;
+    for (i, line) in rdr.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line.starts_with("h3_index")) {
+            continue;
+        }
+        let (cell, val) = parse_csv_row(line)
+            .map_err(|e| e.at_line(i + 1))
+            .with_context(|| file.display().to_string())?;
+        wtr.write_pair(cell, val)?;
+        count += 1;
+    }
+    wtr.finish()?;
+    info!("imported {} records", count);
+    Ok(())
+}
+
+/// Parses an `h3_index,value` row, checking the index is a valid H3
+/// cell. Valid indices have at most 16 hex digits but at least 18
+/// decimal ones, so longer all-digit indices are read as decimal.
+fn parse_csv_row(line: &str) -> Result<(H3Cell, f32), GpwError> {
+    let (index, val) = line
+        .split_once(',')
+        .ok_or(("row", "expected h3_index,value"))?;
+    let index = index.trim();
+    let index = match index.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None if index.len() > 16 => index.parse(),
+        None => u64::from_str_radix(index, 16),
+    }
+    .map_err(|e| ("h3_index", format!("{:?}: {}", index, e)))?;
+    let cell = H3Cell::try_from(index)?;
+    let val = val
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|val| val.is_finite())
+        .ok_or_else(|| ("value", format!("{:?} is not a finite number", val.trim())))?;
+    Ok((cell, val))
+}
+
+/// Percentiles reported by [`hist`].
+const PERCENTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+fn heatmap(
+    Heatmap {
+        width,
+        bbox,
+        colormap,
+        skip_checksum,
+        file,
+        output,
+    }: Heatmap,
+) -> Result<()> {
+    if width == 0 {
+        Err(anyhow!("--width must be at least 1"))?
+    }
+    let mut raster = Raster::new(bbox.unwrap_or(Extent::WORLD), width);
+    let rdr = H3TessReader::new(BufReader::new(File::open(&file)?)).skip_checksum(skip_checksum);
+    for record in rdr {
+        let (cell, val) = record.with_context(|| file.display().to_string())?;
+        let center = cell.to_coordinate().map_err(GpwError::from)?;
+        raster.push(center.y, center.x, f64::from(val));
+    }
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(&output)?),
+        raster.width(),
+        raster.height(),
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()?
+        .write_image_data(&raster.rgba(colormap))?;
+    info!(
+        "wrote {}x{} heatmap to {}",
+        raster.width(),
+        raster.height(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn hist(
+    Hist {
+        buckets,
+        format,
+        skip_checksum,
+        file,
+    }: Hist,
+    out: &mut impl Write,
+) -> Result<()> {
+    let values = || -> Result<_> {
+        let rdr =
+            H3TessReader::new(BufReader::new(File::open(&file)?)).skip_checksum(skip_checksum);
+        Ok(rdr.map(|record| {
+            record
+                .map(|(_, val)| f64::from(val))
+                .with_context(|| file.display().to_string())
+        }))
+    };
+
+    // The first pass finds the range and percentiles, the second
+    // fills buckets across that range.
+    let (mut count, mut min, mut max) = (0_u64, f64::INFINITY, f64::NEG_INFINITY);
+    let mut quantiles = PERCENTILES.map(P2Quantile::new);
+    for val in values()? {
+        let val = val?;
+        count += 1;
+        min = min.min(val);
+        max = max.max(val);
+        for q in &mut quantiles {
+            q.push(val);
+        }
+    }
+    if count == 0 {
+        Err(anyhow!("{}: no records", file.display()))?
+    }
+    let mut histogram = Histogram::new(min, max, buckets);
+    for val in values()? {
+        histogram.push(val?);
+    }
+
+    let percentiles = PERCENTILES
+        .iter()
+        .zip(&quantiles)
+        .map(|(p, q)| (format!("p{}", p * 100.0), q.estimate().unwrap_or(f64::NAN)))
+        .collect::<Vec<_>>();
+    match format {
+        HistFormat::Csv => {
+            writeln!(out, "stat,value")?;
+            writeln!(out, "count,{}", count)?;
+            writeln!(out, "min,{}", min)?;
+            writeln!(out, "max,{}", max)?;
+            for (name, val) in &percentiles {
+                writeln!(out, "{},{}", name, val)?;
+            }
+            writeln!(out)?;
+            writeln!(out, "lower,upper,count")?;
+            for (lower, upper, n) in histogram.buckets() {
+                writeln!(out, "{},{},{}", lower, upper, n)?;
+            }
+        }
+        HistFormat::Json => {
+            let json = serde_json::json!({
+                "count": count,
+                "min": min,
+                "max": max,
+                "percentiles": percentiles
+                    .into_iter()
+                    .map(|(name, val)| (name, val.into()))
+                    .collect::<serde_json::Map<_, _>>(),
+                "buckets": histogram
+                    .buckets()
+                    .map(|(lower, upper, n)| {
+                        serde_json::json!({"lower": lower, "upper": upper, "count": n})
+                    })
+                    .collect::<Vec<_>>(),
+            });
+            writeln!(out, "{}", json)?;
+        }
+    }
+    Ok(())
+}
+
+fn build(
+    Build {
+        intermediate_resolution,
+        resolution,
+        counts,
+        quantize,
+        checksum,
+        delta,
+        sources,
+        output,
+    }: Build,
 ) -> Result<()> {
     // Open all source files at the same time, otherwise fail fast.
     let sources = sources
@@ -88,48 +1085,1326 @@ fn combine(
         .collect::<std::io::Result<Vec<File>>>()?;
     let output_file = File::create(output)?;
 
-    let mut map: HexTreeMap<f32, _> = HexTreeMap::with_compactor(SummationCompactor { resolution });
+    let mut map: HexTreeMap<(f32, u32), _> = HexTreeMap::with_compactor(ReduceCompactor {
+        resolution,
+        mode: ReduceMode::Sum,
+    });
+    let gen_opts = GenOptions {
+        resolution: intermediate_resolution,
+        ..Default::default()
+    };
 
     for source in sources {
         let mut rdr = BufReader::new(source);
-        loop {
-            match (rdr.read_u64::<LE>(), rdr.read_f32::<LE>()) {
-                (Ok(h3_index), Ok(val)) => {
-                    let cell = H3Cell::from_h3index(h3_index);
-                    map.insert(cell, val)
-                }
-                (Err(e), _) if e.kind() == ErrorKind::UnexpectedEof => break,
-                (err @ Err(_), _) => {
-                    err?;
-                }
-                (_, err @ Err(_)) => {
-                    err?;
-                }
-            };
+        let data = GpwAscii::parse(&mut rdr)?;
+        gen_to_disk(data, &gen_opts, &mut MapSink::new(&mut map))?;
+    }
+
+    write_map(
+        &map,
+        Format {
+            counts,
+            quantize,
+            checksum,
+            delta,
+        },
+        &mut BufWriter::new(output_file),
+    )
+}
+
+/// Number of records [`write_map`] writes between flushes.
+const FLUSH_RECORDS: usize = 4096;
+
+/// Writes every (cell, (value, count)) pair in `map` as h3tess
+/// records.
+fn write_map<C>(
+    map: &HexTreeMap<(f32, u32), C>,
+    format: Format,
+    wtr: &mut impl Write,
+) -> Result<()> {
+    // Plain maps stay headerless for older readers, and their size
+    // already gives an exact record count.
+    let wtr = if format == Format::default() {
+        H3TessWriter::with_format(wtr, format)
+    } else {
+        H3TessWriter::with_record_count(wtr, format, map.len() as u64)
+    };
+    let mut wtr = wtr?;
+    for (i, (cell, (val, count))) in map.iter().enumerate() {
+        wtr.write_record(*cell, *val, *count)?;
+        // Flush periodically so a crash late in a long write leaves a
+        // valid prefix of the map behind. Map iteration order is
+        // deterministic, so a rerun can pick up after the last record.
+        if (i + 1) % FLUSH_RECORDS == 0 {
+            wtr.flush()?;
         }
     }
+    wtr.finish()?;
+    Ok(())
+}
 
-    let mut wtr = BufWriter::new(output_file);
-    for (cell, val) in map.iter() {
-        wtr.write_u64::<LE>(**cell)?;
-        wtr.write_f32::<LE>(*val)?;
+/// Writes every (cell, (value, count)) pair in `map` to a new `cells`
+/// table in one transaction, indexing it on population once filled.
+fn write_sqlite<C>(
+    map: &HexTreeMap<(f32, u32), C>,
+    counts: bool,
+    conn: &mut rusqlite::Connection,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    if counts {
+        tx.execute_batch(
+            "CREATE TABLE cells (h3_index INTEGER PRIMARY KEY, population REAL, count INTEGER)",
+        )?;
+    } else {
+        tx.execute_batch("CREATE TABLE cells (h3_index INTEGER PRIMARY KEY, population REAL)")?;
     }
+    {
+        let mut insert = if counts {
+            tx.prepare("INSERT INTO cells VALUES (?1, ?2, ?3)")?
+        } else {
+            tx.prepare("INSERT INTO cells VALUES (?1, ?2)")?
+        };
+        for (cell, (val, count)) in map.iter() {
+            // H3 indices leave the top bit clear, so they fit SQLite's
+            // signed integers unchanged.
+            let h3_index = cell.h3index() as i64;
+            if counts {
+                insert.execute(rusqlite::params![h3_index, f64::from(*val), count])?;
+            } else {
+                insert.execute(rusqlite::params![h3_index, f64::from(*val)])?;
+            }
+        }
+    }
+    tx.execute_batch("CREATE INDEX cells_population ON cells (population)")?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Number of rows in each Parquet row group [`write_parquet`] writes.
+const PARQUET_ROW_GROUP: usize = 65_536;
 
+/// Writes every (cell, (value, count)) pair in `map` as Parquet rows,
+/// closing a row group every [`PARQUET_ROW_GROUP`] rows so only one is
+/// held in memory.
+fn write_parquet<C>(
+    map: &HexTreeMap<(f32, u32), C>,
+    counts: bool,
+    wtr: impl Write + Send,
+) -> Result<()> {
+    let mut fields = vec![
+        Field::new("h3_index", DataType::UInt64, false),
+        Field::new("population", DataType::Float32, false),
+    ];
+    if counts {
+        fields.push(Field::new("count", DataType::UInt32, false));
+    }
+    let schema = Arc::new(Schema::new(fields));
+    let mut wtr = ArrowWriter::try_new(wtr, schema.clone(), None)?;
+    let mut write_row_group = |cells: Vec<u64>, vals: Vec<f32>, cell_counts: Vec<u32>| {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(cells)),
+            Arc::new(Float32Array::from(vals)),
+        ];
+        if counts {
+            columns.push(Arc::new(UInt32Array::from(cell_counts)));
+        }
+        wtr.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+        wtr.flush()?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let (mut cells, mut vals, mut cell_counts) = (Vec::new(), Vec::new(), Vec::new());
+    for (cell, (val, count)) in map.iter() {
+        cells.push(cell.h3index());
+        vals.push(*val);
+        cell_counts.push(*count);
+        if cells.len() == PARQUET_ROW_GROUP {
+            write_row_group(
+                std::mem::take(&mut cells),
+                std::mem::take(&mut vals),
+                std::mem::take(&mut cell_counts),
+            )?;
+        }
+    }
+    if !cells.is_empty() {
+        write_row_group(cells, vals, cell_counts)?;
+    }
+    wtr.close()?;
     Ok(())
 }
 
-struct SummationCompactor {
+/// A `combine` output, opened before any work is done.
+enum MapOutput<'a> {
+    H3tess(Box<dyn Write + 'a>),
+    Sqlite(rusqlite::Connection),
+    Parquet(File),
+}
+
+impl MapOutput<'_> {
+    /// Creates the output at `path`, replacing any existing file.
+    fn create(path: &Path, format: MapFormat) -> Result<Self> {
+        match format {
+            MapFormat::H3tess => Ok(MapOutput::H3tess(create_output(path)?)),
+            MapFormat::Sqlite => {
+                // An empty file is an empty database.
+                File::create(path)?;
+                Ok(MapOutput::Sqlite(rusqlite::Connection::open(path)?))
+            }
+            MapFormat::Parquet => Ok(MapOutput::Parquet(File::create(path)?)),
+        }
+    }
+
+    fn write<C>(self, map: &HexTreeMap<(f32, u32), C>, format: Format) -> Result<()> {
+        match self {
+            MapOutput::H3tess(wtr) => write_map(map, format, &mut BufWriter::new(wtr)),
+            MapOutput::Sqlite(mut conn) => write_sqlite(map, format.counts, &mut conn),
+            MapOutput::Parquet(file) => write_parquet(map, format.counts, BufWriter::new(file)),
+        }
+    }
+}
+
+/// A `Write` sink that decodes h3tess records straight into a map,
+/// letting `build` skip intermediate files.
+struct MapSink<'a, C> {
+    map: &'a mut HexTreeMap<(f32, u32), C>,
+    partial: Vec<u8>,
+}
+
+impl<'a, C> MapSink<'a, C> {
+    fn new(map: &'a mut HexTreeMap<(f32, u32), C>) -> Self {
+        Self {
+            map,
+            partial: Vec::new(),
+        }
+    }
+}
+
+impl<C: Compactor<(f32, u32)>> Write for MapSink<'_, C> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        let whole = self.partial.len() - self.partial.len() % RECORD_LEN;
+        for record in H3TessReader::new(&self.partial[..whole]).counted() {
+            let (cell, val, count) = record?;
+            self.map.insert(cell, (val, count));
+        }
+        self.partial.drain(..whole);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Tracks which combine sources contributed to each output cell, to
+/// help track down double counting across overlapping tiles.
+struct Provenance {
     resolution: u8,
+    cells: BTreeMap<u64, Vec<u32>>,
 }
 
-impl Compactor<f32> for SummationCompactor {
-    fn compact(&mut self, res: u8, children: [Option<&f32>; 7]) -> Option<f32> {
-        if res < self.resolution {
-            return None;
+impl Provenance {
+    fn new(resolution: u8) -> Self {
+        Self {
+            resolution,
+            cells: BTreeMap::new(),
+        }
+    }
+
+    /// Records that source `id` contributed to the output cell
+    /// containing `cell`.
+    fn record(&mut self, cell: H3Cell, id: u32) -> Result<(), GpwError> {
+        let cell = if cell.resolution() > self.resolution {
+            cell.get_parent(self.resolution)?
+        } else {
+            cell
+        };
+        let ids = self.cells.entry(cell.h3index()).or_default();
+        // Sources are inserted one after another, so a repeat can only
+        // be the most recent id.
+        if ids.last() != Some(&id) {
+            ids.push(id);
+        }
+        Ok(())
+    }
+
+    /// Writes a `# <id> <path>` line per source followed by a
+    /// `<cell>\t<id>,<id>,...` line per cell, with cells in hex.
+    fn write(&self, sources: &[&PathBuf], wtr: &mut impl Write) -> Result<()> {
+        for (id, path) in sources.iter().enumerate() {
+            writeln!(wtr, "# {} {}", id, path.display())?;
+        }
+        for (cell, ids) in &self.cells {
+            let ids = ids.iter().map(u32::to_string).collect::<Vec<_>>();
+            writeln!(wtr, "{:x}\t{}", cell, ids.join(","))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::coord;
+    use gpwgen::{combine::insert_h3tess, generate::Distribution};
+    use proptest::{collection::vec, option, prelude::*};
+
+    fn grid() -> impl Strategy<Value = Vec<Vec<Option<f32>>>> {
+        (1_usize..5, 1_usize..5)
+            .prop_flat_map(|(nrows, ncols)| vec(vec(option::of(0.0_f32..10_000.0), ncols), nrows))
+    }
+
+    /// A 4 x 4 grid with a single populated cell, shared by the
+    /// library and binary tests.
+    const FIXTURE: &str = include_str!("../testdata/fixture.asc");
+
+    /// `tessellate` arguments with every flag at its default.
+    fn tessellate_args(sources: Vec<PathBuf>, outdir: PathBuf) -> Tessellate {
+        let mut args = vec![OsStr::new("tessellate"), OsStr::new("--outdir")];
+        args.extend(
+            std::iter::once(&outdir)
+                .chain(&sources)
+                .map(|p| p.as_os_str()),
+        );
+        Tessellate {
+            // Regardless of GPWGEN_NO_CLOBBER in the environment.
+            no_clobber: false,
+            ..Tessellate::parse_from(args)
+        }
+    }
+
+    /// `combine` arguments with every flag at its default.
+    fn combine_args(sources: Vec<PathBuf>, output: PathBuf) -> Combine {
+        let mut args = vec![OsStr::new("combine"), OsStr::new("--output")];
+        args.extend(
+            std::iter::once(&output)
+                .chain(&sources)
+                .map(|p| p.as_os_str()),
+        );
+        Combine::parse_from(args)
+    }
+
+    /// Tessellates [`FIXTURE`] with `opts` into `fixture.res10.h3tess`
+    /// under `dir`, returning its path.
+    fn fixture_h3tess(dir: &Path, opts: &GenOptions) -> PathBuf {
+        let path = dir.join("fixture.res10.h3tess");
+        let data = GpwAscii::parse(&mut BufReader::new(FIXTURE.as_bytes())).unwrap();
+        gen_to_disk(
+            data,
+            opts,
+            &mut BufWriter::new(File::create(&path).unwrap()),
+        )
+        .unwrap();
+        path
+    }
+
+    /// Decodes a serialized map into (cell, value) pairs.
+    fn read_pairs(path: &std::path::Path) -> Vec<(u64, f32)> {
+        H3TessReader::new(BufReader::new(File::open(path).unwrap()))
+            .map(|record| {
+                let (cell, val) = record.unwrap();
+                (*cell, val)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("fixture.asc");
+        std::fs::write(&src, FIXTURE).unwrap();
+        let output = dir.path().join("map.h3idx");
+
+        build(Build {
+            intermediate_resolution: 10,
+            resolution: 8,
+            counts: false,
+            quantize: None,
+            checksum: false,
+            delta: false,
+            sources: vec![src],
+            output: output.clone(),
+        })
+        .unwrap();
+
+        let pairs = read_pairs(&output);
+        assert!(!pairs.is_empty());
+        let total: f32 = pairs.iter().map(|(_, val)| val).sum();
+        assert!((total - 0.123).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_combine_multiple_resolutions() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = fixture_h3tess(dir.path(), &GenOptions::default());
+
+        combine(
+            Combine {
+                resolutions: vec![7, 9],
+                ..combine_args(vec![h3tess], dir.path().join("map.h3idx"))
+            },
+            &mut std::io::sink(),
+        )
+        .unwrap();
+
+        let res7 = read_pairs(&dir.path().join("map.res7.h3idx"));
+        let res9 = read_pairs(&dir.path().join("map.res9.h3idx"));
+        assert!(!dir.path().join("map.h3idx").exists());
+        assert!(res7.len() <= res9.len());
+        for pairs in [res7, res9] {
+            let total: f32 = pairs.iter().map(|(_, val)| val).sum();
+            assert!((total - 0.123).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_combine_delta() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = dir.path().join("dense.res10.h3tess");
+        let parent = H3Cell::from_coordinate(coord! {x: 8.5, y: 47.4}, 6).unwrap();
+        let mut wtr = H3TessWriter::new(BufWriter::new(File::create(&h3tess).unwrap()));
+        for cell in parent.get_children(10).unwrap().iter() {
+            wtr.write_pair(cell, 1.0).unwrap();
+        }
+        wtr.finish().unwrap();
+
+        let args = |delta, compact_partial, output| Combine {
+            resolution: 9,
+            checksum: true,
+            delta,
+            compact_partial,
+            ..combine_args(vec![h3tess.clone()], output)
+        };
+        let plain = dir.path().join("plain.h3idx");
+        let delta = dir.path().join("delta.h3idx");
+        combine(args(false, None, plain.clone()), &mut std::io::sink()).unwrap();
+        combine(args(true, None, delta.clone()), &mut std::io::sink()).unwrap();
+        assert_eq!(read_pairs(&delta), read_pairs(&plain));
+        assert!(
+            std::fs::metadata(&delta).unwrap().len() < std::fs::metadata(&plain).unwrap().len()
+        );
+        assert!(combine(args(true, Some(1), delta), &mut std::io::sink()).is_err());
+    }
+
+    #[test]
+    fn test_combine_coarser_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = dir.path().join("coarse.res6.h3tess");
+        let cell = H3Cell::from_coordinate(coord! {x: 8.5, y: 47.4}, 6).unwrap();
+        let mut wtr = H3TessWriter::new(File::create(&h3tess).unwrap());
+        wtr.write_pair(cell, 1.0).unwrap();
+        wtr.finish().unwrap();
+
+        let args = |resolution, dry_run| Combine {
+            resolution,
+            dry_run,
+            ..combine_args(vec![h3tess.clone()], dir.path().join("map.h3idx"))
+        };
+        for dry_run in [false, true] {
+            let msg = format!(
+                "{:?}",
+                combine(args(8, dry_run), &mut std::io::sink()).unwrap_err()
+            );
+            assert!(msg.contains("tessellated at res 6"), "{}", msg);
+            assert!(msg.contains("combine resolution 8"), "{}", msg);
+            combine(args(6, dry_run), &mut std::io::sink()).unwrap();
+        }
+        assert_eq!(
+            read_pairs(&dir.path().join("map.h3idx")),
+            vec![(*cell, 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_combine_truncated_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = fixture_h3tess(dir.path(), &GenOptions::default());
+        let mut buf = std::fs::read(&h3tess).unwrap();
+        buf.truncate(buf.len() - 4);
+        std::fs::write(&h3tess, &buf).unwrap();
+        assert_ne!(buf.len() % RECORD_LEN, 0);
+
+        let args = |tolerate_truncation| Combine {
+            resolution: 10,
+            tolerate_truncation,
+            ..combine_args(vec![h3tess.clone()], dir.path().join("map.h3idx"))
+        };
+        let err = combine(args(false), &mut std::io::sink()).unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("fixture.res10.h3tess"), "{}", msg);
+        assert!(
+            msg.contains(&format!("offset {}", buf.len() - 8)),
+            "{}",
+            msg
+        );
+
+        combine(args(true), &mut std::io::sink()).unwrap();
+        let pairs_read = read_pairs(&dir.path().join("map.h3idx"));
+        assert_eq!(pairs_read.len(), buf.len() / RECORD_LEN);
+    }
+
+    #[test]
+    fn test_combine_corrupt_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = GenOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        let h3tess = fixture_h3tess(dir.path(), &opts);
+        let mut buf = std::fs::read(&h3tess).unwrap();
+        buf[h3tess::HEADER_LEN + 8] ^= 1;
+        std::fs::write(&h3tess, &buf).unwrap();
+
+        let args = |skip_checksum| Combine {
+            resolution: 10,
+            skip_checksum,
+            ..combine_args(vec![h3tess.clone()], dir.path().join("map.h3idx"))
+        };
+        let msg = format!(
+            "{:?}",
+            combine(args(false), &mut std::io::sink()).unwrap_err()
+        );
+        assert!(msg.contains("fixture.res10.h3tess"), "{}", msg);
+        assert!(msg.contains("checksum mismatch"), "{}", msg);
+        combine(args(true), &mut std::io::sink()).unwrap();
+    }
+
+    #[test]
+    fn test_tessellate_recursive() {
+        let indir = tempfile::tempdir().unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(indir.path().join("2015/europe")).unwrap();
+        std::fs::create_dir_all(indir.path().join("2020")).unwrap();
+        std::fs::write(indir.path().join("2015/europe/tile.asc"), FIXTURE).unwrap();
+        std::fs::write(indir.path().join("2015/README.txt"), "not a grid").unwrap();
+        let mut gz = flate2::write::GzEncoder::new(
+            File::create(indir.path().join("2020/tile.asc.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(FIXTURE.as_bytes()).unwrap();
+        gz.finish().unwrap();
+
+        tessellate(Tessellate {
+            recursive: true,
+            ..tessellate_args(
+                vec![indir.path().to_path_buf()],
+                outdir.path().to_path_buf(),
+            )
+        })
+        .unwrap();
+
+        let europe = read_pairs(&outdir.path().join("2015/europe/tile.res10.h3tess"));
+        let gzipped = read_pairs(&outdir.path().join("2020/tile.res10.h3tess"));
+        assert!(!europe.is_empty());
+        assert_eq!(europe.len(), gzipped.len());
+        assert!(!outdir.path().join("2015/README.res10.h3tess").exists());
+    }
+
+    #[test]
+    fn test_tessellate_parallel_files() {
+        let indir = tempfile::tempdir().unwrap();
+        let sources = (0..5)
+            .map(|i| {
+                let path = indir.path().join(format!("tile{}.asc", i));
+                let grid = FIXTURE.replace("0.123", &format!("{}.5", i));
+                std::fs::write(&path, grid).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+
+        let run = |parallel_files| {
+            let outdir = tempfile::tempdir().unwrap();
+            tessellate(Tessellate {
+                threads: Some(1),
+                parallel_files,
+                ..tessellate_args(sources.clone(), outdir.path().to_path_buf())
+            })
+            .unwrap();
+            (0..5)
+                .map(|i| {
+                    let mut pairs =
+                        read_pairs(&outdir.path().join(format!("tile{}.res10.h3tess", i)));
+                    pairs.sort_unstable_by_key(|(cell, _)| *cell);
+                    pairs
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(1), run(3));
+    }
+
+    #[test]
+    fn test_report() {
+        let indir = tempfile::tempdir().unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let plain = indir.path().join("plain.asc");
+        std::fs::write(&plain, FIXTURE).unwrap();
+        // One more populated cell, negative, for --clamp-negative.
+        let negative = indir.path().join("negative.asc");
+        std::fs::write(&negative, FIXTURE.replacen("-9999 -9999", "-2 -9999", 1)).unwrap();
+        let report = outdir.path().join("tessellate.json");
+        tessellate(Tessellate {
+            clamp_negative: true,
+            threads: Some(1),
+            parallel_files: 2,
+            report: Some(report.clone()),
+            ..tessellate_args(vec![plain, negative], outdir.path().to_path_buf())
+        })
+        .unwrap();
+
+        let read_report = |path: &Path| -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+        };
+        let tessellated = read_report(&report);
+        let sources = tessellated["sources"].as_array().unwrap();
+        assert_eq!(sources.len(), 2);
+        let outputs =
+            ["plain.res10.h3tess", "negative.res10.h3tess"].map(|name| outdir.path().join(name));
+        for ((source, output), cells) in sources.iter().zip(&outputs).zip([1, 2]) {
+            assert_eq!(source["output"], output.to_str().unwrap());
+            assert_eq!(source["input_cells"], cells);
+            assert_eq!(source["pairs"], read_pairs(output).len());
+            assert_eq!(source["dropped_cells"], 0);
+            let population = source["total_population"].as_f64().unwrap();
+            assert!((population - 0.123).abs() < 1e-6);
+            assert!(source["seconds"].as_f64().unwrap() >= 0.0);
+        }
+        assert_eq!(sources[0]["clamped_cells"], 0);
+        assert_eq!(sources[1]["clamped_cells"], 1);
+
+        let report = outdir.path().join("combine.json");
+        combine(
+            Combine {
+                report: Some(report.clone()),
+                ..combine_args(outputs.to_vec(), outdir.path().join("map.h3idx"))
+            },
+            &mut std::io::sink(),
+        )
+        .unwrap();
+
+        let combined = read_report(&report);
+        for (source, tessellated) in combined["sources"].as_array().unwrap().iter().zip(sources) {
+            assert_eq!(source["source"], tessellated["output"]);
+            assert_eq!(source["records"], tessellated["pairs"]);
+            assert_eq!(source["dropped_bytes"], 0);
+        }
+        let outputs = combined["outputs"].as_array().unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0]["resolution"], 8);
+        assert_eq!(
+            outputs[0]["pairs"],
+            read_pairs(&outdir.path().join("map.h3idx")).len()
+        );
+    }
+
+    #[test]
+    fn test_tessellate_drop_zero() {
+        let indir = tempfile::tempdir().unwrap();
+        let src = indir.path().join("zero.asc");
+        std::fs::write(&src, FIXTURE.replacen("-9999 0.123", "0 0.123", 1)).unwrap();
+        let run = |drop_zero| {
+            let outdir = tempfile::tempdir().unwrap();
+            tessellate(Tessellate {
+                drop_zero,
+                threads: Some(1),
+                report: Some(outdir.path().join("report.json")),
+                ..tessellate_args(vec![src.clone()], outdir.path().to_path_buf())
+            })
+            .unwrap();
+            let report: serde_json::Value = serde_json::from_str(
+                &std::fs::read_to_string(outdir.path().join("report.json")).unwrap(),
+            )
+            .unwrap();
+            let pairs = read_pairs(&outdir.path().join("zero.res10.h3tess"));
+            (pairs, report["sources"][0]["zero_cells"].as_u64().unwrap())
+        };
+
+        let (kept, zeros) = run(false);
+        assert_eq!(zeros, 0);
+        assert!(kept.iter().any(|(_, val)| *val == 0.0));
+        let (dropped, zeros) = run(true);
+        assert_eq!(zeros, 1);
+        assert!(!dropped.is_empty());
+        assert!(dropped.iter().all(|(_, val)| *val > 0.0));
+        assert!(dropped.len() < kept.len());
+    }
+
+    #[test]
+    fn test_merge_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        // Two tiles repeating the column at longitude -179.99.
+        let tile = |name: &str, xllcorner: f64, rows: &str| {
+            let path = dir.path().join(name);
+            let src = format!(
+                "ncols 2\nnrows 2\nxllcorner {}\nyllcorner 0\ncellsize 0.01\nNODATA_value -9999\n{}",
+                xllcorner, rows
+            );
+            std::fs::write(&path, src).unwrap();
+            path
+        };
+        let west = tile("west.asc", -180.0, "1 5\n2 6\n");
+        let east = tile("east.asc", -179.99, "5 10\n6 20\n");
+        let output = dir.path().join("merged.asc");
+        merge_tiles(MergeTiles {
+            sources: vec![west, east],
+            output: output.clone(),
+        })
+        .unwrap();
+
+        let merged = GpwAscii::parse(&mut BufReader::new(File::open(&output).unwrap())).unwrap();
+        assert_eq!((merged.header.ncols, merged.header.nrows), (3, 2));
+        assert_eq!(merged.total_population(), 44.0);
+    }
+
+    #[test]
+    fn test_tessellate_per_source_resolution() {
+        let indir = tempfile::tempdir().unwrap();
+        let sources = ["fine.asc", "coarse.asc"].map(|name| {
+            let path = indir.path().join(name);
+            std::fs::write(&path, FIXTURE).unwrap();
+            path
+        });
+        let args = |resolution, outdir: &Path| Tessellate {
+            resolution,
+            threads: Some(1),
+            distribution: Distribution::CentroidOnly,
+            ..tessellate_args(sources.to_vec(), outdir.to_path_buf())
+        };
+
+        let outdir = tempfile::tempdir().unwrap();
+        tessellate(args(vec![10, 9], outdir.path())).unwrap();
+        for (name, res) in [("fine.res10.h3tess", 10), ("coarse.res9.h3tess", 9)] {
+            let pairs = read_pairs(&outdir.path().join(name));
+            assert_eq!(pairs.len(), 1);
+            assert_eq!(H3Cell::new(pairs[0].0).resolution(), res);
         }
-        if let [Some(v0), Some(v1), Some(v2), Some(v3), Some(v4), Some(v5), Some(v6)] = children {
-            return Some(v0 + v1 + v2 + v3 + v4 + v5 + v6);
+        assert!(!outdir.path().join("coarse.res10.h3tess").exists());
+
+        let outdir = tempfile::tempdir().unwrap();
+        let err = tessellate(args(vec![10, 9, 8], outdir.path())).unwrap_err();
+        assert!(err.to_string().contains("3 resolutions for 2 sources"));
+    }
+
+    #[test]
+    fn test_tessellate_skip_existing() {
+        let indir = tempfile::tempdir().unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let sources = ["done", "empty", "corrupt", "fresh"]
+            .map(|name| {
+                let path = indir.path().join(format!("{}.asc", name));
+                std::fs::write(&path, FIXTURE).unwrap();
+                path
+            })
+            .to_vec();
+        let output = |name| outdir.path().join(format!("{}.res10.h3tess", name));
+        // A sentinel that differs from what tessellating would write.
+        let mut done = Vec::new();
+        gen_to_disk(
+            GpwAscii::parse(&mut BufReader::new(
+                FIXTURE.replace("0.123", "7").as_bytes(),
+            ))
+            .unwrap(),
+            &GenOptions::default(),
+            &mut done,
+        )
+        .unwrap();
+        std::fs::write(output("done"), &done).unwrap();
+        std::fs::write(output("empty"), b"").unwrap();
+        std::fs::write(output("corrupt"), &done[..done.len() - 4]).unwrap();
+
+        tessellate(Tessellate {
+            skip_existing: true,
+            ..tessellate_args(sources, outdir.path().to_path_buf())
+        })
+        .unwrap();
+
+        assert_eq!(std::fs::read(output("done")).unwrap(), done);
+        for name in ["empty", "corrupt", "fresh"] {
+            let total: f32 = read_pairs(&output(name)).iter().map(|(_, val)| val).sum();
+            assert!((total - 0.123).abs() < 1e-5, "{}: {}", name, total);
+        }
+    }
+
+    #[test]
+    fn test_tessellate_no_clobber() {
+        let indir = tempfile::tempdir().unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let sources = ["fresh", "existing"]
+            .map(|name| {
+                let path = indir.path().join(format!("{}.asc", name));
+                std::fs::write(&path, FIXTURE).unwrap();
+                path
+            })
+            .to_vec();
+        let existing = outdir.path().join("existing.res10.h3tess");
+        std::fs::write(&existing, b"keep me").unwrap();
+
+        let args = |no_clobber| Tessellate {
+            no_clobber,
+            ..tessellate_args(sources.clone(), outdir.path().to_path_buf())
         };
-        None
+        let msg = format!("{:?}", tessellate(args(true)).unwrap_err());
+        assert!(msg.contains("existing.res10.h3tess"), "{}", msg);
+        assert_eq!(std::fs::read(&existing).unwrap(), b"keep me");
+        // Nothing is written, not even outputs that didn't exist.
+        assert!(!outdir.path().join("fresh.res10.h3tess").exists());
+
+        tessellate(args(false)).unwrap();
+        assert!(!read_pairs(&existing).is_empty());
+    }
+
+    /// Writer that only keeps bytes that were flushed before it
+    /// starts failing, like a file after a crash.
+    struct CrashingWriter {
+        durable: Vec<u8>,
+        pending: Vec<u8>,
+        budget: usize,
+    }
+
+    impl Write for CrashingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() > self.budget {
+                return Err(std::io::Error::other("crash"));
+            }
+            self.budget -= buf.len();
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.durable.append(&mut self.pending);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_map_partial_output() {
+        let mut map = HexTreeMap::with_compactor(ReduceCompactor {
+            resolution: 10,
+            mode: ReduceMode::Sum,
+        });
+        let parent = H3Cell::from_coordinate(coord! {x: 8.5, y: 47.4}, 5).unwrap();
+        // Compacting only below res 10 keeps every child as a record.
+        for cell in parent.get_children(10).unwrap().iter() {
+            map.insert(cell, (1.0, 1));
+        }
+        let mut full = Vec::new();
+        write_map(&map, Format::default(), &mut full).unwrap();
+        assert!(full.len() > 3 * FLUSH_RECORDS * RECORD_LEN);
+
+        let mut crashing = CrashingWriter {
+            durable: Vec::new(),
+            pending: Vec::new(),
+            budget: full.len() / 2,
+        };
+        assert!(write_map(&map, Format::default(), &mut BufWriter::new(&mut crashing)).is_err());
+
+        // Whatever made it out is a whole number of flushed records
+        // and a prefix of the complete output, so a rerun can resume
+        // after its last record.
+        let partial = crashing.durable;
+        assert!(!partial.is_empty());
+        assert_eq!(partial.len() % (FLUSH_RECORDS * RECORD_LEN), 0);
+        assert_eq!(partial[..], full[..partial.len()]);
+        assert_eq!(
+            h3tess::verify(partial.as_slice(), false).unwrap() as usize,
+            partial.len() / RECORD_LEN
+        );
+    }
+
+    #[test]
+    fn test_tessellate_dry_run() {
+        let indir = tempfile::tempdir().unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let good = indir.path().join("good.asc");
+        let bad = indir.path().join("bad.asc");
+        std::fs::write(&good, FIXTURE).unwrap();
+        std::fs::write(
+            &bad,
+            FIXTURE.replace("ncols         4", "ncols         four"),
+        )
+        .unwrap();
+
+        let args = |sources| Tessellate {
+            dry_run: true,
+            ..tessellate_args(sources, outdir.path().join("nested"))
+        };
+        tessellate(args(vec![good.clone()])).unwrap();
+        let err = tessellate(args(vec![good, bad])).unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("bad.asc"), "{}", msg);
+        assert!(msg.contains("ncols"), "{}", msg);
+        assert_eq!(std::fs::read_dir(outdir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_tessellate_bad_header() {
+        let indir = tempfile::tempdir().unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let bad = indir.path().join("bad.asc");
+        std::fs::write(&bad, FIXTURE.replace("nrows         4", "nrows         -4")).unwrap();
+
+        let err = tessellate(tessellate_args(vec![bad], outdir.path().to_path_buf())).unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("bad.asc"), "{}", msg);
+        assert!(msg.contains("line 2: nrows"), "{}", msg);
+    }
+
+    #[test]
+    fn test_combine_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = fixture_h3tess(dir.path(), &GenOptions::default());
+        let corrupt = dir.path().join("corrupt.h3tess");
+        std::fs::write(&corrupt, [0xff; RECORD_LEN]).unwrap();
+
+        let args = |sources| Combine {
+            resolutions: vec![7, 8],
+            dry_run: true,
+            ..combine_args(sources, dir.path().join("map.h3idx"))
+        };
+        combine(args(vec![h3tess.clone()]), &mut std::io::sink()).unwrap();
+        assert!(combine(args(vec![h3tess, corrupt]), &mut std::io::sink()).is_err());
+        assert!(!dir.path().join("map.res7.h3idx").exists());
+        assert!(!dir.path().join("map.res8.h3idx").exists());
+    }
+
+    #[test]
+    fn test_combine_invalid_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = std::fs::read(fixture_h3tess(dir.path(), &GenOptions::default())).unwrap();
+        let sources = [
+            ("a.h3tess", good.clone()),
+            ("truncated.h3tess", good[..good.len() - 4].to_vec()),
+            ("b.h3tess", good.clone()),
+            ("garbage.h3tess", vec![0xff; RECORD_LEN]),
+        ]
+        .map(|(name, buf)| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, buf).unwrap();
+            path
+        });
+
+        let output = dir.path().join("map.h3idx");
+        let err = combine(
+            combine_args(sources.to_vec(), output.clone()),
+            &mut std::io::sink(),
+        )
+        .unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("2 of 4 sources are invalid"), "{}", msg);
+        assert!(msg.contains("truncated.h3tess"), "{}", msg);
+        assert!(msg.contains("garbage.h3tess"), "{}", msg);
+        assert!(!msg.contains("a.h3tess"), "{}", msg);
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_combine_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = fixture_h3tess(dir.path(), &GenOptions::default());
+
+        let args = |format, output| Combine {
+            format,
+            ..combine_args(vec![h3tess.clone()], output)
+        };
+        let map = dir.path().join("map.h3idx");
+        let db = dir.path().join("map.sqlite");
+        combine(args(MapFormat::H3tess, map.clone()), &mut std::io::sink()).unwrap();
+        combine(args(MapFormat::Sqlite, db.clone()), &mut std::io::sink()).unwrap();
+
+        let pairs = read_pairs(&map);
+        let conn = rusqlite::Connection::open(&db).unwrap();
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM cells", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows as usize, pairs.len());
+        let (cell, val) = pairs[0];
+        let population: f64 = conn
+            .query_row(
+                "SELECT population FROM cells WHERE h3_index = ?1",
+                [cell as i64],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(population as f32, val);
+    }
+
+    #[test]
+    fn test_combine_parquet() {
+        use arrow_array::Array;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = fixture_h3tess(dir.path(), &GenOptions::default());
+
+        let args = |format, output| Combine {
+            resolution: 10,
+            format,
+            ..combine_args(vec![h3tess.clone()], output)
+        };
+        let map = dir.path().join("map.h3idx");
+        let parquet = dir.path().join("map.parquet");
+        combine(args(MapFormat::H3tess, map.clone()), &mut std::io::sink()).unwrap();
+        combine(
+            args(MapFormat::Parquet, parquet.clone()),
+            &mut std::io::sink(),
+        )
+        .unwrap();
+
+        let rdr = ParquetRecordBatchReaderBuilder::try_new(File::open(&parquet).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut pairs = Vec::new();
+        for batch in rdr {
+            let batch = batch.unwrap();
+            assert_eq!(batch.num_columns(), 2);
+            let cells = batch
+                .column_by_name("h3_index")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap();
+            let vals = batch
+                .column_by_name("population")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Float32Array>()
+                .unwrap();
+            assert_eq!(cells.null_count() + vals.null_count(), 0);
+            pairs.extend(
+                cells
+                    .values()
+                    .iter()
+                    .copied()
+                    .zip(vals.values().iter().copied()),
+            );
+        }
+        // Same cells in the same order, with bit-for-bit equal values.
+        assert_eq!(pairs, read_pairs(&map));
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let parent = H3Cell::from_coordinate(coord! {x: 8.5, y: 47.4}, 8).unwrap();
+        let children = parent.get_children(10).unwrap();
+        let mut children = children.iter();
+        let (a, b) = (children.next().unwrap(), children.next().unwrap());
+        let csv = dir.path().join("external.csv");
+        std::fs::write(&csv, format!("h3_index,value\n{:x},1.5\n{},2.25\n", *a, *b)).unwrap();
+        let h3tess = dir.path().join("external.h3tess");
+        import_csv(ImportCsv {
+            checksum: true,
+            file: csv.clone(),
+            output: h3tess.clone(),
+        })
+        .unwrap();
+
+        let output = dir.path().join("map.h3idx");
+        combine(
+            Combine {
+                resolution: 10,
+                ..combine_args(vec![h3tess.clone()], output.clone())
+            },
+            &mut std::io::sink(),
+        )
+        .unwrap();
+        let mut pairs = read_pairs(&output);
+        pairs.sort_unstable_by_key(|(cell, _)| *cell);
+        let mut expected = vec![(*a, 1.5), (*b, 2.25)];
+        expected.sort_unstable_by_key(|(cell, _)| *cell);
+        assert_eq!(pairs, expected);
+
+        std::fs::write(&csv, "8a1fb46622dffff,1\nffffffffffffffff,2\n").unwrap();
+        let err = import_csv(ImportCsv {
+            checksum: false,
+            file: csv,
+            output: h3tess,
+        })
+        .unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("external.csv"), "{}", msg);
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = fixture_h3tess(dir.path(), &GenOptions::default());
+        let output = dir.path().join("fixture.png");
+        heatmap(Heatmap {
+            width: 64,
+            bbox: None,
+            colormap: Default::default(),
+            skip_checksum: false,
+            file: h3tess,
+            output: output.clone(),
+        })
+        .unwrap();
+
+        let mut rdr = png::Decoder::new(BufReader::new(File::open(&output).unwrap()))
+            .read_info()
+            .unwrap();
+        let (width, height) = (rdr.info().width, rdr.info().height);
+        assert_eq!((width, height), (64, 32));
+        let mut buf = vec![0; (width * height * 4) as usize];
+        rdr.next_frame(&mut buf).unwrap();
+        // The fixture's one populated grid cell sits on the equator at
+        // the antimeridian: the left edge, halfway down.
+        let opaque = buf
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, px)| px[3] > 0)
+            .map(|(i, _)| (i % 64, i / 64))
+            .collect::<Vec<_>>();
+        assert!(!opaque.is_empty());
+        assert!(
+            opaque
+                .iter()
+                .all(|(col, row)| *col == 0 && (15..=16).contains(row)),
+            "{:?}",
+            opaque
+        );
+    }
+
+    #[test]
+    fn test_hist() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("map.h3tess");
+        let parent = H3Cell::from_coordinate(coord! {x: 8.5, y: 47.4}, 5).unwrap();
+        let mut wtr = H3TessWriter::new(File::create(&file).unwrap());
+        for (i, cell) in parent
+            .get_children(9)
+            .unwrap()
+            .iter()
+            .take(1000)
+            .enumerate()
+        {
+            wtr.write_pair(cell, (i + 1) as f32).unwrap();
+        }
+        wtr.finish().unwrap();
+
+        let run = |format| {
+            let mut out = Vec::new();
+            hist(
+                Hist {
+                    buckets: 4,
+                    format,
+                    skip_checksum: false,
+                    file: file.clone(),
+                },
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let csv = run(HistFormat::Csv);
+        assert!(
+            csv.starts_with("stat,value\ncount,1000\nmin,1\nmax,1000\n"),
+            "{}",
+            csv
+        );
+        assert!(
+            csv.ends_with("lower,upper,count\n1,250.75,250\n250.75,500.5,250\n500.5,750.25,250\n750.25,1000,250\n"),
+            "{}",
+            csv
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&run(HistFormat::Json)).unwrap();
+        assert_eq!(json["count"], 1000);
+        for (name, expected) in [("p50", 500.0), ("p90", 900.0), ("p99", 990.0)] {
+            let actual = json["percentiles"][name].as_f64().unwrap();
+            assert!((actual - expected).abs() <= 10.0, "{}: {}", name, actual);
+        }
+        assert_eq!(json["buckets"].as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_combine_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = GenOptions {
+            counts: true,
+            ..Default::default()
+        };
+        let h3tess = fixture_h3tess(dir.path(), &opts);
+        let hexes = read_pairs(&h3tess).len();
+
+        let output = dir.path().join("map.h3idx");
+        combine(
+            Combine {
+                counts: true,
+                ..combine_args(vec![h3tess], output.clone())
+            },
+            &mut std::io::sink(),
+        )
+        .unwrap();
+
+        let mut rdr = H3TessReader::new(File::open(&output).unwrap());
+        let records = rdr.counted().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            rdr.format(),
+            Some(Format {
+                counts: true,
+                ..Default::default()
+            })
+        );
+        let total_count: u32 = records.iter().map(|(_, _, count)| count).sum();
+        assert_eq!(total_count as usize, hexes);
+    }
+
+    #[test]
+    fn test_combine_matches_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = fixture_h3tess(dir.path(), &GenOptions::default());
+        // Overlapping sources, so the result depends on insertion order.
+        let pairs = read_pairs(&fixture);
+        let doubled = dir.path().join("doubled.h3tess");
+        let reversed = dir.path().join("reversed.h3tess");
+        for (path, pairs) in [
+            (
+                &doubled,
+                pairs[..pairs.len() / 2]
+                    .iter()
+                    .map(|(c, v)| (*c, v * 2.0))
+                    .collect(),
+            ),
+            (&reversed, pairs.iter().rev().copied().collect::<Vec<_>>()),
+        ] {
+            let mut wtr = H3TessWriter::new(File::create(path).unwrap());
+            for (cell, val) in pairs {
+                wtr.write_pair(H3Cell::new(cell), val).unwrap();
+            }
+            wtr.finish().unwrap();
+        }
+        let sources = vec![doubled, fixture, reversed.clone(), reversed];
+
+        let output = dir.path().join("map.h3idx");
+        combine(
+            Combine {
+                counts: true,
+                ..combine_args(sources.clone(), output.clone())
+            },
+            &mut std::io::sink(),
+        )
+        .unwrap();
+
+        let mut map = HexTreeMap::with_compactor(ReduceCompactor {
+            resolution: 8,
+            mode: ReduceMode::Sum,
+        });
+        for source in &sources {
+            let mut rdr = H3TessReader::new(BufReader::new(File::open(source).unwrap()));
+            insert_h3tess(&mut map, &mut rdr, |_| Ok(())).unwrap();
+        }
+        let mut expected = Vec::new();
+        let format = Format {
+            counts: true,
+            ..Default::default()
+        };
+        write_map(&map, format, &mut expected).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_combine_stdout() {
+        let dir = tempfile::tempdir().unwrap();
+        let h3tess = fixture_h3tess(dir.path(), &GenOptions::default());
+        let combine_to = |output: PathBuf, stdout: &mut Vec<u8>| {
+            let args = Combine {
+                checksum: true,
+                ..combine_args(vec![h3tess.clone()], output)
+            };
+            combine(args, stdout)
+        };
+
+        let output = dir.path().join("map.h3idx");
+        let mut stdout = Vec::new();
+        combine_to(output.clone(), &mut stdout).unwrap();
+        assert!(stdout.is_empty());
+        combine_to("-".into(), &mut stdout).unwrap();
+        assert_eq!(stdout, std::fs::read(&output).unwrap());
+
+        let err = combine(
+            Combine {
+                resolutions: vec![7, 8],
+                ..combine_args(vec![h3tess], "-".into())
+            },
+            &mut std::io::sink(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("stdout"), "{}", err);
+    }
+
+    #[test]
+    fn test_combine_provenance() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = H3Cell::from_coordinate(coord! {x: 8.5, y: 47.4}, 8).unwrap();
+        let only_b = H3Cell::from_coordinate(coord! {x: 9.5, y: 47.4}, 8).unwrap();
+        let children = |cell: H3Cell| cell.get_children(10).unwrap().iter().collect::<Vec<_>>();
+        let write_source = |name: &str, cells: &[H3Cell]| {
+            let path = dir.path().join(name);
+            let mut wtr = H3TessWriter::new(File::create(&path).unwrap());
+            for cell in cells {
+                wtr.write_pair(*cell, 1.0).unwrap();
+            }
+            wtr.finish().unwrap();
+            path
+        };
+        let a = write_source("a.h3tess", &children(shared)[..3]);
+        let b = write_source(
+            "b.h3tess",
+            &[&children(shared)[3..], &children(only_b)[..]].concat(),
+        );
+
+        let sidecar = dir.path().join("map.provenance");
+        combine(
+            Combine {
+                provenance: Some(sidecar.clone()),
+                ..combine_args(vec![a.clone(), b.clone()], dir.path().join("map.h3idx"))
+            },
+            &mut std::io::sink(),
+        )
+        .unwrap();
+
+        let contents = std::fs::read_to_string(&sidecar).unwrap();
+        let mut lines = contents.lines().collect::<Vec<_>>();
+        lines.sort_unstable();
+        let mut expected = vec![
+            format!("# 0 {}", a.display()),
+            format!("# 1 {}", b.display()),
+            format!("{:x}\t0,1", *shared),
+            format!("{:x}\t1", *only_b),
+        ];
+        expected.sort_unstable();
+        assert_eq!(lines, expected);
+    }
+
+    proptest! {
+        #[test]
+        fn tessellate_then_combine_conserves_population(data in grid()) {
+            let header = GpwAsciiHeader {
+                ncols: data[0].len(),
+                nrows: data.len(),
+                xllcorner: 8.0,
+                yllcorner: 47.0,
+                cellsize: 0.0083333333333333,
+                dx: None,
+                dy: None,
+                nodata_value: "-9999".to_string(),
+            };
+            let src = GpwAscii { header, data, filename: None };
+            let expected = src.total_population();
+
+            let mut h3tess = Vec::new();
+            gen_to_disk(src, &GenOptions::default(), &mut h3tess).unwrap();
+            let mut map = HexTreeMap::with_compactor(ReduceCompactor {
+                resolution: 8,
+                mode: ReduceMode::Sum,
+            });
+            insert_h3tess(&mut map, &mut H3TessReader::new(h3tess.as_slice()), |_| Ok(())).unwrap();
+
+            let actual: f64 = map.iter().map(|(_, (v, _))| f64::from(*v)).sum();
+            prop_assert!((actual - expected).abs() <= expected * 1e-4 + 1e-3);
+        }
     }
 }
@@ -0,0 +1,74 @@
+//! Output file names that carry the H3 resolution of their contents,
+//! so archives holding, say, res 7 and res 8 maps can't mix them up.
+
+use crate::h3tess::Encoding;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+/// Stem of a combined map's file name when no output is given.
+pub const DEFAULT_MAP_STEM: &str = "gpw";
+
+/// Returns `path` with `res{N}` inserted before its extension, e.g.
+/// `map.h3idx` becomes `map.res8.h3idx`.
+pub fn with_resolution(path: &Path, res: u8) -> PathBuf {
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    path.with_extension(format!("res{}.{}", res, ext).trim_end_matches('.'))
+}
+
+/// Returns the output path for a tessellated grid: `rel_path` under
+/// `outdir` with any `.gz` dropped and the extension replaced by
+/// `res{N}.` and the encoding's extension.
+pub fn tessellation_path(
+    outdir: &Path,
+    rel_path: &Path,
+    resolution: u8,
+    encoding: Encoding,
+) -> PathBuf {
+    let mut dst = outdir.join(rel_path);
+    if dst.extension() == Some(OsStr::new("gz")) {
+        dst.set_extension("");
+    }
+    with_resolution(&dst.with_extension(encoding.extension()), resolution)
+}
+
+/// Returns the name of a combined map with extension `ext`, before
+/// any resolution is added with [`with_resolution`].
+pub fn map_path(ext: &str) -> PathBuf {
+    Path::new(DEFAULT_MAP_STEM).with_extension(ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        let tess =
+            |rel: &str| tessellation_path(Path::new("out"), Path::new(rel), 10, Encoding::H3tess);
+        assert_eq!(tess("a.asc"), Path::new("out/a.res10.h3tess"));
+        assert_eq!(
+            tess("tiles/a.asc.gz"),
+            Path::new("out/tiles/a.res10.h3tess")
+        );
+        assert_eq!(tess("grid"), Path::new("out/grid.res10.h3tess"));
+        assert_eq!(
+            tessellation_path(Path::new("out"), Path::new("a.asc"), 9, Encoding::Csv),
+            Path::new("out/a.res9.csv")
+        );
+
+        assert_eq!(
+            with_resolution(&map_path("h3idx"), 8),
+            Path::new("gpw.res8.h3idx")
+        );
+        assert_eq!(
+            with_resolution(&map_path("sqlite"), 7),
+            Path::new("gpw.res7.sqlite")
+        );
+        assert_eq!(
+            with_resolution(Path::new("maps/world"), 6),
+            Path::new("maps/world.res6")
+        );
+    }
+}
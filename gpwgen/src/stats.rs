@@ -0,0 +1,271 @@
+//! Streaming summary statistics for maps too large to hold in memory.
+
+/// Estimates a single quantile of a stream in constant space with the
+/// P² algorithm (Jain & Chlamtac, 1985).
+///
+/// Five markers track the minimum, maximum, the target quantile and
+/// the quantiles halfway to either side. Marker heights are adjusted
+/// with piecewise-parabolic interpolation as values arrive. Results
+/// are exact for fewer than five values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct P2Quantile {
+    p: f64,
+    count: usize,
+    /// Marker heights.
+    heights: [f64; 5],
+    /// Actual marker positions.
+    positions: [f64; 5],
+    /// Desired marker positions.
+    desired: [f64; 5],
+    /// Desired position increments per value.
+    increments: [f64; 5],
+}
+
+impl P2Quantile {
+    /// Creates an estimator for quantile `p` in `[0, 1]`.
+    pub fn new(p: f64) -> Self {
+        Self {
+            p,
+            count: 0,
+            heights: [0.0; 5],
+            positions: [0.0, 1.0, 2.0, 3.0, 4.0],
+            desired: [0.0, 2.0 * p, 4.0 * p, 2.0 + 2.0 * p, 4.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    pub fn push(&mut self, x: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = x;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_by(f64::total_cmp);
+            }
+            return;
+        }
+        self.count += 1;
+
+        let q = &mut self.heights;
+        let k = if x < q[0] {
+            q[0] = x;
+            0
+        } else if x >= q[4] {
+            q[4] = x;
+            3
+        } else {
+            (1..5).find(|&i| x < q[i]).unwrap_or(4) - 1
+        };
+        for n in &mut self.positions[k + 1..] {
+            *n += 1.0;
+        }
+        for (np, dn) in self.desired.iter_mut().zip(self.increments) {
+            *np += dn;
+        }
+
+        for i in 1..4 {
+            let n = &self.positions;
+            let d = self.desired[i] - n[i];
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let parabolic = self.parabolic(i, d);
+                self.heights[i] =
+                    if self.heights[i - 1] < parabolic && parabolic < self.heights[i + 1] {
+                        parabolic
+                    } else {
+                        self.linear(i, d)
+                    };
+                self.positions[i] += d;
+            }
+        }
+    }
+
+    /// Returns the current estimate, or `None` before any values.
+    pub fn estimate(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            1..=4 => {
+                let mut seen = self.heights[..self.count].to_vec();
+                seen.sort_by(f64::total_cmp);
+                Some(seen[((self.count - 1) as f64 * self.p).round() as usize])
+            }
+            _ => Some(self.heights[2]),
+        }
+    }
+
+    fn parabolic(&self, i: usize, d: f64) -> f64 {
+        let (q, n) = (&self.heights, &self.positions);
+        q[i] + d / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, d: f64) -> f64 {
+        let (q, n) = (&self.heights, &self.positions);
+        let j = if d > 0.0 { i + 1 } else { i - 1 };
+        q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+    }
+}
+
+/// Equal-width value histogram over a known `[min, max]` range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    min: f64,
+    max: f64,
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Creates `buckets` empty buckets spanning `[min, max]`.
+    pub fn new(min: f64, max: f64, buckets: usize) -> Self {
+        Self {
+            min,
+            max,
+            counts: vec![0; buckets.max(1)],
+        }
+    }
+
+    /// Counts `x`, clamping values outside the range into the first
+    /// or last bucket. The last bucket includes `max`.
+    pub fn push(&mut self, x: f64) {
+        let last = self.counts.len() - 1;
+        let width = self.max - self.min;
+        let idx = if width > 0.0 {
+            (((x - self.min) / width * self.counts.len() as f64).max(0.0) as usize).min(last)
+        } else {
+            0
+        };
+        self.counts[idx] += 1;
+    }
+
+    /// Returns each bucket's `(lower, upper, count)`.
+    pub fn buckets(&self) -> impl Iterator<Item = (f64, f64, u64)> + '_ {
+        let width = (self.max - self.min) / self.counts.len() as f64;
+        self.counts.iter().enumerate().map(move |(i, count)| {
+            let lower = self.min + width * i as f64;
+            (lower, lower + width, *count)
+        })
+    }
+}
+
+/// Weighted mean of points on the sphere, e.g. the
+/// population-weighted centroid of a region.
+///
+/// Points are averaged as unit vectors and the sum projected back
+/// onto the sphere, so regions spanning the antimeridian or a pole
+/// come out where expected rather than on the far side of the globe.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Centroid {
+    sum: [f64; 3],
+    weight: f64,
+}
+
+impl Centroid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a point in degrees with the given weight.
+    pub fn push(&mut self, lat: f64, lng: f64, weight: f64) {
+        let (lat, lng) = (lat.to_radians(), lng.to_radians());
+        let v = [lat.cos() * lng.cos(), lat.cos() * lng.sin(), lat.sin()];
+        for (sum, v) in self.sum.iter_mut().zip(v) {
+            *sum += weight * v;
+        }
+        self.weight += weight;
+    }
+
+    /// Total weight pushed so far.
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    /// Returns the centroid as `(lat, lng)` in degrees, or `None` if
+    /// there's no weight or it cancels out, as for two equally
+    /// weighted antipodes.
+    pub fn estimate(&self) -> Option<(f64, f64)> {
+        let [x, y, z] = self.sum;
+        let norm = (x * x + y * y + z * z).sqrt();
+        if self.weight <= 0.0 || norm <= 1e-12 * self.weight {
+            return None;
+        }
+        Some((z.atan2(x.hypot(y)).to_degrees(), y.atan2(x).to_degrees()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_p2_quantile() {
+        // Feed 1..=1000 in a scrambled but deterministic order.
+        let values = (0..1000).map(|i| ((i * 379) % 1000 + 1) as f64);
+        let mut estimators = [0.5, 0.9, 0.99].map(P2Quantile::new);
+        for x in values {
+            for est in &mut estimators {
+                est.push(x);
+            }
+        }
+        for (est, expected) in estimators.iter().zip([500.0, 900.0, 990.0]) {
+            let actual = est.estimate().unwrap();
+            assert!(
+                (actual - expected).abs() <= 10.0,
+                "{} vs {}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_p2_quantile_few_values() {
+        let mut est = P2Quantile::new(0.5);
+        assert_eq!(est.estimate(), None);
+        for x in [3.0, 1.0, 2.0] {
+            est.push(x);
+        }
+        assert_eq!(est.estimate(), Some(2.0));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut hist = Histogram::new(0.0, 10.0, 5);
+        for x in [0.0, 1.9, 2.0, 5.0, 9.9, 10.0] {
+            hist.push(x);
+        }
+        let counts = hist
+            .buckets()
+            .map(|(_, _, count)| count)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![2, 1, 1, 0, 2]);
+        assert_eq!(hist.buckets().last(), Some((8.0, 10.0, 2)));
+    }
+
+    #[test]
+    fn test_centroid() {
+        let close = |(lat, lng): (f64, f64), (exp_lat, exp_lng): (f64, f64)| {
+            (lat - exp_lat).abs() < 1e-9 && (lng - exp_lng).abs() < 1e-9
+        };
+
+        let mut centroid = Centroid::new();
+        assert_eq!(centroid.estimate(), None);
+        centroid.push(0.0, 0.0, 1.0);
+        centroid.push(0.0, 90.0, 3.0);
+        let est = centroid.estimate().unwrap();
+        assert!(close(est, (0.0, 3f64.atan().to_degrees())), "{:?}", est);
+        assert_eq!(centroid.weight(), 4.0);
+
+        // Straddling the antimeridian lands on it, not at 0°.
+        let mut centroid = Centroid::new();
+        centroid.push(10.0, 179.0, 2.0);
+        centroid.push(10.0, -179.0, 2.0);
+        let (lat, lng) = centroid.estimate().unwrap();
+        assert!(lat > 10.0 && lat < 10.01, "{}", lat);
+        assert!((lng.abs() - 180.0).abs() < 1e-9, "{}", lng);
+
+        let mut centroid = Centroid::new();
+        centroid.push(0.0, 0.0, 1.0);
+        centroid.push(0.0, 180.0, 1.0);
+        assert_eq!(centroid.estimate(), None);
+    }
+}
@@ -0,0 +1,9 @@
+//! Serving population maps over HTTP.
+//!
+//! [`service::GpwService`] is a `tower::Service` for the full HTTP API,
+//! and [`service::handle_query`] the bare lookup behind it, for
+//! embedding in other servers.
+
+#![deny(clippy::unwrap_used)]
+
+pub mod service;
@@ -1,20 +1,26 @@
 #![deny(clippy::unwrap_used)]
 
 mod options;
-use anyhow::Result;
-use byteorder::{LittleEndian as LE, ReadBytesExt};
-use clap::Parser;
-use hextree::{h3ron::H3Cell, HexTreeMap};
+use anyhow::{bail, Context, Result};
+use gpwgen::{
+    combine::{insert_h3tess, ReduceCompactor, ReduceMode},
+    h3tess::H3TessReader,
+};
+use gpws::service;
+use hextree::HexTreeMap;
 use hyper::{
-    body::Body,
-    service::{make_service_fn, service_fn},
-    Error, Response, Server, StatusCode,
+    server::{accept::Accept, conn::AddrIncoming},
+    service::make_service_fn,
+    Error, Server,
 };
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use std::{
-    convert::TryFrom,
+    collections::HashSet,
     fs::File,
-    io::{BufReader, ErrorKind},
+    future::Future,
+    io::{BufReader, Read},
+    num::NonZeroUsize,
+    path::Path,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
@@ -24,104 +30,188 @@ use std::{
 };
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = options::Cli::parse();
-    let f = File::open(args.path)?;
-    let map: HexTreeMap<f32> = deserialize_hexmap(f)?;
-    let map = Arc::new(map);
-
-    let make_service = make_service_fn(move |_| {
-        let map = map.clone();
-        async move {
-            Ok::<_, Error>(service_fn(move |req| {
-                let path = req.uri().path();
-                let population = u64::from_str_radix(&path[1..], 16)
-                    .ok()
-                    .and_then(|index| H3Cell::try_from(index).ok())
-                    .and_then(|cell| {
-                        map.reduce(cell, |_resolution, cells| {
-                            cells.iter().sum::<f32>()
-                        })
-                    });
-                async move {
-                    match population {
-                        Some(pop) => {
-                            Ok::<_, Error>(Response::new(Body::from(format!("{:?}", pop))))
-                        }
-                        None => {
-                            let mut not_found = Response::default();
-                            *not_found.status_mut() = StatusCode::NOT_FOUND;
-                            Ok(not_found)
-                        }
-                    }
-                }
-            }))
+fn main() -> Result<()> {
+    let args = options::Cli::load()?;
+    runtime(args.worker_threads)?.block_on(run(args))
+}
+
+/// Builds the multi-threaded runtime the server runs on, with
+/// `worker_threads` workers or, by default, one per core.
+fn runtime(worker_threads: Option<NonZeroUsize>) -> Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = worker_threads {
+        builder.worker_threads(threads.get());
+    }
+    Ok(builder.build()?)
+}
+
+async fn run(args: options::Cli) -> Result<()> {
+    init_logging(args.log_format);
+    let load = |path: &Path| -> Result<HexTreeMap<f32>> {
+        let f = File::open(path).with_context(|| path.display().to_string())?;
+        match args.combine_resolution {
+            Some(res) => combine_hexmap(f, res, args.tolerate_truncation, args.skip_checksum),
+            None => deserialize_hexmap(f, args.tolerate_truncation, args.skip_checksum),
         }
-    });
+        .with_context(|| path.display().to_string())
+    };
+    let mut state = match &args.path {
+        Some(path) => service::State::new(load(path)?, args.cache_size, args.top_max),
+        None => service::State::empty(args.cache_size, args.top_max),
+    };
+    let mut names = HashSet::new();
+    for (name, path) in &args.maps {
+        if !names.insert(name) {
+            bail!("map name {:?} given more than once", name);
+        }
+        info!("Loading map {:?}", name);
+        state = state.named_map(name.clone(), load(path)?);
+    }
+    let state = Arc::new(
+        state
+            .precompute(&args.precompute)
+            .downsample(args.output_resolution_downsample)
+            .request_timeout(args.request_timeout),
+    );
 
-    let addr = ([127, 0, 0, 1], 3000).into();
-    let server = Server::bind(&addr).serve(make_service);
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
 
-    println!("Listening on http://{}", addr);
+    #[cfg(unix)]
+    if let Some(path) = &args.unix {
+        info!("Listening on unix:{}", path.display());
+        return serve_unix(path, state, shutdown).await;
+    }
+
+    let addr = ([127, 0, 0, 1], 3000).into();
+    let incoming = AddrIncoming::bind(&addr)?;
+    info!("Listening on http://{}", addr);
+    serve(incoming, state, shutdown).await
+}
 
-    server.await?;
+/// Serves `state` on every connection from `incoming` until
+/// `shutdown` resolves, then lets in-flight requests finish.
+async fn serve<I>(
+    incoming: I,
+    state: Arc<service::State>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()>
+where
+    I: Accept,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_service = make_service_fn(move |_: &I::Conn| {
+        let state = state.clone();
+        async move { Ok::<_, Error>(service::GpwService::new(state)) }
+    });
+    Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await?;
     Ok(())
 }
 
-fn deserialize_hexmap(src_file: File) -> Result<HexTreeMap<f32>> {
+/// Serves on a Unix domain socket at `path`, removing the socket file
+/// once the server stops.
+#[cfg(unix)]
+async fn serve_unix(
+    path: &std::path::Path,
+    state: Arc<service::State>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let listener = tokio::net::UnixListener::bind(path)?;
+    let incoming = hyper::server::accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|conn| Some(conn.map(|(stream, _addr)| stream)))
+    });
+    let served = serve(incoming, state, shutdown).await;
+    std::fs::remove_file(path)?;
+    served
+}
+
+/// Logs to stderr at info by default, overridable through RUST_LOG
+/// (e.g. `RUST_LOG=gpws::access=off` silences access logs).
+fn init_logging(format: options::LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        options::LogFormat::Text => builder.init(),
+        options::LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Number of records in a map file of `file_size` bytes, from its
+/// header if it records one and otherwise estimated from the size.
+fn record_total(rdr: &mut H3TessReader<impl Read>, file_size: u64) -> Result<u64> {
+    let format = rdr.read_header()?;
+    Ok(rdr
+        .record_count()
+        .unwrap_or_else(|| file_size.saturating_sub(rdr.offset()) / format.record_len() as u64))
+}
+
+fn deserialize_hexmap(
+    src_file: File,
+    tolerate_truncation: bool,
+    skip_checksum: bool,
+) -> Result<HexTreeMap<f32>> {
     let file_size = src_file.metadata()?.len();
     let mut map = HexTreeMap::new();
     let mut ret_err: Option<anyhow::Error> = None;
-    let idx_val_pairs_total =
-        file_size / (std::mem::size_of::<u64>() + std::mem::size_of::<f32>()) as u64;
+    let mut rdr = H3TessReader::new(BufReader::new(src_file))
+        .tolerate_truncation(tolerate_truncation)
+        .skip_checksum(skip_checksum);
+    let idx_val_pairs_total = record_total(&mut rdr, file_size)?;
     let idx_val_pairs_processed = AtomicU64::new(0);
 
     {
-        println!("Deserializing hex map");
+        info!("Deserializing hex map");
     }
 
     let hexmap_complete = AtomicBool::new(false);
 
     thread::scope(|s| {
         s.spawn(|| {
-            let mut rdr = BufReader::new(src_file);
-            loop {
-                match (rdr.read_u64::<LE>(), rdr.read_f32::<LE>()) {
-                    (Ok(h3_index), Ok(val)) => {
-                        let cell = H3Cell::try_from(h3_index)
-                            .expect("serialized hexmap should only contain valid indices");
+            for record in rdr.by_ref() {
+                match record {
+                    Ok((cell, val)) => {
                         map.insert(cell, val);
                         idx_val_pairs_processed.fetch_add(1, Ordering::Relaxed);
                     }
-                    (Err(e), _) if e.kind() == ErrorKind::UnexpectedEof => {
-                        hexmap_complete.store(true, Ordering::Relaxed);
-                        break;
-                    }
-                    (Err(e), _) => {
+                    Err(e) => {
                         ret_err = Some(e.into());
-                        hexmap_complete.store(true, Ordering::Relaxed);
                         break;
                     }
-                    (_, Err(e)) => {
-                        ret_err = Some(e.into());
-                        hexmap_complete.store(true, Ordering::Relaxed);
-                        break;
-                    }
-                };
+                }
             }
+            if let Some(partial) = rdr.truncated() {
+                warn!(
+                    "dropped {} trailing bytes of a partial record at offset {}",
+                    partial,
+                    rdr.offset() - partial as u64
+                );
+            }
+            hexmap_complete.store(true, Ordering::Relaxed);
         });
 
         s.spawn(|| {
             let pb = ProgressBar::new(idx_val_pairs_total);
             pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({eta})")
                          .expect("incorrect progress bar format string")
-                         .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+                         .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| { let _ = write!(w, "{:.1}s", state.eta().as_secs_f64()); })
                          .progress_chars("#>-"));
 
             while !hexmap_complete.load(Ordering::Relaxed) && idx_val_pairs_processed.load(Ordering::Relaxed) < idx_val_pairs_total {
@@ -139,3 +229,191 @@ fn deserialize_hexmap(src_file: File) -> Result<HexTreeMap<f32>> {
         Ok(map)
     }
 }
+
+/// Builds a map from `gpwgen tessellate` output, summing complete
+/// sets of children up to `resolution` as `gpwgen combine` would.
+fn combine_hexmap(
+    src_file: File,
+    resolution: u8,
+    tolerate_truncation: bool,
+    skip_checksum: bool,
+) -> Result<HexTreeMap<f32>> {
+    let mut map = HexTreeMap::with_compactor(ReduceCompactor {
+        resolution,
+        mode: ReduceMode::Sum,
+    });
+    let mut rdr = H3TessReader::new(BufReader::new(src_file))
+        .tolerate_truncation(tolerate_truncation)
+        .skip_checksum(skip_checksum);
+    info!("Combining tessellation to resolution {}", resolution);
+    insert_h3tess(&mut map, &mut rdr, |_| Ok(()))?;
+    if let Some(partial) = rdr.truncated() {
+        warn!(
+            "dropped {} trailing bytes of a partial record at offset {}",
+            partial,
+            rdr.offset() - partial as u64
+        );
+    }
+    Ok(map.iter().map(|(cell, (val, _))| (*cell, *val)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{body::Body, client::conn, Request, StatusCode};
+
+    #[test]
+    fn test_record_total() {
+        use gpwgen::h3tess::{Format, H3TessWriter, Quantize, RECORD_LEN};
+        use hextree::h3ron::{FromH3Index, H3Cell};
+
+        let cells = H3Cell::from_h3index(0x851fb467fffffff)
+            .get_children(8)
+            .expect("children");
+        let format = Format {
+            quantize: Some(Quantize::U16 { scale: 10 }),
+            checksum: true,
+            ..Default::default()
+        };
+        let mut wtr = H3TessWriter::with_record_count(Vec::new(), format, cells.count() as u64)
+            .expect("header");
+        for cell in cells.iter() {
+            wtr.write_pair(cell, 1.5).expect("record");
+        }
+        let buf = wtr.finish().expect("finish");
+        assert_ne!(buf.len() / RECORD_LEN, cells.count());
+
+        let mut rdr = H3TessReader::new(buf.as_slice());
+        let total = record_total(&mut rdr, buf.len() as u64).expect("total");
+        assert_eq!(total, cells.count() as u64);
+
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("map.h3tess");
+        std::fs::write(&path, &buf).expect("write");
+        let map = deserialize_hexmap(File::open(&path).expect("open"), false, false).expect("map");
+        assert_eq!(map.len(), cells.count());
+    }
+
+    #[tokio::test]
+    async fn test_combine_hexmap() {
+        use gpwgen::h3tess::H3TessWriter;
+        use hextree::h3ron::{FromH3Index, H3Cell};
+        use hyper::service::Service;
+
+        // Tessellation output: every res 10 child of one res 9 cell and
+        // a single child of its neighbor.
+        let tile = H3Cell::from_h3index(0x851fb467fffffff)
+            .get_children(9)
+            .expect("children");
+        let mut tile = tile.iter();
+        let parent = tile.next().expect("cell");
+        let children = parent.get_children(10).expect("children");
+        let stray = tile
+            .next()
+            .and_then(|cell| cell.get_children(10).ok()?.iter().next())
+            .expect("cell");
+        let mut wtr = H3TessWriter::new(Vec::new());
+        for cell in children.iter() {
+            wtr.write_pair(cell, 2.0).expect("record");
+        }
+        wtr.write_pair(stray, 3.0).expect("record");
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("tile.h3tess");
+        std::fs::write(&path, wtr.finish().expect("finish")).expect("write");
+
+        let map = combine_hexmap(File::open(&path).expect("open"), 9, false, false).expect("map");
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(parent), Some(&14.0));
+        assert_eq!(map.get(stray), Some(&3.0));
+
+        let state = Arc::new(service::State::new(map, 0, service::DEFAULT_TOP_MAX));
+        let mut service = service::GpwService::new(state);
+        let req = Request::get(format!("/{:x}", *parent))
+            .header(hyper::header::ACCEPT, "text/plain")
+            .body(Body::empty())
+            .expect("valid request");
+        let resp = service.call(req).await.expect("infallible");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .expect("in-memory body");
+        let total = std::str::from_utf8(&body).expect("utf-8");
+        assert_eq!(total.parse::<f32>().expect("float"), 14.0);
+    }
+
+    #[test]
+    fn test_worker_threads() {
+        let rt = runtime(NonZeroUsize::new(2)).expect("runtime");
+        assert_eq!(rt.metrics().num_workers(), 2);
+        rt.block_on(async {
+            let incoming = AddrIncoming::bind(&([127, 0, 0, 1], 0).into()).expect("bind");
+            let addr = incoming.local_addr();
+            let state = Arc::new(service::State::new(
+                HexTreeMap::new(),
+                0,
+                service::DEFAULT_TOP_MAX,
+            ));
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let server = tokio::spawn(serve(incoming, state, async {
+                let _ = stopped.await;
+            }));
+
+            let stream = tokio::net::TcpStream::connect(addr).await.expect("connect");
+            let (mut sender, connection) = conn::handshake(stream).await.expect("handshake");
+            let connection = tokio::spawn(connection);
+            let req = Request::get("/metrics")
+                .body(Body::empty())
+                .expect("valid request");
+            let resp = sender.send_request(req).await.expect("response");
+            assert_eq!(resp.status(), StatusCode::OK);
+            drop(sender);
+            connection.await.expect("join").expect("connection");
+
+            stop.send(()).expect("server running");
+            server.await.expect("join").expect("serve");
+        });
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("gpws.sock");
+        let state = Arc::new(service::State::new(
+            HexTreeMap::new(),
+            0,
+            service::DEFAULT_TOP_MAX,
+        ));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move {
+                serve_unix(&path, state, async {
+                    let _ = stopped.await;
+                })
+                .await
+            }
+        });
+
+        // The listener is bound once the socket file shows up.
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        let stream = tokio::net::UnixStream::connect(&path)
+            .await
+            .expect("connect");
+        let (mut sender, connection) = conn::handshake(stream).await.expect("handshake");
+        let connection = tokio::spawn(connection);
+        let req = Request::get("/metrics")
+            .body(Body::empty())
+            .expect("valid request");
+        let resp = sender.send_request(req).await.expect("response");
+        assert_eq!(resp.status(), StatusCode::OK);
+        drop(sender);
+        connection.await.expect("join").expect("connection");
+
+        stop.send(()).expect("server running");
+        server.await.expect("join").expect("serve");
+        assert!(!path.exists());
+    }
+}
@@ -1,9 +1,284 @@
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use gpws::service::check_map_name;
+use serde::Deserialize;
+use std::{collections::BTreeMap, num::NonZeroUsize, path::PathBuf};
 
 /// Serve global word population via H3 cells.
 #[allow(clippy::unwrap_used)]
-#[derive(Parser)]
+#[derive(Parser, Debug)]
 pub struct Cli {
-    /// Path to serialized H3 (cell, population) pairs.
-    pub path: std::path::PathBuf,
+    /// Path to serialized H3 (cell, population) pairs, served at the
+    /// root.
+    #[arg(required_unless_present_any = ["maps", "config"])]
+    pub path: Option<std::path::PathBuf>,
+    /// An additional map served under `/{name}/`, given as
+    /// `name=path`. Repeat for several maps; `/maps` lists them. Maps
+    /// named after a year (e.g. `2015=...`) also feed `/series`.
+    #[arg(long = "map", value_name = "NAME=PATH", value_parser = parse_named_map)]
+    pub maps: Vec<(String, std::path::PathBuf)>,
+    /// Drop, with a warning, a partial record at the end of the map
+    /// file instead of refusing to start.
+    #[arg(long)]
+    pub tolerate_truncation: bool,
+    /// Load the map even if its checksum is missing or doesn't match.
+    #[arg(long)]
+    pub skip_checksum: bool,
+    /// Treat map files as `gpwgen tessellate` output and combine each
+    /// to this resolution at startup, summing as `gpwgen combine`
+    /// does, rather than loading them as finished maps.
+    #[arg(long, value_name = "RES", value_parser = clap::value_parser!(u8).range(0..=15))]
+    pub combine_resolution: Option<u8>,
+    /// Listen on a Unix domain socket at this path instead of TCP. The
+    /// socket file is removed on shutdown.
+    #[cfg(unix)]
+    #[arg(long)]
+    pub unix: Option<std::path::PathBuf>,
+    /// Number of query results to memoize. Zero disables caching.
+    #[arg(long, default_value_t = 65_536)]
+    pub cache_size: usize,
+    /// Resolutions (e.g. `3,4,5`) whose per-cell totals are computed at
+    /// startup, making queries at them a direct lookup at the cost of
+    /// extra memory.
+    #[arg(long, value_delimiter = ',')]
+    pub precompute: Vec<u8>,
+    /// Also precompute totals at this many resolutions just coarser
+    /// than each map's own, so `?res=` queries to `/bbox` and
+    /// `/centroid` that far up are lookups. Unlike `--precompute`, this
+    /// follows every map's resolution.
+    #[arg(long, default_value_t = 0, value_name = "LEVELS")]
+    pub output_resolution_downsample: u8,
+    /// Most cells `/top` lists, ranked when each map is loaded.
+    #[arg(long, default_value_t = gpws::service::DEFAULT_TOP_MAX)]
+    pub top_max: usize,
+    /// Answer requests still running after this many seconds with a
+    /// 503 Service Unavailable.
+    #[arg(long, value_parser = parse_seconds)]
+    pub request_timeout: Option<std::time::Duration>,
+    /// Number of threads handling connections [default: logical core
+    /// count]. Queries themselves run on tokio's separate blocking
+    /// pool.
+    #[arg(long)]
+    pub worker_threads: Option<NonZeroUsize>,
+    /// Log line format. Levels and per-target filters (e.g. the
+    /// `gpws::access` request log) are set through RUST_LOG.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// TOML file setting any of the options above, keyed by their long
+    /// names in snake case (e.g. `cache_size = 1024`), with maps as a
+    /// `[maps]` table of `name = "path"`. Options given on the command
+    /// line take precedence.
+    #[arg(long)]
+    pub config: Option<std::path::PathBuf>,
+}
+
+impl Cli {
+    /// Parses the command line, filling in whatever it leaves out from
+    /// the `--config` file, if any.
+    pub fn load() -> Result<Self> {
+        Self::from_matches(&Self::command().get_matches())
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let mut cli = Self::from_arg_matches(matches)?;
+        if let Some(path) = &cli.config {
+            let config: Config = std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|src| Ok(toml::from_str(&src)?))
+                .with_context(|| path.display().to_string())?;
+            cli.merge(config, matches)?;
+        }
+        if cli.path.is_none() && cli.maps.is_empty() {
+            bail!("no map to serve; give a path or --map");
+        }
+        Ok(cli)
+    }
+
+    /// Takes every option not given on the command line from `config`.
+    fn merge(&mut self, config: Config, matches: &ArgMatches) -> Result<()> {
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        if unset("path") && config.path.is_some() {
+            self.path = config.path;
+        }
+        if unset("maps") && !config.maps.is_empty() {
+            for name in config.maps.keys() {
+                check_map_name(name).map_err(anyhow::Error::msg)?;
+            }
+            self.maps = config.maps.into_iter().collect();
+        }
+        if unset("tolerate_truncation") {
+            self.tolerate_truncation = config.tolerate_truncation.unwrap_or_default();
+        }
+        if unset("skip_checksum") {
+            self.skip_checksum = config.skip_checksum.unwrap_or_default();
+        }
+        if unset("combine_resolution") && config.combine_resolution.is_some() {
+            if config.combine_resolution > Some(15) {
+                bail!("combine_resolution must be from 0 to 15");
+            }
+            self.combine_resolution = config.combine_resolution;
+        }
+        #[cfg(unix)]
+        if unset("unix") && config.unix.is_some() {
+            self.unix = config.unix;
+        }
+        if let Some(cache_size) = config.cache_size.filter(|_| unset("cache_size")) {
+            self.cache_size = cache_size;
+        }
+        if let Some(precompute) = config.precompute.filter(|_| unset("precompute")) {
+            self.precompute = precompute;
+        }
+        if let Some(levels) = config
+            .output_resolution_downsample
+            .filter(|_| unset("output_resolution_downsample"))
+        {
+            self.output_resolution_downsample = levels;
+        }
+        if let Some(top_max) = config.top_max.filter(|_| unset("top_max")) {
+            self.top_max = top_max;
+        }
+        if let Some(secs) = config.request_timeout.filter(|_| unset("request_timeout")) {
+            self.request_timeout = Some(std::time::Duration::try_from_secs_f64(secs)?);
+        }
+        if unset("worker_threads") && config.worker_threads.is_some() {
+            self.worker_threads = config.worker_threads;
+        }
+        if let Some(log_format) = config.log_format.filter(|_| unset("log_format")) {
+            self.log_format = log_format;
+        }
+        Ok(())
+    }
+}
+
+/// Contents of a `--config` file. Every field is optional and mirrors
+/// the [`Cli`] option of the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    path: Option<PathBuf>,
+    #[serde(default)]
+    maps: BTreeMap<String, PathBuf>,
+    tolerate_truncation: Option<bool>,
+    skip_checksum: Option<bool>,
+    combine_resolution: Option<u8>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    unix: Option<PathBuf>,
+    cache_size: Option<usize>,
+    precompute: Option<Vec<u8>>,
+    output_resolution_downsample: Option<u8>,
+    top_max: Option<usize>,
+    /// Seconds, possibly fractional.
+    request_timeout: Option<f64>,
+    worker_threads: Option<NonZeroUsize>,
+    log_format: Option<LogFormat>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Parses a non-negative, possibly fractional, number of seconds.
+fn parse_seconds(arg: &str) -> Result<std::time::Duration, String> {
+    let secs = arg.parse::<f64>().map_err(|e| e.to_string())?;
+    std::time::Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+/// Parses a `name=path` map argument, checking the name is usable as
+/// a path prefix.
+fn parse_named_map(arg: &str) -> Result<(String, std::path::PathBuf), String> {
+    let (name, path) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=path, got {:?}", arg))?;
+    gpws::service::check_map_name(name)?;
+    Ok((name.to_string(), path.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `args` after writing `config` to a file passed with
+    /// `--config`.
+    fn parse(config: &str, args: &[&str]) -> Result<Cli> {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("gpws.toml");
+        std::fs::write(&path, config).expect("write config");
+        let config_arg = format!("--config={}", path.display());
+        let matches = Cli::command().try_get_matches_from(
+            ["gpws", config_arg.as_str()]
+                .into_iter()
+                .chain(args.iter().copied()),
+        )?;
+        Cli::from_matches(&matches)
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = r#"
+path = "world.h3idx"
+cache_size = 1024
+precompute = [3, 4]
+top_max = 10
+request_timeout = 1.5
+log_format = "json"
+
+[maps]
+2015 = "2015.h3idx"
+2020 = "2020.h3idx"
+"#;
+        // File values fill in every option left unset.
+        let cli = parse(config, &[]).expect("valid config");
+        assert_eq!(cli.path, Some(PathBuf::from("world.h3idx")));
+        assert_eq!(
+            cli.maps,
+            vec![
+                ("2015".to_string(), PathBuf::from("2015.h3idx")),
+                ("2020".to_string(), PathBuf::from("2020.h3idx")),
+            ]
+        );
+        assert_eq!(cli.cache_size, 1024);
+        assert_eq!(cli.precompute, vec![3, 4]);
+        assert_eq!(cli.top_max, 10);
+        assert_eq!(
+            cli.request_timeout,
+            Some(std::time::Duration::from_millis(1500))
+        );
+        assert_eq!(cli.log_format, LogFormat::Json);
+        // Left out of both, so the default.
+        assert_eq!(cli.output_resolution_downsample, 0);
+
+        // Command line flags win.
+        let cli = parse(
+            config,
+            &["other.h3idx", "--cache-size=7", "--map", "2000=2000.h3idx"],
+        )
+        .expect("valid config and flags");
+        assert_eq!(cli.path, Some(PathBuf::from("other.h3idx")));
+        assert_eq!(cli.cache_size, 7);
+        assert_eq!(
+            cli.maps,
+            vec![("2000".to_string(), PathBuf::from("2000.h3idx"))]
+        );
+        assert_eq!(cli.top_max, 10);
+    }
+
+    #[test]
+    fn test_config_file_errors() {
+        // Map names are checked like --map's.
+        let err = parse("[maps]\nbbox = \"a.h3idx\"\n", &[]).expect_err("invalid config");
+        assert!(err.to_string().contains("clashes"), "{:#}", err);
+        let err = parse("[maps]\n\"a/b\" = \"a.h3idx\"\n", &[]).expect_err("invalid config");
+        assert!(err.to_string().contains("must be"), "{:#}", err);
+
+        let err = parse("path = \"a.h3idx\"\ncache = 1\n", &[]).expect_err("invalid config");
+        assert!(format!("{:#}", err).contains("unknown field"), "{:#}", err);
+
+        assert!(parse("cache_size = 1\n", &[]).is_err());
+        assert!(parse("path = \"a.h3idx\"\ncombine_resolution = 16\n", &[]).is_err());
+    }
 }
@@ -0,0 +1,2273 @@
+use geo::{coord, BooleanOps, LineString, MapCoords, Rect};
+use gpwgen::{
+    area::{cell_area_km2, cells_area_km2, density},
+    combine::ReduceMode,
+    error::GpwError,
+    stats::Centroid,
+};
+use hextree::{
+    h3ron::{self, H3Cell, Index, ToCoordinate, ToPolygon},
+    HexTreeMap,
+};
+use hyper::{
+    body::Body,
+    header::{HeaderValue, ACCEPT, CONTENT_TYPE},
+    Request, Response, StatusCode,
+};
+use lru::LruCache;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    convert::TryFrom,
+    future::Future,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// Everything a request handler needs, shared across connections.
+///
+/// Maps never change under a `State`, so cached populations stay
+/// valid for its whole life. Loading a new map means building a new
+/// `State`, which starts with empty caches.
+pub struct State {
+    /// The map served at the root, if any.
+    default: Option<Dataset>,
+    /// Maps served under a `/{name}/` path prefix.
+    named: BTreeMap<String, Dataset>,
+    /// Number of query results memoized per map.
+    cache_size: usize,
+    /// Requests taking longer than this are answered with a 503.
+    request_timeout: Option<Duration>,
+    /// Most cells `/top` will list.
+    top_max: usize,
+}
+
+/// Paths that can't be used as map names since they'd shadow an
+/// endpoint.
+pub const RESERVED_NAMES: &[&str] = &[
+    "metrics", "maps", "series", "bbox", "index", "area", "boundary", "children", "parent", "top",
+    "centroid", "count", "cells",
+];
+
+/// Most cells a box query such as `/cells` or `/bbox` may cover.
+pub const CELLS_MAX: usize = 100_000;
+
+/// Cells per chunk of a streamed `/cells` body.
+const CELLS_CHUNK: usize = 1024;
+
+/// Default for the most cells `/top` lists.
+pub const DEFAULT_TOP_MAX: usize = 1000;
+
+/// Checks that `name` can be used as a map's path prefix.
+pub fn check_map_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "map name {:?} must be letters, digits, '-' or '_'",
+            name
+        ));
+    }
+    if RESERVED_NAMES.contains(&name) || parse_cell(name).is_some() {
+        return Err(format!("map name {:?} clashes with an endpoint", name));
+    }
+    Ok(())
+}
+
+impl State {
+    /// Serves `map` at the root, memoizing up to `cache_size` query
+    /// results per map. A size of zero disables caching. Every map
+    /// ranks its `top_max` most populated cells for `/top` when loaded.
+    pub fn new(map: HexTreeMap<f32>, cache_size: usize, top_max: usize) -> Self {
+        Self {
+            default: Some(Dataset::new(map, cache_size, top_max)),
+            ..Self::empty(cache_size, top_max)
+        }
+    }
+
+    /// Serves no map at the root, only those added with
+    /// [`Self::named_map`].
+    pub fn empty(cache_size: usize, top_max: usize) -> Self {
+        Self {
+            default: None,
+            named: BTreeMap::new(),
+            cache_size,
+            request_timeout: None,
+            top_max,
+        }
+    }
+
+    /// Serves `map` under `/{name}/`, replacing any map of the same
+    /// name. See [`check_map_name`] for valid names.
+    pub fn named_map(mut self, name: String, map: HexTreeMap<f32>) -> Self {
+        self.named
+            .insert(name, Dataset::new(map, self.cache_size, self.top_max));
+        self
+    }
+
+    /// Answers requests not done within `limit` with a 503.
+    pub fn request_timeout(mut self, limit: Option<Duration>) -> Self {
+        self.request_timeout = limit;
+        self
+    }
+
+    /// Precomputes the total population of every populated cell at
+    /// each of `resolutions`, in every map.
+    ///
+    /// Map cells coarser than a precomputed resolution are left out;
+    /// queries under them fall back to [`HexTreeMap::reduce`].
+    pub fn precompute(mut self, resolutions: &[u8]) -> Self {
+        for dataset in self.default.iter_mut().chain(self.named.values_mut()) {
+            dataset.precompute(resolutions);
+        }
+        self
+    }
+
+    /// Precomputes totals at each of the `levels` resolutions just
+    /// coarser than every map's own, so `?res=` queries a few levels
+    /// up are lookups too.
+    pub fn downsample(mut self, levels: u8) -> Self {
+        for dataset in self.default.iter_mut().chain(self.named.values_mut()) {
+            let resolutions = (1..=levels)
+                .filter_map(|level| dataset.resolution.checked_sub(level))
+                .collect::<Vec<_>>();
+            dataset.precompute(&resolutions);
+        }
+        self
+    }
+
+    fn datasets(&self) -> impl Iterator<Item = &Dataset> {
+        self.default.iter().chain(self.named.values())
+    }
+
+    /// Splits a request path into the map it addresses and the rest
+    /// of the path, which keeps its leading `/`. Paths not starting
+    /// with a map's name address the default map.
+    fn dataset<'a>(&self, path: &'a str) -> Option<(&Dataset, &'a str)> {
+        let prefix = path[1..].split('/').next().unwrap_or_default();
+        match self.named.get(prefix) {
+            Some(dataset) => Some((dataset, &path[1 + prefix.len()..])),
+            None => Some((self.default.as_ref()?, path)),
+        }
+    }
+
+    /// Renders counters, summed over all maps, in the Prometheus text
+    /// format.
+    fn metrics(&self) -> String {
+        let (hits, misses) = self.datasets().fold((0, 0), |(hits, misses), dataset| {
+            (
+                hits + dataset.cache_hits.load(Ordering::Relaxed),
+                misses + dataset.cache_misses.load(Ordering::Relaxed),
+            )
+        });
+        format!(
+            "gpws_cache_hits_total {}\ngpws_cache_misses_total {}\n",
+            hits, misses
+        )
+    }
+}
+
+/// A loaded map along with the query caches built over it.
+pub struct Dataset {
+    pub map: HexTreeMap<f32>,
+    /// Finest resolution of any cell in `map`.
+    resolution: u8,
+    /// Reduced populations keyed by H3 index. There is only one
+    /// reduction (sum), so the index alone identifies a result.
+    cache: Option<Mutex<LruCache<u64, f32>>>,
+    /// Per-resolution totals computed at load time, so coarse queries
+    /// are a lookup instead of a walk over every descendant.
+    precomputed: HashMap<u8, HashMap<u64, f32>>,
+    /// The most populated map cells, most populated first, as listed
+    /// by `/top`.
+    top: Vec<(H3Cell, f32)>,
+    /// The map's populated cells, each counting one, built by the
+    /// first `/count` query.
+    populated: OnceLock<HexTreeMap<u32>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Dataset {
+    fn new(map: HexTreeMap<f32>, cache_size: usize, top_max: usize) -> Self {
+        let top = top_cells(&map, top_max);
+        let resolution = map
+            .iter()
+            .map(|(cell, _)| cell.resolution())
+            .max()
+            .unwrap_or(0);
+        Self {
+            map,
+            resolution,
+            cache: NonZeroUsize::new(cache_size).map(|size| Mutex::new(LruCache::new(size))),
+            precomputed: HashMap::new(),
+            top,
+            populated: OnceLock::new(),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+        }
+    }
+
+    fn precompute(&mut self, resolutions: &[u8]) {
+        for &res in resolutions {
+            let mut totals = HashMap::new();
+            for (cell, val) in self.map.iter() {
+                if cell.resolution() < res {
+                    continue;
+                }
+                if let Ok(parent) = cell.get_parent(res) {
+                    *totals.entry(*parent).or_insert(0.0) += *val;
+                }
+            }
+            self.precomputed.insert(res, totals);
+        }
+    }
+
+    /// Returns the summed population of `cell` and its descendants.
+    fn population(&self, cell: H3Cell) -> Option<f32> {
+        if let Some(pop) = self
+            .precomputed
+            .get(&cell.resolution())
+            .and_then(|totals| totals.get(&*cell))
+        {
+            return Some(*pop);
+        }
+        let reduce = || reduce_cell(&self.map, cell, ReduceMode::Sum);
+        let Some(cache) = &self.cache else {
+            return reduce();
+        };
+        // A poisoned cache only means another request panicked
+        // mid-update, so fall back to computing the value.
+        if let Some(pop) = cache.lock().ok().and_then(|mut c| c.get(&*cell).copied()) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(pop);
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        let pop = reduce()?;
+        if let Ok(mut cache) = cache.lock() {
+            cache.put(*cell, pop);
+        }
+        Some(pop)
+    }
+
+    /// Returns the populated cell closest to `cell` within `max_ring`
+    /// grid rings, with its ring distance and population. `cell`
+    /// itself is ring 0. Ties go to the lowest index.
+    fn nearest(&self, cell: H3Cell, max_ring: u32) -> Option<(H3Cell, u32, f32)> {
+        let mut candidates = cell.grid_disk_distances(0, max_ring).ok()?;
+        candidates.sort_unstable_by_key(|(distance, cell)| (*distance, **cell));
+        candidates
+            .into_iter()
+            .find_map(|(distance, cell)| Some((cell, distance, self.population(cell)?)))
+    }
+
+    /// Returns the summed population of the cells at `res` whose
+    /// centers fall inside a lat/lng box, along with the summed area of
+    /// those cells in km². See [`bbox_cells`] for which cells are
+    /// included.
+    fn bbox_population(
+        &self,
+        bbox: BBox,
+        res: u8,
+        deadline: Option<Instant>,
+    ) -> Result<(f32, f64), BBoxError> {
+        let (mut total, mut area) = (0.0, 0.0);
+        for cell in bbox_cells(bbox, res, deadline)? {
+            check_deadline(deadline)?;
+            total += self.population(cell).unwrap_or(0.0);
+            area += cell_area_km2(cell)?;
+        }
+        Ok((total, area))
+    }
+
+    /// Returns how many map cells at or under `cell` have a nonzero
+    /// population. Cells missing from the map, such as those that were
+    /// NODATA in the source grid, count the same as zeros: not at all.
+    fn populated_cells(&self, cell: H3Cell) -> u32 {
+        let populated = self.populated.get_or_init(|| {
+            let mut populated = HexTreeMap::new();
+            for (cell, pop) in self.map.iter() {
+                if *pop > 0.0 {
+                    populated.insert(*cell, 1);
+                }
+            }
+            populated
+        });
+        populated
+            .reduce(cell, |_resolution, counts| counts.iter().sum())
+            .unwrap_or(0)
+    }
+
+    /// Returns the centroid of the centers of the cells at `res` inside
+    /// a lat/lng box, weighted by population.
+    fn bbox_centroid(
+        &self,
+        bbox: BBox,
+        res: u8,
+        deadline: Option<Instant>,
+    ) -> Result<Centroid, BBoxError> {
+        let mut centroid = Centroid::new();
+        for cell in bbox_cells(bbox, res, deadline)? {
+            check_deadline(deadline)?;
+            if let Some(pop) = self.population(cell) {
+                let center = cell.to_coordinate()?;
+                centroid.push(center.y, center.x, f64::from(pop));
+            }
+        }
+        Ok(centroid)
+    }
+}
+
+/// Why the cells inside a box weren't listed.
+#[derive(Debug)]
+enum BBoxError {
+    /// The box covers about `count` cells at `res`, more than
+    /// [`CELLS_MAX`].
+    TooManyCells {
+        count: usize,
+        res: u8,
+    },
+    /// The request's deadline passed first.
+    TimedOut,
+    Gpw(GpwError),
+}
+
+impl BBoxError {
+    /// The response for a request this ended.
+    fn response(self) -> Response<Body> {
+        match self {
+            BBoxError::TooManyCells { count, res } => status_response(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "box covers about {} cells at res {}, more than the limit of {}\n",
+                    count, res, CELLS_MAX
+                ),
+            ),
+            BBoxError::TimedOut => timed_out(),
+            BBoxError::Gpw(e) => status_response(StatusCode::BAD_REQUEST, format!("{:?}\n", e)),
+        }
+    }
+}
+
+/// Fails once `deadline` has passed, so loops over many cells give up
+/// instead of running on after the client got its 503.
+fn check_deadline(deadline: Option<Instant>) -> Result<(), BBoxError> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(BBoxError::TimedOut),
+        _ => Ok(()),
+    }
+}
+
+impl From<GpwError> for BBoxError {
+    fn from(e: GpwError) -> Self {
+        BBoxError::Gpw(e)
+    }
+}
+
+impl From<h3ron::Error> for BBoxError {
+    fn from(e: h3ron::Error) -> Self {
+        BBoxError::Gpw(e.into())
+    }
+}
+
+/// Returns the cells at `resolution` whose centers fall inside a
+/// lat/lng box. A box whose `min_lng` is east of its `max_lng` is
+/// taken to cross the antimeridian. Boxes covering more than
+/// [`CELLS_MAX`] cells are refused, so no endpoint walks an unbounded
+/// number of cells.
+fn bbox_cells(
+    bbox: BBox,
+    resolution: u8,
+    deadline: Option<Instant>,
+) -> Result<Vec<H3Cell>, BBoxError> {
+    // Listing a planet's worth of cells just to count them would be
+    // the very cost the limit guards against, so estimate first.
+    let center = coord! {x: bbox.min_lng, y: (bbox.min_lat + bbox.max_lat) / 2.0};
+    let estimate = bbox.area_km2() / cell_area_km2(H3Cell::from_coordinate(center, resolution)?)?;
+    if estimate > 2.0 * CELLS_MAX as f64 {
+        return Err(BBoxError::TooManyCells {
+            count: estimate as usize,
+            res: resolution,
+        });
+    }
+    let BBox {
+        min_lat,
+        min_lng,
+        max_lat,
+        max_lng,
+    } = bbox;
+    let rects = if min_lng > max_lng {
+        vec![(min_lng, 180.0), (-180.0, max_lng)]
+    } else {
+        vec![(min_lng, max_lng)]
+    };
+    let mut cells = Vec::new();
+    for (west, east) in rects {
+        check_deadline(deadline)?;
+        let rect = Rect::new(coord! {x: west, y: min_lat}, coord! {x: east, y: max_lat});
+        cells.extend(h3ron::polygon_to_cells(&rect.to_polygon(), resolution)?.iter());
+    }
+    if cells.len() > CELLS_MAX {
+        return Err(BBoxError::TooManyCells {
+            count: cells.len(),
+            res: resolution,
+        });
+    }
+    Ok(cells)
+}
+
+/// Returns up to `n` cells of `map` by descending population, ties
+/// going to the lowest index.
+fn top_cells(map: &HexTreeMap<f32>, n: usize) -> Vec<(H3Cell, f32)> {
+    let mut cells = map
+        .iter()
+        .map(|(cell, pop)| (*cell, *pop))
+        .collect::<Vec<_>>();
+    let by_rank =
+        |a: &(H3Cell, f32), b: &(H3Cell, f32)| b.1.total_cmp(&a.1).then((*a.0).cmp(&*b.0));
+    if n < cells.len() {
+        cells.select_nth_unstable_by(n, by_rank);
+        cells.truncate(n);
+    }
+    cells.sort_unstable_by(by_rank);
+    cells
+}
+
+/// Returns the values of `index` and its descendants in `map`,
+/// combined by `reduce`, or `None` if none are present.
+///
+/// This is the lookup behind the HTTP API without any of its routing,
+/// caching or hyper types. It runs to completion when polled, so
+/// callers worried about huge reductions should run it off their
+/// executor threads.
+pub async fn handle_query(map: &HexTreeMap<f32>, index: H3Cell, reduce: ReduceMode) -> Option<f32> {
+    reduce_cell(map, index, reduce)
+}
+
+fn reduce_cell(map: &HexTreeMap<f32>, cell: H3Cell, mode: ReduceMode) -> Option<f32> {
+    map.reduce(cell, |_resolution, cells| mode.apply(cells))
+}
+
+/// The HTTP API as a `tower::Service` (through hyper's re-export of
+/// the trait), for mounting in tower or axum stacks.
+#[derive(Clone)]
+pub struct GpwService {
+    state: Arc<State>,
+}
+
+impl GpwService {
+    pub fn new(state: Arc<State>) -> Self {
+        Self { state }
+    }
+}
+
+impl hyper::service::Service<Request<Body>> for GpwService {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        Box::pin(handle(self.state.clone(), req))
+    }
+}
+
+/// Serves `req`, logging method, path, status and latency for it.
+pub async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    // Both are cheap reference-counted clones.
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    // Route on the blocking pool so a slow query can be abandoned
+    // without stalling the connection's executor thread. Handlers that
+    // walk many cells also stop by themselves at the deadline.
+    let limit = state.request_timeout;
+    let deadline = limit.map(|limit| start + limit);
+    let work = tokio::task::spawn_blocking(move || route(&state, req, deadline));
+    let resp = with_timeout(limit, async {
+        work.await.unwrap_or_else(|_| {
+            status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "handler panicked\n".to_string(),
+            )
+        })
+    })
+    .await;
+    info!(
+        target: "gpws::access",
+        method = %method,
+        path = uri.path(),
+        status = resp.status().as_u16(),
+        latency_us = start.elapsed().as_micros() as u64,
+        "request"
+    );
+    Ok(resp)
+}
+
+/// Resolves `fut`, or gives up with a 503 once `limit` passes. The
+/// abandoned work is not interrupted, only no longer waited on, so
+/// long handlers also check the deadline themselves.
+async fn with_timeout(
+    limit: Option<Duration>,
+    fut: impl Future<Output = Response<Body>>,
+) -> Response<Body> {
+    let Some(limit) = limit else {
+        return fut.await;
+    };
+    tokio::time::timeout(limit, fut)
+        .await
+        .unwrap_or_else(|_| timed_out())
+}
+
+fn timed_out() -> Response<Body> {
+    status_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "request timed out\n".to_string(),
+    )
+}
+
+fn route(state: &Arc<State>, req: Request<Body>, deadline: Option<Instant>) -> Response<Body> {
+    let Some(repr) = negotiate(&req) else {
+        return status_response(
+            StatusCode::NOT_ACCEPTABLE,
+            "supported types are application/json and text/plain\n".to_string(),
+        );
+    };
+    let query = req.uri().query().unwrap_or_default();
+    let Some(rounding) = Rounding::from_query(query) else {
+        return status_response(
+            StatusCode::BAD_REQUEST,
+            "round must be true or false\n".to_string(),
+        );
+    };
+    let Some(nearest) = bool_param(query, "nearest") else {
+        return status_response(
+            StatusCode::BAD_REQUEST,
+            "nearest must be true or false\n".to_string(),
+        );
+    };
+    let Some(measure) = Measure::from_query(query) else {
+        return status_response(
+            StatusCode::BAD_REQUEST,
+            "per_area must be true or false\n".to_string(),
+        );
+    };
+    match req.uri().path() {
+        "/metrics" => Response::new(Body::from(state.metrics())),
+        "/maps" => maps(state, repr),
+        "/index" => index(repr, query),
+        path if path.starts_with("/area/") => area(repr, &path["/area/".len()..]),
+        path if path.starts_with("/series/") => {
+            series(state, repr, rounding, measure, &path["/series/".len()..])
+        }
+        full_path => {
+            let Some((dataset, path)) = state.dataset(full_path) else {
+                return not_found();
+            };
+            match path {
+                "" => not_found(),
+                "/bbox" => bbox(dataset, repr, rounding, measure, query, deadline),
+                "/count" => bbox_count(dataset, repr, query, deadline),
+                "/cells" => {
+                    // The streamed body outlives this borrow of the
+                    // dataset, so it's looked up again by prefix.
+                    let prefix = full_path[..full_path.len() - path.len()].to_string();
+                    cells(
+                        state.clone(),
+                        dataset,
+                        prefix,
+                        repr,
+                        rounding,
+                        query,
+                        deadline,
+                    )
+                }
+                "/centroid" => centroid(dataset, repr, rounding, query, deadline),
+                "/top" => top(dataset, repr, rounding, query),
+                path => match path[1..].split_once('/') {
+                    Some(("boundary", hex)) => boundary(dataset, rounding, measure, hex),
+                    Some(("count", hex)) => count(dataset, repr, hex),
+                    Some(("children", hex)) => children(dataset, repr, rounding, measure, hex),
+                    Some(("parent", hex)) => parent(dataset, repr, rounding, measure, hex),
+                    _ => lookup(dataset, repr, rounding, measure, nearest, &path[1..]),
+                },
+            }
+        }
+    }
+}
+
+/// Whether populations are reported as whole numbers (`?round=true`)
+/// or as the raw summed float, the default, which keeps full
+/// precision for clients that aggregate further.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rounding(bool);
+
+impl Rounding {
+    fn from_query(query: &str) -> Option<Self> {
+        bool_param(query, "round").map(Self)
+    }
+
+    /// Rounds half away from zero, so `2.5` becomes `3`.
+    fn json(self, pop: f32) -> serde_json::Value {
+        if self.0 {
+            json!(pop.round() as i64)
+        } else {
+            json!(pop)
+        }
+    }
+
+    fn text(self, pop: f32) -> String {
+        if self.0 {
+            format!("{}", pop.round() as i64)
+        } else {
+            format!("{:?}", pop)
+        }
+    }
+}
+
+/// Whether cells report their population, the default, or with
+/// `?per_area=true` their population per km² of cell area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Measure {
+    Population,
+    Density,
+}
+
+impl Measure {
+    fn from_query(query: &str) -> Option<Self> {
+        bool_param(query, "per_area").map(|per_area| {
+            if per_area {
+                Self::Density
+            } else {
+                Self::Population
+            }
+        })
+    }
+
+    /// The JSON key values are reported under.
+    fn key(self) -> &'static str {
+        match self {
+            Self::Population => "population",
+            Self::Density => "density",
+        }
+    }
+
+    /// Returns the measure of `cell` and its descendants, or `None` if
+    /// none are populated.
+    fn of(self, dataset: &Dataset, cell: H3Cell) -> Option<f32> {
+        let pop = dataset.population(cell)?;
+        match self {
+            Self::Population => Some(pop),
+            Self::Density => Some(density(pop, cell_area_km2(cell).ok()?)),
+        }
+    }
+
+    /// Returns the measure of a population spread over `area_km2`.
+    fn of_total(self, pop: f32, area_km2: f64) -> f32 {
+        match self {
+            Self::Population => pop,
+            Self::Density => density(pop, area_km2),
+        }
+    }
+}
+
+/// Parses an optional `key=true|false` query parameter, defaulting to
+/// `false`. Returns `None` for any other value.
+fn bool_param(query: &str, key: &str) -> Option<bool> {
+    let value = query.split('&').find_map(|pair| {
+        pair.strip_prefix(key)
+            .and_then(|rest| rest.strip_prefix('='))
+    });
+    match value {
+        None | Some("false") => Some(false),
+        Some("true") => Some(true),
+        Some(_) => None,
+    }
+}
+
+/// How a response body is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repr {
+    /// A structured `application/json` body.
+    Json,
+    /// The bare value as `text/plain`.
+    Text,
+    /// One JSON value per line as `application/x-ndjson`. Endpoints
+    /// listing many values put each on its own line.
+    Ndjson,
+}
+
+impl Repr {
+    fn content_type(self) -> &'static str {
+        match self {
+            Repr::Json => "application/json",
+            Repr::Text => "text/plain; charset=utf-8",
+            Repr::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+/// Picks a representation from the `Accept` header. The first media
+/// range we can serve wins; q-values are not considered. A missing
+/// header is treated as `*/*`, which means JSON.
+fn negotiate(req: &Request<Body>) -> Option<Repr> {
+    let Some(accept) = req.headers().get(ACCEPT) else {
+        return Some(Repr::Json);
+    };
+    accept.to_str().ok()?.split(',').find_map(|range| {
+        match range.split(';').next().unwrap_or_default().trim() {
+            "application/json" | "application/*" | "*/*" => Some(Repr::Json),
+            "text/plain" | "text/*" => Some(Repr::Text),
+            "application/x-ndjson" => Some(Repr::Ndjson),
+            _ => None,
+        }
+    })
+}
+
+/// Builds a `200 OK` response carrying either `json` or `text`,
+/// whichever `repr` asks for, with a matching content type.
+fn reply(repr: Repr, json: serde_json::Value, text: impl FnOnce() -> String) -> Response<Body> {
+    let body = match repr {
+        Repr::Json => json.to_string(),
+        Repr::Text => text(),
+        Repr::Ndjson => format!("{}\n", json),
+    };
+    let mut resp = Response::new(Body::from(body));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(repr.content_type()));
+    resp
+}
+
+/// Parses a hex H3 index, rejecting anything that isn't a valid cell.
+fn parse_cell(hex: &str) -> Option<H3Cell> {
+    u64::from_str_radix(hex, 16)
+        .ok()
+        .and_then(|index| H3Cell::try_from(index).ok())
+}
+
+fn not_found() -> Response<Body> {
+    let mut not_found = Response::default();
+    *not_found.status_mut() = StatusCode::NOT_FOUND;
+    not_found
+}
+
+/// `GET /maps`: names of the maps served under a path prefix, in
+/// sorted order, one per line as text.
+fn maps(state: &State, repr: Repr) -> Response<Body> {
+    let names = state.named.keys().collect::<Vec<_>>();
+    reply(repr, json!(names), || {
+        names.iter().map(|name| format!("{}\n", name)).collect()
+    })
+}
+
+/// `GET /series/{index}`: population of a cell in every map named
+/// after a year (e.g. `--map 2015=...`), oldest first. Years where the
+/// cell is unpopulated are `null` in JSON and left out of the
+/// `<year> <population>` text lines.
+fn series(
+    state: &State,
+    repr: Repr,
+    rounding: Rounding,
+    measure: Measure,
+    hex: &str,
+) -> Response<Body> {
+    let Some(cell) = parse_cell(hex) else {
+        return not_found();
+    };
+    let mut years = state
+        .named
+        .iter()
+        .filter_map(|(name, dataset)| Some((name.parse::<i32>().ok()?, dataset)))
+        .map(|(year, dataset)| (year, measure.of(dataset, cell)))
+        .collect::<Vec<_>>();
+    years.sort_unstable_by_key(|(year, _)| *year);
+    let json = years
+        .iter()
+        .map(|(year, pop)| json!({"year": year, measure.key(): pop.map(|pop| rounding.json(pop))}))
+        .collect();
+    reply(repr, json, || {
+        years
+            .iter()
+            .filter_map(|(year, pop)| Some(format!("{} {}\n", year, rounding.text((*pop)?))))
+            .collect()
+    })
+}
+
+/// `GET /{index}`: population of a cell and its descendants.
+///
+/// With `?nearest=true`, a miss instead answers with the closest
+/// populated cell within [`NEAREST_MAX_RING`] rings, along with its
+/// ring distance, as JSON or `<index> <population> <distance>` text.
+fn lookup(
+    dataset: &Dataset,
+    repr: Repr,
+    rounding: Rounding,
+    measure: Measure,
+    nearest: bool,
+    hex: &str,
+) -> Response<Body> {
+    let Some(cell) = parse_cell(hex) else {
+        return not_found();
+    };
+    if nearest {
+        return match dataset.nearest(cell, NEAREST_MAX_RING) {
+            Some((found, distance, pop)) => {
+                let val = measure.of_total(pop, cell_area_km2(found).unwrap_or_default());
+                reply(
+                    repr,
+                    json!({
+                        "index": format!("{:x}", *found),
+                        measure.key(): rounding.json(val),
+                        "distance": distance,
+                    }),
+                    || format!("{:x} {} {}", *found, rounding.text(val), distance),
+                )
+            }
+            None => not_found(),
+        };
+    }
+    match measure.of(dataset, cell) {
+        Some(pop) => reply(
+            repr,
+            json!({"index": format!("{:x}", *cell), measure.key(): rounding.json(pop)}),
+            || rounding.text(pop),
+        ),
+        None => not_found(),
+    }
+}
+
+/// Furthest ring `?nearest=true` searches. Ring `k` holds `6k` cells,
+/// so this bounds a miss to a few hundred lookups.
+const NEAREST_MAX_RING: u32 = 10;
+
+/// `GET /children/{index}`: populations of a cell's direct children,
+/// one resolution down. Unpopulated children are `null` in JSON and
+/// left out of the `<index> <population>` text lines.
+fn children(
+    dataset: &Dataset,
+    repr: Repr,
+    rounding: Rounding,
+    measure: Measure,
+    hex: &str,
+) -> Response<Body> {
+    let children = parse_cell(hex)
+        .filter(|cell| cell.resolution() < 15)
+        .and_then(|cell| cell.get_children(cell.resolution() + 1).ok());
+    let Some(children) = children else {
+        return not_found();
+    };
+    let populations = children
+        .iter()
+        .map(|child| (child, measure.of(dataset, child)))
+        .collect::<Vec<_>>();
+    let json = populations
+        .iter()
+        .map(|(child, pop)| {
+            json!({
+                "index": format!("{:x}", **child),
+                measure.key(): pop.map(|pop| rounding.json(pop)),
+            })
+        })
+        .collect();
+    reply(repr, json, || {
+        populations
+            .iter()
+            .filter_map(|(child, pop)| Some(format!("{:x} {}\n", **child, rounding.text((*pop)?))))
+            .collect()
+    })
+}
+
+/// `GET /parent/{index}`: population of a cell's parent, one
+/// resolution up.
+fn parent(
+    dataset: &Dataset,
+    repr: Repr,
+    rounding: Rounding,
+    measure: Measure,
+    hex: &str,
+) -> Response<Body> {
+    let parent = parse_cell(hex)
+        .filter(|cell| cell.resolution() > 0)
+        .and_then(|cell| cell.get_parent(cell.resolution() - 1).ok());
+    match parent.and_then(|parent| Some((parent, measure.of(dataset, parent)?))) {
+        Some((parent, pop)) => reply(
+            repr,
+            json!({"index": format!("{:x}", *parent), measure.key(): rounding.json(pop)}),
+            || rounding.text(pop),
+        ),
+        None => not_found(),
+    }
+}
+
+/// `GET /bbox?min_lat=..&min_lng=..&max_lat=..&max_lng=..`: population
+/// of the cells inside a box. With `?per_area=true`, that population
+/// over the cells' summed area. An optional `res=..` at or coarser
+/// than the map's resolution sums coarser cells instead, which is
+/// cheaper for large boxes.
+fn bbox(
+    dataset: &Dataset,
+    repr: Repr,
+    rounding: Rounding,
+    measure: Measure,
+    query: &str,
+    deadline: Option<Instant>,
+) -> Response<Body> {
+    let (res, query) = match split_res(dataset, query) {
+        Ok(split) => split,
+        Err(msg) => return status_response(StatusCode::BAD_REQUEST, msg),
+    };
+    match BBox::from_query(&query) {
+        Some(bbox) => match dataset.bbox_population(bbox, res, deadline) {
+            Ok((pop, area)) => {
+                let val = measure.of_total(pop, area);
+                reply(repr, json!({measure.key(): rounding.json(val)}), || {
+                    rounding.text(val)
+                })
+            }
+            Err(e) => e.response(),
+        },
+        None => status_response(
+            StatusCode::BAD_REQUEST,
+            "expected min_lat, min_lng, max_lat and max_lng query parameters\n".to_string(),
+        ),
+    }
+}
+
+/// `GET /cells?bbox=min_lng,min_lat,max_lng,max_lat&res=..`: every
+/// populated cell at `res` inside a box, with its population, as a
+/// JSON array, NDJSON or `<index> <population>` text lines.
+///
+/// The body is streamed in chunks as populations are looked up. Boxes
+/// covering more than [`CELLS_MAX`] cells are refused up front, and a
+/// body still streaming at `deadline` is aborted.
+fn cells(
+    state: Arc<State>,
+    dataset: &Dataset,
+    prefix: String,
+    repr: Repr,
+    rounding: Rounding,
+    query: &str,
+    deadline: Option<Instant>,
+) -> Response<Body> {
+    let params = query_values(query, ["bbox", "res"])
+        .and_then(|[bbox, res]| Some((BBox::from_lng_lat_list(bbox)?, res.parse::<u8>().ok()?)));
+    let Some((bbox, res)) = params else {
+        return status_response(
+            StatusCode::BAD_REQUEST,
+            "expected bbox=min_lng,min_lat,max_lng,max_lat and res query parameters\n".to_string(),
+        );
+    };
+    if let Err(msg) = check_res(dataset, res) {
+        return status_response(StatusCode::BAD_REQUEST, msg);
+    }
+    let cells = match bbox_cells(bbox, res, deadline) {
+        Ok(cells) => cells,
+        Err(e) => return e.response(),
+    };
+
+    let (mut tx, body) = Body::channel();
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let path = format!("{}/cells", prefix);
+        let Some((dataset, _)) = state.dataset(&path) else {
+            return;
+        };
+        let mut first = true;
+        let send = |tx: &mut hyper::body::Sender, chunk: String| {
+            handle.block_on(tx.send_data(chunk.into())).is_ok()
+        };
+        if repr == Repr::Json && !send(&mut tx, "[".to_string()) {
+            return;
+        }
+        for batch in cells.chunks(CELLS_CHUNK) {
+            if check_deadline(deadline).is_err() {
+                // Tells the client the body is cut short, unlike
+                // just ending it.
+                tx.abort();
+                return;
+            }
+            let mut chunk = String::new();
+            for cell in batch {
+                let Some(pop) = dataset.population(*cell) else {
+                    continue;
+                };
+                match repr {
+                    Repr::Json | Repr::Ndjson => {
+                        if repr == Repr::Json && !first {
+                            chunk.push(',');
+                        }
+                        chunk.push_str(
+                            &json!({"index": format!("{:x}", **cell), "population": rounding.json(pop)})
+                                .to_string(),
+                        );
+                        if repr == Repr::Ndjson {
+                            chunk.push('\n');
+                        }
+                    }
+                    Repr::Text => {
+                        chunk.push_str(&format!("{:x} {}\n", **cell, rounding.text(pop)));
+                    }
+                }
+                first = false;
+            }
+            // Sends only fail once the client has gone away.
+            if !chunk.is_empty() && !send(&mut tx, chunk) {
+                return;
+            }
+        }
+        if repr == Repr::Json {
+            send(&mut tx, "]".to_string());
+        }
+    });
+    let mut resp = Response::new(body);
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(repr.content_type()));
+    resp
+}
+
+/// `GET /count/{index}`: number of populated map cells at or under a
+/// cell. Unlike population lookups, an empty cell is a count of zero
+/// rather than a 404.
+fn count(dataset: &Dataset, repr: Repr, hex: &str) -> Response<Body> {
+    let Some(cell) = parse_cell(hex) else {
+        return not_found();
+    };
+    let count = dataset.populated_cells(cell);
+    reply(
+        repr,
+        json!({"index": format!("{:x}", *cell), "count": count}),
+        || count.to_string(),
+    )
+}
+
+/// `GET /count?min_lat=..&min_lng=..&max_lat=..&max_lng=..`: number of
+/// populated map cells inside a box.
+fn bbox_count(
+    dataset: &Dataset,
+    repr: Repr,
+    query: &str,
+    deadline: Option<Instant>,
+) -> Response<Body> {
+    let Some(bbox) = BBox::from_query(query) else {
+        return status_response(
+            StatusCode::BAD_REQUEST,
+            "expected min_lat, min_lng, max_lat and max_lng query parameters\n".to_string(),
+        );
+    };
+    let count = bbox_cells(bbox, dataset.resolution, deadline).and_then(|cells| {
+        let mut count = 0;
+        for cell in cells {
+            check_deadline(deadline)?;
+            count += u64::from(dataset.populated_cells(cell));
+        }
+        Ok(count)
+    });
+    match count {
+        Ok(count) => reply(repr, json!({ "count": count }), || count.to_string()),
+        Err(e) => e.response(),
+    }
+}
+
+/// `GET /centroid?min_lat=..&min_lng=..&max_lat=..&max_lng=..`: the
+/// population-weighted centroid of the cells inside a box, with their
+/// total population, as JSON or `<lat> <lng>` text. Unpopulated boxes
+/// are a 404. Takes an optional `res=..` like `/bbox`.
+fn centroid(
+    dataset: &Dataset,
+    repr: Repr,
+    rounding: Rounding,
+    query: &str,
+    deadline: Option<Instant>,
+) -> Response<Body> {
+    let (res, query) = match split_res(dataset, query) {
+        Ok(split) => split,
+        Err(msg) => return status_response(StatusCode::BAD_REQUEST, msg),
+    };
+    let Some(bbox) = BBox::from_query(&query) else {
+        return status_response(
+            StatusCode::BAD_REQUEST,
+            "expected min_lat, min_lng, max_lat and max_lng query parameters\n".to_string(),
+        );
+    };
+    match dataset.bbox_centroid(bbox, res, deadline) {
+        Ok(centroid) => match centroid.estimate() {
+            Some((lat, lng)) => reply(
+                repr,
+                json!({
+                    "lat": lat,
+                    "lng": lng,
+                    "population": rounding.json(centroid.weight() as f32),
+                }),
+                || format!("{} {}", lat, lng),
+            ),
+            None => not_found(),
+        },
+        Err(e) => e.response(),
+    }
+}
+
+/// `GET /top?n=..`: the `n` most populated map cells, most populated
+/// first, as JSON or `<index> <population>` text lines. `n` is capped
+/// at the `top_max` the map was loaded with.
+fn top(dataset: &Dataset, repr: Repr, rounding: Rounding, query: &str) -> Response<Body> {
+    let Some(n) = query_values(query, ["n"]).and_then(|[n]| n.parse::<usize>().ok()) else {
+        return status_response(
+            StatusCode::BAD_REQUEST,
+            "expected a non-negative n query parameter\n".to_string(),
+        );
+    };
+    let top = &dataset.top[..n.min(dataset.top.len())];
+    let json = top
+        .iter()
+        .map(|(cell, pop)| json!({"index": format!("{:x}", **cell), "population": rounding.json(*pop)}))
+        .collect();
+    reply(repr, json, || {
+        top.iter()
+            .map(|(cell, pop)| format!("{:x} {}\n", **cell, rounding.text(*pop)))
+            .collect()
+    })
+}
+
+/// `GET /index?lat=..&lng=..&res=..`: hex index of the cell containing
+/// a point.
+fn index(repr: Repr, query: &str) -> Response<Body> {
+    match coordinate_to_cell(query) {
+        Some(cell) => {
+            let hex = format!("{:x}", *cell);
+            reply(repr, json!({ "index": hex }), || hex.clone())
+        }
+        None => status_response(
+            StatusCode::BAD_REQUEST,
+            "expected lat, lng and res query parameters\n".to_string(),
+        ),
+    }
+}
+
+/// `GET /area/{index}[,{index}...]`: area of a cell in km², or the
+/// summed area of several comma-separated cells.
+fn area(repr: Repr, hexes: &str) -> Response<Body> {
+    let Some(cells) = hexes.split(',').map(parse_cell).collect::<Option<Vec<_>>>() else {
+        return not_found();
+    };
+    match cells_area_km2(cells) {
+        Ok(area) => reply(repr, json!({ "area_km2": area }), || format!("{:?}", area)),
+        Err(e) => status_response(StatusCode::BAD_REQUEST, format!("{:?}\n", e)),
+    }
+}
+
+/// `GET /boundary/{index}`: the cell's outline as a GeoJSON feature
+/// with its population (`null` if unpopulated) as a property.
+fn boundary(dataset: &Dataset, rounding: Rounding, measure: Measure, hex: &str) -> Response<Body> {
+    let Some(cell) = parse_cell(hex) else {
+        return not_found();
+    };
+    let geometry = match boundary_geometry(cell) {
+        Ok(geometry) => geometry,
+        Err(e) => return status_response(StatusCode::BAD_REQUEST, format!("{:?}\n", e)),
+    };
+    let feature = json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": {
+            "index": format!("{:x}", *cell),
+            measure.key(): measure.of(dataset, cell).map(|pop| rounding.json(pop)),
+        },
+    });
+    let mut resp = Response::new(Body::from(feature.to_string()));
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/geo+json"),
+    );
+    resp
+}
+
+/// Returns a cell's outline as a GeoJSON geometry. Cells straddling
+/// the antimeridian are cut into a two polygon `MultiPolygon`, as
+/// RFC 7946 asks, rather than wrapping around the globe.
+fn boundary_geometry(cell: H3Cell) -> Result<serde_json::Value, h3ron::Error> {
+    let poly = cell.to_polygon()?;
+    let crosses = poly
+        .exterior()
+        .lines()
+        .any(|line| (line.start.x - line.end.x).abs() > 180.0);
+    if !crosses {
+        return Ok(json!({
+            "type": "Polygon",
+            "coordinates": [ring_coords(poly.exterior())],
+        }));
+    }
+    // Shift western vertices east so the ring is continuous, then cut
+    // it at 180 and shift the eastern piece back.
+    let unwrapped =
+        poly.map_coords(|c| coord! {x: if c.x < 0.0 { c.x + 360.0 } else { c.x }, y: c.y});
+    let west_half = Rect::new(coord! {x: 0.0, y: -90.0}, coord! {x: 180.0, y: 90.0});
+    let east_half = Rect::new(coord! {x: 180.0, y: -90.0}, coord! {x: 360.0, y: 90.0});
+    let parts = unwrapped
+        .intersection(&west_half.to_polygon())
+        .into_iter()
+        .chain(
+            unwrapped
+                .intersection(&east_half.to_polygon())
+                .into_iter()
+                .map(|part| part.map_coords(|c| coord! {x: c.x - 360.0, y: c.y})),
+        )
+        .map(|part| json!([ring_coords(part.exterior())]))
+        .collect::<Vec<_>>();
+    Ok(json!({
+        "type": "MultiPolygon",
+        "coordinates": parts,
+    }))
+}
+
+/// Returns a ring as GeoJSON `[lng, lat]` positions.
+fn ring_coords(ring: &LineString) -> Vec<[f64; 2]> {
+    ring.coords().map(|c| [c.x, c.y]).collect()
+}
+
+/// Query parameters every endpoint accepts, parsed in [`route`].
+const SHARED_PARAMS: [&str; 3] = ["round", "nearest", "per_area"];
+
+/// Returns the values of exactly `keys` from a `key=value&...` query
+/// string, in the order of `keys`, or `None` if any is missing or an
+/// unknown key is present. [`SHARED_PARAMS`] are skipped.
+fn query_values<'a, const N: usize>(query: &'a str, keys: [&str; N]) -> Option<[&'a str; N]> {
+    let mut values = [None; N];
+    for pair in query.split('&') {
+        let (key, val) = pair.split_once('=')?;
+        if SHARED_PARAMS.contains(&key) {
+            continue;
+        }
+        let pos = keys.iter().position(|k| *k == key)?;
+        values[pos] = Some(val);
+    }
+    let mut found = [""; N];
+    for (dst, val) in found.iter_mut().zip(values) {
+        *dst = val?;
+    }
+    Some(found)
+}
+
+/// Refuses a `res` finer than `dataset`'s own resolution, which has no
+/// cells to sum, with the message for a 400.
+fn check_res(dataset: &Dataset, res: u8) -> Result<u8, String> {
+    if res > dataset.resolution {
+        return Err(format!(
+            "res must be at most the map's resolution {}\n",
+            dataset.resolution
+        ));
+    }
+    Ok(res)
+}
+
+/// Splits an optional `res=..` parameter off `query`, returning the
+/// resolution to answer at, the map's own if absent, and the rest of
+/// the query for [`query_values`], or the message for a 400.
+fn split_res(dataset: &Dataset, query: &str) -> Result<(u8, String), String> {
+    let mut res = None;
+    let mut rest = Vec::new();
+    for pair in query.split('&') {
+        match pair.strip_prefix("res=") {
+            Some(val) => res = Some(val),
+            None => rest.push(pair),
+        }
+    }
+    let res = match res.map(str::parse::<u8>) {
+        None => dataset.resolution,
+        Some(Ok(res)) => check_res(dataset, res)?,
+        Some(Err(_)) => return Err("res must be a resolution from 0 to 15\n".to_string()),
+    };
+    Ok((res, rest.join("&")))
+}
+
+fn lat_ok(lat: f64) -> bool {
+    (-90.0..=90.0).contains(&lat)
+}
+
+fn lng_ok(lng: f64) -> bool {
+    (-180.0..=180.0).contains(&lng)
+}
+
+/// Parses `lat=..&lng=..&res=..` into the cell containing that point.
+fn coordinate_to_cell(query: &str) -> Option<H3Cell> {
+    let [lat, lng, res] = query_values(query, ["lat", "lng", "res"])?;
+    let (lat, lng) = (lat.parse::<f64>().ok()?, lng.parse::<f64>().ok()?);
+    let res = res.parse::<u8>().ok()?;
+    if !lat_ok(lat) || !lng_ok(lng) || res > 15 {
+        return None;
+    }
+    H3Cell::from_coordinate(coord! {x: lng, y: lat}, res).ok()
+}
+
+fn status_response(status: StatusCode, body: String) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// A lat/lng bounding box in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BBox {
+    min_lat: f64,
+    min_lng: f64,
+    max_lat: f64,
+    max_lng: f64,
+}
+
+impl BBox {
+    /// Parses `min_lat=..&min_lng=..&max_lat=..&max_lng=..` in any
+    /// order, rejecting unknown keys and missing, unparsable or out of
+    /// range values.
+    fn from_query(query: &str) -> Option<Self> {
+        let values = query_values(query, ["min_lat", "min_lng", "max_lat", "max_lng"])?;
+        let [min_lat, min_lng, max_lat, max_lng] = values.map(|val| val.parse::<f64>().ok());
+        Self {
+            min_lat: min_lat?,
+            min_lng: min_lng?,
+            max_lat: max_lat?,
+            max_lng: max_lng?,
+        }
+        .checked()
+    }
+
+    /// Parses a comma-separated `min_lng,min_lat,max_lng,max_lat`
+    /// list, the order map libraries give viewports in.
+    fn from_lng_lat_list(list: &str) -> Option<Self> {
+        let values = list
+            .split(',')
+            .map(|val| val.parse::<f64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let [min_lng, min_lat, max_lng, max_lat] = values[..] else {
+            return None;
+        };
+        Self {
+            min_lat,
+            min_lng,
+            max_lat,
+            max_lng,
+        }
+        .checked()
+    }
+
+    /// Returns the box if it's in range, with its south edge not north
+    /// of its north edge.
+    fn checked(self) -> Option<Self> {
+        (lat_ok(self.min_lat)
+            && lat_ok(self.max_lat)
+            && self.min_lat <= self.max_lat
+            && lng_ok(self.min_lng)
+            && lng_ok(self.max_lng))
+        .then_some(self)
+    }
+
+    /// Area of the box in km², on a sphere of the Earth's mean radius.
+    fn area_km2(self) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0088;
+        let mut width = self.max_lng - self.min_lng;
+        if width < 0.0 {
+            width += 360.0;
+        }
+        EARTH_RADIUS_KM.powi(2)
+            * width.to_radians()
+            * (self.max_lat.to_radians().sin() - self.min_lat.to_radians().sin())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hextree::h3ron::{FromH3Index, ToCoordinate};
+    use std::sync::Mutex;
+
+    const CELL: u64 = 0x8a1fb46622dffff;
+
+    fn state() -> Arc<State> {
+        let mut map = HexTreeMap::new();
+        map.insert(H3Cell::from_h3index(CELL), 2.5);
+        Arc::new(State::new(map, 16, DEFAULT_TOP_MAX))
+    }
+
+    /// Sends a request with the given `Accept` header, returning the
+    /// status, content type and body.
+    async fn request(
+        state: &Arc<State>,
+        uri: &str,
+        accept: Option<&str>,
+    ) -> (StatusCode, Option<String>, String) {
+        let mut req = Request::get(uri);
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT, accept);
+        }
+        let req = req.body(Body::empty()).expect("valid request");
+        let resp = handle(state.clone(), req).await.expect("infallible");
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|val| val.to_str().expect("ascii").to_string());
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .expect("in-memory body");
+        (
+            status,
+            content_type,
+            String::from_utf8_lossy(&body).into_owned(),
+        )
+    }
+
+    /// Sends a request for a plain text body.
+    async fn get(state: &Arc<State>, uri: &str) -> (StatusCode, String) {
+        let (status, _, body) = request(state, uri, Some("text/plain")).await;
+        (status, body)
+    }
+
+    /// Collects formatted log output for assertions.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().expect("logs lock").extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for Logs {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_query() {
+        let cell = H3Cell::from_h3index(CELL);
+        let parent = cell.get_parent(cell.resolution() - 1).expect("parent");
+        let sibling = parent
+            .get_children(cell.resolution())
+            .expect("children")
+            .iter()
+            .find(|child| *child != cell)
+            .expect("sibling");
+        let mut map = HexTreeMap::new();
+        map.insert(cell, 2.5);
+        map.insert(sibling, 1.0);
+
+        assert_eq!(handle_query(&map, cell, ReduceMode::Sum).await, Some(2.5));
+        assert_eq!(handle_query(&map, parent, ReduceMode::Sum).await, Some(3.5));
+        assert_eq!(handle_query(&map, parent, ReduceMode::Min).await, Some(1.0));
+        assert_eq!(handle_query(&map, parent, ReduceMode::Max).await, Some(2.5));
+        let far = H3Cell::from_coordinate(coord! {x: 0.5, y: 0.5}, 10).expect("cell");
+        assert_eq!(handle_query(&map, far, ReduceMode::Sum).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_query_matches_combine() {
+        use gpwgen::{combine::combine_readers, h3tess::H3TessWriter};
+
+        // Every res 10 cell under a res 8 cell, with varied values.
+        let root = H3Cell::from_h3index(CELL).get_parent(8).expect("parent");
+        let mut fine = HexTreeMap::new();
+        let mut wtr = H3TessWriter::new(Vec::new());
+        for (i, cell) in root.get_children(10).expect("children").iter().enumerate() {
+            let val = (i % 7 * 3 + 1) as f32;
+            fine.insert(cell, val);
+            wtr.write_pair(cell, val).expect("record");
+        }
+        let h3tess = wtr.finish().expect("finish");
+
+        for mode in [ReduceMode::Sum, ReduceMode::Min, ReduceMode::Max] {
+            let combined = combine_readers([h3tess.as_slice()], 9, mode).expect("combine");
+            assert_eq!(combined.len(), 7);
+            for (cell, val) in combined.iter() {
+                assert_eq!(cell.resolution(), 9);
+                assert_eq!(
+                    handle_query(&fine, *cell, mode).await,
+                    Some(*val),
+                    "{:?}",
+                    mode
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        use hyper::service::Service;
+
+        let mut service = GpwService::new(state());
+        let req = Request::get(format!("/{:x}", CELL))
+            .header(ACCEPT, "text/plain")
+            .body(Body::empty())
+            .expect("valid request");
+        let resp = service.call(req).await.expect("infallible");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .expect("in-memory body");
+        assert_eq!(&body[..], b"2.5");
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let state = state();
+        let cell = H3Cell::from_h3index(CELL);
+        let (status, body) = get(&state, &format!("/{:x}", CELL)).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "2.5"));
+        let parent = cell.get_parent(cell.resolution() - 1).expect("parent");
+        let (status, body) = get(&state, &format!("/{:x}", *parent)).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "2.5"));
+        let (status, _) = get(&state, "/not-a-cell").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let state = state();
+        let uri = format!("/{:x}", CELL);
+        assert_eq!(get(&state, &uri).await, get(&state, &uri).await);
+        let (_, metrics) = get(&state, "/metrics").await;
+        assert!(metrics.contains("gpws_cache_hits_total 1\n"), "{}", metrics);
+        assert!(
+            metrics.contains("gpws_cache_misses_total 1\n"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn test_precompute() {
+        // A sparse sample of res 10 cells under a single res 5 cell.
+        let mut map = HexTreeMap::new();
+        let root = H3Cell::from_h3index(CELL).get_parent(5).expect("parent");
+        for (i, cell) in root
+            .get_children(10)
+            .expect("children")
+            .iter()
+            .step_by(997)
+            .enumerate()
+        {
+            map.insert(cell, i as f32 + 0.5);
+        }
+        let mut dataset = Dataset::new(map, 0, 0);
+        let parents = dataset
+            .map
+            .iter()
+            .flat_map(|(cell, _)| (5..10).map(|res| cell.get_parent(res).expect("parent")))
+            .collect::<Vec<_>>();
+        let reduced = parents
+            .iter()
+            .map(|cell| dataset.population(*cell))
+            .collect::<Vec<_>>();
+
+        dataset.precompute(&[5, 6, 8]);
+        for (cell, expected) in parents.iter().zip(reduced) {
+            let expected = expected.expect("populated");
+            let actual = dataset.population(*cell).expect("populated");
+            assert!((actual - expected).abs() < 1e-3, "{:?}", cell);
+        }
+        assert!(!dataset.precomputed[&5].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_named_maps() {
+        let cell = H3Cell::from_h3index(CELL);
+        let map = |pop| {
+            let mut map = HexTreeMap::new();
+            map.insert(cell, pop);
+            map
+        };
+        let state = Arc::new(
+            State::empty(16, DEFAULT_TOP_MAX)
+                .named_map("2015".to_string(), map(1.5))
+                .named_map("2020".to_string(), map(2.5)),
+        );
+
+        let (status, body) = get(&state, &format!("/2015/{:x}", CELL)).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "1.5"));
+        let (status, body) = get(&state, &format!("/2020/{:x}", CELL)).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "2.5"));
+        let parent = cell.get_parent(9).expect("parent");
+        let (status, body) = get(&state, &format!("/2020/parent/{:x}", CELL)).await;
+        assert_eq!((status, body), (StatusCode::OK, "2.5".to_string()));
+        let (_, _, children) =
+            request(&state, &format!("/2015/children/{:x}", *parent), None).await;
+        assert!(children.contains("1.5"), "{}", children);
+
+        let (status, _, body) = request(&state, "/maps", None).await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, r#"["2015","2020"]"#)
+        );
+
+        // Without a default map, unprefixed and unknown prefixes miss.
+        for uri in [
+            format!("/{:x}", CELL),
+            format!("/2025/{:x}", CELL),
+            "/2015".to_string(),
+        ] {
+            let (status, _) = get(&state, &uri).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_series() {
+        let cell = H3Cell::from_h3index(CELL);
+        let map = |pop| {
+            let mut map = HexTreeMap::new();
+            map.insert(cell, pop);
+            map
+        };
+        // Added out of order, alongside a map that isn't a year.
+        let state = Arc::new(
+            State::new(map(9.0), 0, DEFAULT_TOP_MAX)
+                .named_map("2020".to_string(), map(2.5))
+                .named_map("2015".to_string(), map(1.5))
+                .named_map("density".to_string(), map(7.0)),
+        );
+        let (status, _, body) = request(&state, &format!("/series/{:x}", CELL), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let series: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(
+            series,
+            json!([
+                {"year": 2015, "population": 1.5},
+                {"year": 2020, "population": 2.5},
+            ])
+        );
+        let (_, text) = get(&state, &format!("/series/{:x}?round=true", CELL)).await;
+        assert_eq!(text, "2015 2\n2020 3\n");
+
+        let (status, _) = get(&state, "/series/nope").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_nearest() {
+        let cell = H3Cell::from_h3index(CELL);
+        let neighbor = cell
+            .grid_disk_distances(1, 1)
+            .expect("ring")
+            .into_iter()
+            .map(|(_, cell)| cell)
+            .min()
+            .expect("neighbor");
+        let mut map = HexTreeMap::new();
+        map.insert(neighbor, 4.0);
+        let state = Arc::new(State::new(map, 0, DEFAULT_TOP_MAX));
+
+        let uri = format!("/{:x}", CELL);
+        let (status, _) = get(&state, &uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _, body) = request(&state, &format!("{}?nearest=true", uri), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(
+            body,
+            json!({"index": format!("{:x}", *neighbor), "population": 4.0, "distance": 1})
+        );
+        let (_, text) = get(&state, &format!("{}?nearest=true&round=true", uri)).await;
+        assert_eq!(text, format!("{:x} 4 1", *neighbor));
+
+        // Exact hits report distance 0.
+        let (_, text) = get(&state, &format!("/{:x}?nearest=true", *neighbor)).await;
+        assert_eq!(text, format!("{:x} 4.0 0", *neighbor));
+
+        let far = H3Cell::from_coordinate(coord! {x: 0.0, y: 0.0}, 10).expect("cell");
+        let (status, _) = get(&state, &format!("/{:x}?nearest=true", *far)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get(&state, &format!("{}?nearest=maybe", uri)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_check_map_name() {
+        assert!(check_map_name("2015").is_ok());
+        assert!(check_map_name("pop-density_v4").is_ok());
+        let cell = format!("{:x}", CELL);
+        for bad in ["", "a/b", "maps", "metrics", "count", "cells", &cell] {
+            assert!(check_map_name(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    /// Returns the res 10 cells whose centers lie in a box.
+    fn cells_in(west: f64, south: f64, east: f64, north: f64) -> Vec<H3Cell> {
+        let rect = Rect::new(coord! {x: west, y: south}, coord! {x: east, y: north});
+        h3ron::polygon_to_cells(&rect.to_polygon(), 10)
+            .expect("valid polygon")
+            .iter()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_cells() {
+        let mut cells = H3Cell::from_h3index(CELL)
+            .grid_disk(1)
+            .expect("valid cell")
+            .iter()
+            .collect::<Vec<_>>();
+        cells.sort_unstable_by_key(|cell| **cell);
+        let mut map = HexTreeMap::new();
+        for (i, cell) in cells.iter().enumerate() {
+            map.insert(*cell, i as f32 + 0.5);
+        }
+        let state = Arc::new(State::new(map, 0, DEFAULT_TOP_MAX));
+        let center = H3Cell::from_h3index(CELL)
+            .to_coordinate()
+            .expect("valid cell");
+        let uri = format!(
+            "/cells?bbox={},{},{},{}&res=10",
+            center.x - 0.01,
+            center.y - 0.01,
+            center.x + 0.01,
+            center.y + 0.01
+        );
+
+        let (status, content_type, json) = request(&state, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        let mut listed = serde_json::from_str::<Vec<serde_json::Value>>(&json)
+            .expect("valid json")
+            .into_iter()
+            .map(|item| {
+                let index = item["index"].as_str().expect("hex index");
+                let pop = item["population"].as_f64().expect("population");
+                (u64::from_str_radix(index, 16).expect("hex index"), pop)
+            })
+            .collect::<Vec<_>>();
+        listed.sort_unstable_by_key(|(index, _)| *index);
+        let expected = cells
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| (**cell, i as f64 + 0.5))
+            .collect::<Vec<_>>();
+        assert_eq!(listed, expected);
+
+        let (_, content_type, ndjson) = request(&state, &uri, Some("application/x-ndjson")).await;
+        assert_eq!(content_type.as_deref(), Some("application/x-ndjson"));
+        assert_eq!(ndjson.lines().count(), cells.len());
+        let (_, text) = get(&state, &uri).await;
+        assert_eq!(text.lines().count(), cells.len());
+
+        // Coarser cells sum their populated children.
+        let parent = H3Cell::from_h3index(CELL)
+            .get_parent(9)
+            .expect("valid cell");
+        let (_, text) = get(&state, &uri.replace("res=10", "res=9")).await;
+        assert!(text.contains(&format!("{:x} ", *parent)), "{}", text);
+
+        let (status, body) = get(&state, "/cells?bbox=-180,-90,180,90&res=10").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("more than the limit"), "{}", body);
+        let (status, _) = get(&state, &uri.replace("res=10", "res=11")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get(&state, "/cells?bbox=1,2,3&res=10").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_count() {
+        let cell = H3Cell::from_h3index(CELL);
+        let parent = cell.get_parent(cell.resolution() - 1).expect("valid cell");
+        let children = parent
+            .get_children(cell.resolution())
+            .expect("valid cell")
+            .iter()
+            .collect::<Vec<_>>();
+        // Two populated children, one empty and the rest missing, as
+        // NODATA grid cells would be.
+        let mut map = HexTreeMap::new();
+        map.insert(children[0], 2.0);
+        map.insert(children[1], 0.5);
+        map.insert(children[2], 0.0);
+        let state = Arc::new(State::new(map, 0, DEFAULT_TOP_MAX));
+
+        let (status, body) = get(&state, &format!("/count/{:x}", *parent)).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "2"));
+        let (_, body) = get(&state, &format!("/count/{:x}", *children[2])).await;
+        assert_eq!(body, "0");
+        let (_, body) = get(&state, &format!("/count/{:x}", *children[3])).await;
+        assert_eq!(body, "0");
+        let (_, _, json) = request(&state, &format!("/count/{:x}", *children[0]), None).await;
+        assert_eq!(
+            json,
+            json!({"index": format!("{:x}", *children[0]), "count": 1}).to_string()
+        );
+
+        let center = parent.to_coordinate().expect("valid cell");
+        let bbox = format!(
+            "min_lat={}&min_lng={}&max_lat={}&max_lng={}",
+            center.y - 0.1,
+            center.x - 0.1,
+            center.y + 0.1,
+            center.x + 0.1
+        );
+        let (status, body) = get(&state, &format!("/count?{}", bbox)).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "2"));
+        let (status, _) = get(&state, "/count").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_centroid() {
+        let cell = H3Cell::from_h3index(CELL);
+        let neighbor = cell
+            .grid_disk(1)
+            .expect("valid cell")
+            .iter()
+            .find(|c| *c != cell)
+            .expect("cell has neighbors");
+        let mut map = HexTreeMap::new();
+        map.insert(cell, 1.0);
+        map.insert(neighbor, 3.0);
+        let state = Arc::new(State::new(map, 0, DEFAULT_TOP_MAX));
+
+        let [a, b] = [cell, neighbor].map(|c| c.to_coordinate().expect("valid cell"));
+        let (lat, lng) = ((a.y + 3.0 * b.y) / 4.0, (a.x + 3.0 * b.x) / 4.0);
+        let bbox = format!(
+            "min_lat={}&min_lng={}&max_lat={}&max_lng={}",
+            lat - 0.05,
+            lng - 0.05,
+            lat + 0.05,
+            lng + 0.05
+        );
+        let (status, _, json) = request(&state, &format!("/centroid?{}", bbox), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let json = serde_json::from_str::<serde_json::Value>(&json).expect("valid json");
+        assert_eq!(json["population"], 4.0);
+        // Over a few hundred meters, the spherical and planar weighted
+        // means agree to well under a meter.
+        let coord = |key: &str| json[key].as_f64().expect("numeric coordinate");
+        let got = (coord("lat"), coord("lng"));
+        assert!((got.0 - lat).abs() < 1e-6, "{:?} vs {:?}", got, (lat, lng));
+        assert!((got.1 - lng).abs() < 1e-6, "{:?} vs {:?}", got, (lat, lng));
+
+        let (status, _) = get(
+            &state,
+            "/centroid?min_lat=0&min_lng=0&max_lat=0.01&max_lng=0.01",
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get(&state, "/centroid").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_top() {
+        let cells = H3Cell::from_h3index(CELL)
+            .grid_disk(1)
+            .expect("valid cell")
+            .iter()
+            .collect::<Vec<_>>();
+        let mut map = HexTreeMap::new();
+        for (pop, cell) in cells.iter().enumerate() {
+            map.insert(*cell, pop as f32);
+        }
+        let state = Arc::new(State::new(map, 0, 3));
+
+        let (status, body) = get(&state, "/top?n=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, format!("{:x} 6.0\n{:x} 5.0\n", *cells[6], *cells[5]));
+        let (_, _, json) = request(&state, "/top?n=2", None).await;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).expect("valid json"),
+            json!([
+                {"index": format!("{:x}", *cells[6]), "population": 6.0},
+                {"index": format!("{:x}", *cells[5]), "population": 5.0},
+            ])
+        );
+
+        // Capped at `top_max`.
+        let (_, body) = get(&state, "/top?n=100").await;
+        assert_eq!(body.lines().count(), 3);
+        let (status, _) = get(&state, "/top?n=-1").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get(&state, "/top").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_bbox() {
+        let zurich = cells_in(8.50, 47.36, 8.52, 47.38);
+        let east = cells_in(179.98, -0.01, 180.0, 0.01);
+        let west = cells_in(-180.0, -0.01, -179.98, 0.01);
+        let mut map = HexTreeMap::new();
+        for cell in zurich.iter().chain(&east).chain(&west) {
+            map.insert(*cell, 1.0);
+        }
+        let state = Arc::new(State::new(map, 0, DEFAULT_TOP_MAX));
+
+        let query = |bbox: &str| {
+            let state = state.clone();
+            let uri = format!("/bbox?{}", bbox);
+            async move { get(&state, &uri).await }
+        };
+        let (status, body) = query("min_lat=47.35&min_lng=8.49&max_lat=47.39&max_lng=8.53").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.parse::<f32>().expect("float"), zurich.len() as f32);
+
+        // Crossing the antimeridian picks up both sides.
+        let (status, body) =
+            query("min_lat=-0.02&min_lng=179.97&max_lat=0.02&max_lng=-179.97").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body.parse::<f32>().expect("float"),
+            (east.len() + west.len()) as f32
+        );
+
+        let (status, _) = query("min_lat=47.3&min_lng=8.4&max_lat=47.4").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = query("min_lat=95&min_lng=8.4&max_lat=47.4&max_lng=8.6").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_bbox_limit() {
+        let state = state();
+        let europe = "min_lat=35&min_lng=-10&max_lat=70&max_lng=40";
+        for endpoint in ["bbox", "centroid", "count"] {
+            let (status, body) = get(&state, &format!("/{}?{}", endpoint, europe)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", endpoint);
+            assert!(body.contains("more than the limit"), "{}", body);
+        }
+        // Few enough cells at a coarser resolution.
+        let (status, _) = get(&state, &format!("/bbox?{}&res=3", europe)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_bbox_res() {
+        let parent = H3Cell::from_coordinate(coord! {x: 8.51, y: 47.37}, 8).expect("cell");
+        let mut map = HexTreeMap::new();
+        for (i, cell) in parent
+            .get_children(10)
+            .expect("children")
+            .iter()
+            .enumerate()
+        {
+            map.insert(cell, (i % 5) as f32);
+        }
+        let total = map.iter().map(|(_, pop)| *pop).sum::<f32>();
+        let state = Arc::new(State::new(map, 16, DEFAULT_TOP_MAX).downsample(1));
+
+        let bbox = "min_lat=47.3&min_lng=8.4&max_lat=47.4&max_lng=8.6";
+        let sum = |res: &str| {
+            let state = state.clone();
+            let uri = format!("/bbox?{}{}", bbox, res);
+            async move {
+                let (status, body) = get(&state, &uri).await;
+                assert_eq!(status, StatusCode::OK, "{}", body);
+                body.parse::<f32>().expect("float")
+            }
+        };
+        let fine = sum("").await;
+        assert_eq!(fine, total);
+        assert_eq!(sum("&res=10").await, fine);
+        // Res 9 is precomputed; res 8 goes through the cache.
+        assert_eq!(sum("&res=9").await, fine);
+        assert_eq!(sum("&res=8").await, fine);
+
+        let (status, _) = get(&state, &format!("/centroid?res=9&{}", bbox)).await;
+        assert_eq!(status, StatusCode::OK);
+        for res in ["11", "x", ""] {
+            let (status, _) = get(&state, &format!("/bbox?{}&res={}", bbox, res)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "res={}", res);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_index() {
+        let state = state();
+        // Zurich main station.
+        let (status, body) = get(&state, "/index?lat=47.3779&lng=8.5403&res=10").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "8a1f8ed95187fff"));
+        // A res 10 hex is ~65 m across, so its center is close by.
+        let center = H3Cell::from_h3index(0x8a1f8ed95187fff)
+            .to_coordinate()
+            .expect("center");
+        assert!((center.y - 47.3779).abs() < 1e-3 && (center.x - 8.5403).abs() < 1e-3);
+        // Parameters may come in any order.
+        let (_, reordered) = get(&state, "/index?res=10&lng=8.5403&lat=47.3779").await;
+        assert_eq!(reordered, body);
+
+        for bad in [
+            "/index?lat=47.3779&lng=8.5403",
+            "/index?lat=97&lng=8.5403&res=10",
+            "/index?lat=47.3779&lng=8.5403&res=16",
+            "/index?lat=47.3779&lng=east&res=10",
+        ] {
+            let (status, _) = get(&state, bad).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_boundary() {
+        let state = state();
+        let req = Request::get(format!("/boundary/{:x}", CELL))
+            .body(Body::empty())
+            .expect("valid request");
+        let resp = handle(state.clone(), req).await.expect("infallible");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/geo+json");
+        let body = hyper::body::to_bytes(resp.into_body()).await.expect("body");
+        let feature: serde_json::Value = serde_json::from_slice(&body).expect("json");
+        assert_eq!(feature["type"], "Feature");
+        assert_eq!(feature["properties"]["index"], format!("{:x}", CELL));
+        assert_eq!(feature["properties"]["population"], 2.5);
+        assert_eq!(feature["geometry"]["type"], "Polygon");
+        let ring = feature["geometry"]["coordinates"][0]
+            .as_array()
+            .expect("ring");
+        // Six vertices plus the closing one.
+        assert_eq!(ring.len(), 7);
+        assert_eq!(ring.first(), ring.last());
+
+        let (status, _) = get(&state, "/boundary/nope").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_boundary_antimeridian() {
+        let cell = H3Cell::from_coordinate(coord! {x: 180.0, y: 0.0}, 3).expect("cell");
+        let geometry = boundary_geometry(cell).expect("geometry");
+        assert_eq!(geometry["type"], "MultiPolygon");
+        let parts = geometry["coordinates"].as_array().expect("parts");
+        assert_eq!(parts.len(), 2);
+        for part in parts {
+            let lngs = part[0]
+                .as_array()
+                .expect("ring")
+                .iter()
+                .map(|pos| pos[0].as_f64().expect("lng"))
+                .collect::<Vec<_>>();
+            // Each piece stays on its own side of the antimeridian.
+            let east = lngs.iter().all(|lng| *lng >= 0.0);
+            let west = lngs.iter().all(|lng| *lng <= 0.0);
+            assert!(east ^ west, "{:?}", lngs);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_children_and_parent() {
+        // Two of a res 9 cell's seven children are populated.
+        let root = H3Cell::from_h3index(CELL).get_parent(9).expect("parent");
+        let kids = root
+            .get_children(10)
+            .expect("children")
+            .iter()
+            .collect::<Vec<_>>();
+        let mut map = HexTreeMap::new();
+        map.insert(kids[0], 1.0);
+        map.insert(kids[3], 2.0);
+        let state = Arc::new(State::new(map, 0, DEFAULT_TOP_MAX));
+
+        let (status, _, body) = request(&state, &format!("/children/{:x}", *root), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let listed: serde_json::Value = serde_json::from_str(&body).expect("json");
+        let listed = listed.as_array().expect("list");
+        assert_eq!(listed.len(), 7);
+        for (entry, kid) in listed.iter().zip(&kids) {
+            assert_eq!(entry["index"], format!("{:x}", **kid));
+            let expected = match *kid {
+                k if k == kids[0] => json!(1.0),
+                k if k == kids[3] => json!(2.0),
+                _ => serde_json::Value::Null,
+            };
+            assert_eq!(entry["population"], expected);
+        }
+
+        for kid in [kids[0], kids[5]] {
+            let (status, body) = get(&state, &format!("/parent/{:x}", *kid)).await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, "3.0"));
+        }
+
+        let res0 = root.get_parent(0).expect("base cell");
+        let (status, _) = get(&state, &format!("/parent/{:x}", *res0)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get(&state, "/children/nope").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_content_negotiation() {
+        let state = state();
+        let uri = format!("/{:x}", CELL);
+        let json = json!({"index": format!("{:x}", CELL), "population": 2.5}).to_string();
+        for (accept, content_type, body) in [
+            (None, "application/json", json.as_str()),
+            (Some("*/*"), "application/json", json.as_str()),
+            (Some("application/json"), "application/json", json.as_str()),
+            (Some("text/plain"), "text/plain; charset=utf-8", "2.5"),
+            (
+                Some("text/html, text/plain;q=0.9, */*;q=0.1"),
+                "text/plain; charset=utf-8",
+                "2.5",
+            ),
+        ] {
+            let resp = request(&state, &uri, accept).await;
+            assert_eq!(
+                resp,
+                (
+                    StatusCode::OK,
+                    Some(content_type.to_string()),
+                    body.to_string()
+                ),
+                "{:?}",
+                accept
+            );
+        }
+        let (status, _, _) = request(&state, &uri, Some("text/html")).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[tokio::test]
+    async fn test_round() {
+        let state = state();
+        let uri = format!("/{:x}", CELL);
+        let (_, raw) = get(&state, &uri).await;
+        assert_eq!(raw, "2.5");
+        let (_, rounded) = get(&state, &format!("{}?round=true", uri)).await;
+        assert_eq!(rounded, "3");
+        let (_, _, body) = request(&state, &format!("{}?round=true", uri), None).await;
+        let body: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(body["population"], json!(3));
+
+        let (status, _) = get(&state, &format!("{}?round=yes", uri)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_per_area() {
+        let state = state();
+        let uri = format!("/{:x}?per_area=true", CELL);
+        let area = H3Cell::from_h3index(CELL).area_km2().expect("area");
+        let expected = (2.5 / area) as f32;
+        let (status, text) = get(&state, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(text.parse::<f32>().expect("float"), expected);
+        let (_, _, body) = request(&state, &uri, None).await;
+        let body: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(body["density"], json!(expected));
+        assert_eq!(body.get("population"), None);
+
+        // A box holding only the cell divides by the area of every
+        // cell it covers.
+        let center = H3Cell::from_h3index(CELL).to_coordinate().expect("center");
+        let (status, text) = get(
+            &state,
+            &format!(
+                "/bbox?min_lat={}&min_lng={}&max_lat={}&max_lng={}&per_area=true",
+                center.y - 1e-4,
+                center.x - 1e-4,
+                center.y + 1e-4,
+                center.x + 1e-4,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(text.parse::<f32>().expect("float"), expected);
+
+        let (status, _) = get(&state, &format!("/{:x}?per_area=1", CELL)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_area() {
+        let state = state();
+        let cell = H3Cell::from_h3index(CELL);
+        let parent = cell.get_parent(8).expect("parent");
+        let (status, text) = get(&state, &format!("/area/{:x}", *parent)).await;
+        assert_eq!(status, StatusCode::OK);
+        let area = text.parse::<f64>().expect("float");
+        // Res 8 hexes average 0.737 km².
+        assert!((0.5..1.0).contains(&area), "{}", area);
+
+        let (status, _, body) =
+            request(&state, &format!("/area/{:x},{:x}", *parent, CELL), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).expect("json");
+        let expected = area + cell.area_km2().expect("area");
+        assert_eq!(body["area_km2"], json!(expected));
+
+        let (status, _) = get(&state, &format!("/area/{:x},nope", CELL)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Response::new(Body::from("too late"))
+        };
+        let resp = with_timeout(Some(Duration::from_millis(10)), slow).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let fast = async { Response::new(Body::from("fine")) };
+        let resp = with_timeout(Some(Duration::from_secs(10)), fast).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The limit applies to whole requests through `handle` too.
+        let state = State::new(HexTreeMap::new(), 0, DEFAULT_TOP_MAX)
+            .request_timeout(Some(Duration::from_secs(10)));
+        let (status, _) = get(&Arc::new(state), "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+
+        // With no time at all, any real handler is too slow.
+        let zurich = "min_lat=47.25&min_lng=8.3&max_lat=47.5&max_lng=8.7";
+        let mut map = HexTreeMap::new();
+        map.insert(H3Cell::from_h3index(CELL), 2.5);
+        let state = State::new(map, 0, DEFAULT_TOP_MAX).request_timeout(Some(Duration::ZERO));
+        let state = Arc::new(state);
+        for endpoint in ["bbox", "centroid", "count"] {
+            let (status, body) = get(&state, &format!("/{}?{}", endpoint, zurich)).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", endpoint);
+            assert_eq!(body, "request timed out\n");
+        }
+
+        // The handlers give up by themselves too, rather than running on
+        // unwaited-for.
+        let dataset = state.default.as_ref().expect("default map");
+        let bbox = BBox::from_query(zurich).expect("valid bbox");
+        let err = dataset
+            .bbox_population(bbox, 10, Some(Instant::now()))
+            .expect_err("past deadline");
+        assert!(matches!(err, BBoxError::TimedOut), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        get(&state(), &format!("/{:x}", CELL)).await;
+        let logs = String::from_utf8(logs.0.lock().expect("logs lock").clone()).expect("utf8");
+        assert!(logs.contains("gpws::access"), "{}", logs);
+        assert!(logs.contains("method=GET"), "{}", logs);
+        assert!(logs.contains(&format!("path=\"/{:x}\"", CELL)), "{}", logs);
+        assert!(logs.contains("status=200"), "{}", logs);
+        assert!(logs.contains("latency_us="), "{}", logs);
+    }
+}